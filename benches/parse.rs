@@ -0,0 +1,158 @@
+//! Parsing of a large modules.dep (over 10 MB): time and peak memory.
+//!
+//! Loading a kernel reads modules.dep as the features tell, so compare
+//! `cargo bench --bench parse` with `cargo bench --bench parse --features mmap`.
+//! Peak memory (VmHWM) is measured in a child process for each way of parsing,
+//! as the process peak cannot be reset. Mapped pages of modules.dep are resident too,
+//! but they are page cache, which the kernel can drop, unlike a heap copy of the file.
+
+use criterion::{criterion_group, Criterion};
+use kmoddep::kerman::{parse_deps, parse_deps_slice, KernelInfo};
+use std::{
+    env,
+    fs::{self, read_to_string, File},
+    hint::black_box,
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+static KVER: &str = "0.0.0-bench";
+static MODULES: usize = 40_000;
+
+/// Environment variable, which makes the process a child, which only parses
+static CHILD_ENV: &str = "KMODDEP_BENCH_PARSE";
+
+/// Root filesystem with just the modules.dep of the kernel, removed on drop
+struct Tree(PathBuf);
+
+impl Tree {
+    fn new() -> Self {
+        let root = env::temp_dir().join(format!("kmoddep-bench-parse-{}", process::id()));
+        let kdir = root.join("lib/modules").join(KVER);
+        fs::create_dir_all(kdir.join("kernel")).unwrap();
+
+        let mut data = String::new();
+        for idx in 0..MODULES {
+            data.push_str(&modpath(idx));
+            data.push(':');
+            for dep in [idx / 2, idx / 3, idx / 5, idx / 7] {
+                if dep < idx {
+                    data.push(' ');
+                    data.push_str(&modpath(dep));
+                }
+            }
+            data.push('\n');
+        }
+        fs::write(kdir.join("modules.dep"), data).unwrap();
+
+        Tree(root)
+    }
+
+    fn root(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    fn dep_path(&self) -> PathBuf {
+        self.0.join("lib/modules").join(KVER).join("modules.dep")
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn modpath(idx: usize) -> String {
+    format!(
+        "kernel/drivers/subsystem{}/vendor{}/module_with_a_long_name_{}.ko.zst",
+        idx % 50,
+        idx % 7,
+        idx
+    )
+}
+
+/// Parse in one of the ways
+fn parse(how: &str, root: &str, dep_path: &Path) -> usize {
+    match how {
+        "baseline" => 0,
+        "read_to_string" => parse_deps_slice(read_to_string(dep_path).unwrap().as_bytes())
+            .unwrap()
+            .deplist
+            .len(),
+        "bufreader" => parse_deps(BufReader::new(File::open(dep_path).unwrap()))
+            .unwrap()
+            .deplist
+            .len(),
+        "load" => {
+            let ki = KernelInfo::new(root, KVER).unwrap();
+            assert!(ki.is_valid());
+            ki.module_count()
+        }
+        how => panic!("Unknown way of parsing: {}", how),
+    }
+}
+
+/// Get peak resident memory of the process in kB
+fn peak_rss() -> u64 {
+    read_to_string("/proc/self/status")
+        .unwrap_or_default()
+        .lines()
+        .find_map(|x| x.strip_prefix("VmHWM:"))
+        .and_then(|x| x.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap_or_default()
+}
+
+fn report_peak_rss(tree: &Tree) {
+    let size = fs::metadata(tree.dep_path()).unwrap().len();
+    println!("modules.dep: {} modules, {} kB", MODULES, size / 1024);
+    println!("mmap feature: {}", cfg!(feature = "mmap"));
+    for how in ["baseline", "read_to_string", "bufreader", "load"] {
+        let out = Command::new(env::current_exe().unwrap())
+            .env(CHILD_ENV, how)
+            .arg(tree.root())
+            .output()
+            .unwrap();
+        println!(
+            "peak RSS {:<16} {} kB",
+            how,
+            String::from_utf8_lossy(&out.stdout).trim()
+        );
+    }
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let tree = Tree::new();
+    let dep_path = tree.dep_path();
+    let mut group = c.benchmark_group("parse modules.dep");
+    group.sample_size(10);
+    for how in ["read_to_string", "bufreader", "load"] {
+        group.bench_function(how, |b| {
+            b.iter(|| black_box(parse(how, tree.root(), &dep_path)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+
+fn main() {
+    if let Ok(how) = env::var(CHILD_ENV) {
+        let root = env::args().next_back().unwrap();
+        let dep_path = Path::new(&root)
+            .join("lib/modules")
+            .join(KVER)
+            .join("modules.dep");
+        assert_eq!(
+            parse(&how, &root, &dep_path),
+            if how == "baseline" { 0 } else { MODULES }
+        );
+        println!("{}", peak_rss());
+        return;
+    }
+
+    report_peak_rss(&Tree::new());
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
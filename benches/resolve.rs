@@ -0,0 +1,111 @@
+//! Name resolution and dependency walks on a synthetic module tree,
+//! which is about the size of a distribution kernel.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use kmoddep::kerman::{parse_deps_slice, KernelInfo, KernelInfoBuilder};
+use std::hint::black_box;
+
+/// Modules in layers, each depends on three modules of the layer below
+static LAYERS: usize = 10;
+static PER_LAYER: usize = 400;
+
+fn modpath(layer: usize, idx: usize) -> String {
+    format!(
+        "kernel/drivers/l{}/sub{}/mod_{}_{}.ko.zst",
+        layer,
+        idx % 20,
+        layer,
+        idx
+    )
+}
+
+fn modules_dep() -> String {
+    let mut data = String::new();
+    for layer in 0..LAYERS {
+        for idx in 0..PER_LAYER {
+            data.push_str(&modpath(layer, idx));
+            data.push(':');
+            if layer > 0 {
+                for dep in [idx, idx * 7 + 1, idx * 13 + 5] {
+                    data.push(' ');
+                    data.push_str(&modpath(layer - 1, dep % PER_LAYER));
+                }
+            }
+            data.push('\n');
+        }
+    }
+
+    data
+}
+
+fn kernel() -> KernelInfo {
+    KernelInfoBuilder::default()
+        .rootfs("/nonexistent")
+        .deps(parse_deps_slice(modules_dep().as_bytes()).unwrap())
+        .build("0.0.0-bench")
+        .unwrap()
+}
+
+fn bench_resolve(c: &mut Criterion) {
+    let ki = kernel();
+
+    // Short names, in the other spelling of the dashes, and partial paths
+    let short: Vec<String> = (0..PER_LAYER)
+        .map(|idx| format!("mod-{}-{}", idx % LAYERS, idx))
+        .collect();
+    let partial: Vec<String> = (0..PER_LAYER)
+        .map(|idx| format!("sub{}/mod_{}_{}.ko", idx % 20, idx % LAYERS, idx))
+        .collect();
+
+    assert!(short
+        .iter()
+        .chain(&partial)
+        .all(|x| ki.resolve_candidates(x).len() == 1));
+
+    c.bench_function("resolve_candidates short names", |b| {
+        b.iter(|| {
+            for name in &short {
+                black_box(ki.resolve_candidates(name));
+            }
+        })
+    });
+    c.bench_function("resolve_candidates partial paths", |b| {
+        b.iter(|| {
+            for name in &partial {
+                black_box(ki.resolve_candidates(name));
+            }
+        })
+    });
+
+    // Second layer from the bottom, so the walks are short and the resolution shows
+    let top: Vec<String> = (0..PER_LAYER).map(|idx| format!("mod-1-{}", idx)).collect();
+    c.bench_function("get_deps_for short names", |b| {
+        b.iter(|| black_box(ki.get_deps_for(&top)))
+    });
+
+    // Top layer, where the closures overlap the most: one call per name computes the direct
+    // dependencies of the shared subtrees over and over, one call for all the names
+    // memoizes them, though each name still walks its subtree
+    let overlapping: Vec<String> = (0..PER_LAYER / 4)
+        .map(|idx| format!("mod-{}-{}", LAYERS - 1, idx))
+        .collect();
+    let mut per_name: std::collections::HashMap<String, Vec<String>> = Default::default();
+    for name in &overlapping {
+        per_name.extend(ki.get_deps_for(std::slice::from_ref(name)));
+    }
+    assert_eq!(per_name, ki.get_deps_for(&overlapping));
+
+    c.bench_function("get_deps_for overlapping, per name", |b| {
+        b.iter(|| {
+            for name in &overlapping {
+                black_box(ki.get_deps_for(std::slice::from_ref(name)));
+            }
+        })
+    });
+    c.bench_function("get_deps_for overlapping, batched", |b| {
+        b.iter(|| black_box(ki.get_deps_for(&overlapping)))
+    });
+}
+
+criterion_group!(benches, bench_resolve);
+criterion_main!(benches);
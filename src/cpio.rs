@@ -0,0 +1,298 @@
+use crate::{error::Error, kerman::MOD_DEP_EXT};
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind, Read, Write},
+};
+
+/// CPIO "newc" format magic
+pub static CPIO_NEWC_MAGIC: &str = "070701";
+/// CPIO "newc" format with checksums magic
+pub static CPIO_CRC_MAGIC: &str = "070702";
+pub static CPIO_TRAILER: &str = "TRAILER!!!";
+
+// Longest entry name, which is read, as the kernel takes them (PATH_MAX)
+static CPIO_NAME_MAX: u64 = 4096;
+
+/// Writer of CPIO archives in "newc" format, as the kernel expects an initramfs.
+///
+/// Entries have no owner and zero modification time, so the same content
+/// always makes the same archive.
+pub struct CpioWriter<W: Write> {
+    out: W,
+    ino: u32,
+    dirs: HashSet<String>,
+    written: u64,
+}
+
+impl<W: Write> CpioWriter<W> {
+    pub fn new(out: W) -> Self {
+        CpioWriter {
+            out,
+            ino: 0,
+            dirs: HashSet::default(),
+            written: 0,
+        }
+    }
+
+    /// Add a directory with all its parents, which are not added yet.
+    pub fn add_dir(&mut self, path: &str) -> Result<(), Error> {
+        let path = path.trim_matches('/');
+        if path.is_empty() || self.dirs.contains(path) {
+            return Ok(());
+        }
+
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.add_dir(parent)?;
+        }
+
+        self.dirs.insert(path.to_string());
+        self.add_entry(path, 0o040755, 2, &[])
+    }
+
+    /// Add a regular file with its parent directories, which are not added yet.
+    pub fn add_file(&mut self, path: &str, mode: u32, data: &[u8]) -> Result<(), Error> {
+        let path = path.trim_matches('/');
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.add_dir(parent)?;
+        }
+
+        self.add_entry(path, 0o100000 | (mode & 0o7777), 1, data)
+    }
+
+    /// Write the trailer and return the size of the archive in bytes.
+    pub fn finish(mut self) -> Result<u64, Error> {
+        self.ino = 0;
+        self.add_entry(CPIO_TRAILER, 0, 1, &[])?;
+        self.out.flush()?;
+
+        Ok(self.written)
+    }
+
+    /// Get number of bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Write an entry header, its name and data, each padded to 4 bytes.
+    /// This is an internal method
+    fn add_entry(&mut self, name: &str, mode: u32, nlink: u32, data: &[u8]) -> Result<(), Error> {
+        if name != CPIO_TRAILER {
+            self.ino += 1;
+        }
+
+        let header = format!(
+            "{}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            CPIO_NEWC_MAGIC,
+            self.ino,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            0, // mtime
+            data.len(),
+            0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name.len() + 1,
+            0, // check
+        );
+
+        self.write_padded(&[header.as_bytes(), name.as_bytes(), b"\0"].concat())?;
+        self.write_padded(data)
+    }
+
+    /// Write data, padding it with zeroes to 4 bytes.
+    /// This is an internal method
+    fn write_padded(&mut self, data: &[u8]) -> Result<(), Error> {
+        let pad = (4 - data.len() % 4) % 4;
+        self.out.write_all(data)?;
+        self.out.write_all(&[0u8; 3][..pad])?;
+        self.written += (data.len() + pad) as u64;
+
+        Ok(())
+    }
+}
+
+/// Entry of a CPIO archive (see `read_cpio_modules`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpioEntry {
+    /// Path in the archive, e.g. "lib/modules/6.1.0/kernel/fs/ext4/ext4.ko.zst"
+    pub path: String,
+
+    /// Size of the data in bytes
+    pub size: u64,
+
+    /// Whether the entry is a module file (.ko, also compressed)
+    pub is_module: bool,
+}
+
+/// Read entries of a CPIO archive in "newc" format, e.g. written by `KernelInfo::write_cpio`
+/// or an uncompressed initramfs segment, up to its trailer. The data is skipped,
+/// so the archive is read as a stream without keeping it in memory.
+pub fn read_cpio_modules<R: Read>(mut input: R) -> Result<Vec<CpioEntry>, Error> {
+    let mut entries: Vec<CpioEntry> = vec![];
+    loop {
+        let mut header = [0u8; 110];
+        input.read_exact(&mut header)?;
+        if !header.starts_with(CPIO_NEWC_MAGIC.as_bytes())
+            && !header.starts_with(CPIO_CRC_MAGIC.as_bytes())
+        {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a CPIO newc archive").into());
+        }
+
+        // Fields are 8 hex digits each after the magic: mode is the 2nd, size the 7th,
+        // name size the 12th
+        let field = |idx: usize| {
+            std::str::from_utf8(&header[6 + idx * 8..14 + idx * 8])
+                .ok()
+                .and_then(|x| u64::from_str_radix(x, 16).ok())
+                .ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Malformed CPIO entry header")
+                })
+        };
+        let (mode, size, namesize) = (field(1)?, field(6)?, field(11)?);
+        if namesize > CPIO_NAME_MAX {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Too long CPIO entry name").into());
+        }
+
+        // Name is padded, so the header with it is aligned to 4 bytes
+        let mut name = vec![0u8; (namesize as usize + 110).next_multiple_of(4) - 110];
+        input.read_exact(&mut name)?;
+        name.truncate((namesize as usize).saturating_sub(1));
+        let path = String::from_utf8_lossy(&name).to_string();
+        if path == CPIO_TRAILER {
+            break;
+        }
+
+        let skip = size.next_multiple_of(4);
+        if io::copy(&mut (&mut input).take(skip), &mut io::sink())? != skip {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated CPIO archive").into());
+        }
+
+        let fname = path.rsplit('/').next().unwrap_or_default();
+        entries.push(CpioEntry {
+            is_module: mode & 0o170000 == 0o100000
+                && (fname.ends_with(".ko")
+                    || MOD_DEP_EXT
+                        .iter()
+                        .any(|fext| fname.ends_with(&format!(".ko{}", fext)))),
+            path,
+            size,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn entry(path: &str, size: u64, is_module: bool) -> CpioEntry {
+        CpioEntry {
+            path: path.to_string(),
+            size,
+            is_module,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut out: Vec<u8> = vec![];
+        let mut cpio = CpioWriter::new(&mut out);
+        // Names and data of all lengths modulo 4, to hit every padding
+        cpio.add_file("lib/modules/6.1.0/kernel/a.ko", 0o644, b"a")
+            .unwrap();
+        cpio.add_file("lib/modules/6.1.0/kernel/bb.ko.zst", 0o644, b"bb")
+            .unwrap();
+        cpio.add_file("lib/modules/6.1.0/kernel/ccc.ko.xz", 0o644, b"ccc")
+            .unwrap();
+        cpio.add_file("lib/modules/6.1.0/modules.dep", 0o644, b"dddd")
+            .unwrap();
+        cpio.add_file("lib/modules/6.1.0/kernel/empty.ko", 0o644, b"")
+            .unwrap();
+        let bytes = cpio.finish().unwrap();
+        assert_eq!(bytes, out.len() as u64);
+        assert_eq!(out.len() % 4, 0);
+
+        // Anything after the trailer, e.g. the next initramfs segment, is not read
+        out.extend(b"garbage");
+        assert_eq!(
+            read_cpio_modules(&out[..]).unwrap(),
+            vec![
+                entry("lib", 0, false),
+                entry("lib/modules", 0, false),
+                entry("lib/modules/6.1.0", 0, false),
+                entry("lib/modules/6.1.0/kernel", 0, false),
+                entry("lib/modules/6.1.0/kernel/a.ko", 1, true),
+                entry("lib/modules/6.1.0/kernel/bb.ko.zst", 2, true),
+                entry("lib/modules/6.1.0/kernel/ccc.ko.xz", 3, true),
+                entry("lib/modules/6.1.0/modules.dep", 4, false),
+                entry("lib/modules/6.1.0/kernel/empty.ko", 0, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn kernel_archive_round_trip() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/a.ko: kernel/b.ko\nkernel/b.ko:\nkernel/c.ko:\n",
+        );
+        let mut out: Vec<u8> = vec![];
+        let report = ki
+            .write_cpio(&names(&["a"]), &HashSet::default(), &mut out)
+            .unwrap();
+
+        let modules: Vec<String> = read_cpio_modules(&out[..])
+            .unwrap()
+            .into_iter()
+            .filter(|x| x.is_module)
+            .map(|x| x.path)
+            .collect();
+        assert_eq!(
+            modules,
+            report
+                .written
+                .iter()
+                .map(|x| format!("{}/{}", ki.get_archive_path(), x))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(modules.len(), 2);
+    }
+
+    #[test]
+    fn malformed_archives() {
+        let mut out: Vec<u8> = vec![];
+        let mut cpio = CpioWriter::new(&mut out);
+        cpio.add_file("kernel/a.ko", 0o644, &[0u8; 100]).unwrap();
+        cpio.finish().unwrap();
+
+        // No trailer
+        let no_trailer = &out[..out.len() - 124];
+        assert!(read_cpio_modules(no_trailer).is_err());
+
+        // Truncated data
+        assert!(matches!(
+            read_cpio_modules(&out[..150]),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof
+        ));
+
+        // Not a CPIO at all
+        assert!(matches!(
+            read_cpio_modules(&b"\x1f\x8b"[..].repeat(100)[..]),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData
+        ));
+
+        // Bad hex in the header
+        let mut bad = out.clone();
+        bad[14] = b'z';
+        assert!(matches!(
+            read_cpio_modules(&bad[..]),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData
+        ));
+    }
+}
@@ -0,0 +1,45 @@
+use thiserror::Error;
+
+/// Errors of the kmoddep API
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Filesystem or system call failure
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Data, which should be a text, is not a valid UTF-8
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+
+    /// Malformed line of a text file, e.g. /proc/modules. Lines are counted from 1.
+    #[error("Parse error at line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+
+    /// External "modinfo" utility is not available
+    #[error("modinfo was not found")]
+    ModinfoNotFound,
+
+    /// External "modinfo" utility failed or timed out
+    #[error("modinfo failed: {0}")]
+    ModinfoFailed(String),
+
+    /// Module was not found in the kernel modules
+    #[error("Module {0} was not found")]
+    ModuleNotFound(String),
+
+    /// Several modules were not found in the kernel modules
+    #[error("Modules {} were not found", .0.join(", "))]
+    ModulesNotFound(Vec<String>),
+
+    /// Modules of the kernel were not loaded, as it was created for the metadata only
+    #[error("Modules of kernel {0} are not loaded")]
+    NotLoaded(String),
+
+    /// Modules of the kernel failed to load, e.g. its modules.dep is corrupt or unreadable
+    #[error("Modules of kernel {0} cannot be loaded: {1}")]
+    LoadFailed(String, String),
+
+    /// Kernel or kernel modules directory was not found
+    #[error("Kernel {0} was not found")]
+    KernelNotFound(String),
+}
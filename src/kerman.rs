@@ -1,277 +1,5442 @@
+use crate::cpio::CpioWriter;
+use crate::error::Error;
+#[cfg(feature = "kmod-index")]
+use crate::kmodindex::{is_kmod_index, parse_kmod_index};
+use crate::modalias::{fnmatch, parse_aliases};
+use crate::moddeps::PROC_OSRELEASE;
+use crate::modfile::{
+    parse_modinfo, read_alloc_sections, read_arch, read_exported_symbols, read_modinfo,
+    read_module, read_undefined_symbols, ModCompression, ModDetails, ModParam, ModinfoCache,
+};
+use crate::modprobe::{ModprobeConfig, PROC_CMDLINE};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     ffi::OsStr,
-    fs::read_to_string,
-    io::Error,
+    fs::{metadata, read, read_dir, read_to_string, File},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-pub static MOD_D: &str = "/lib/modules";
-pub static MOD_DEP_F: &str = "modules.dep";
-pub static MOD_INFO_EXE: &str = "/usr/sbin/modinfo";
+pub static MOD_D: &str = "/lib/modules";
+pub static MOD_DEP_F: &str = "modules.dep";
+pub static MOD_TOP_D: &str = "kernel";
+pub static MOD_DEP_EXT: [&str; 3] = [".zst", ".xz", ".gz"];
+pub static MOD_INDEX_EXT: &str = ".bin";
+pub static MOD_ORDER_F: &str = "modules.order";
+pub static MOD_ALIAS_F: &str = "modules.alias";
+pub static MOD_SYMBOLS_F: &str = "modules.symbols";
+pub static MOD_SOFTDEP_F: &str = "modules.softdep";
+pub static MOD_WEAKDEP_F: &str = "modules.weakdep";
+pub static SYS_D: &str = "/sys";
+pub static SYS_DEVICES_D: &str = "/sys/devices";
+pub static PROC_MOUNTS: &str = "/proc/mounts";
+pub static FIRMWARE_D: &str = "/lib/firmware";
+pub static FIRMWARE_EXT: [&str; 3] = ["", ".zst", ".xz"];
+
+/// Default firmware search path, in the kernel search order. "{}" is the kernel version.
+pub static FIRMWARE_SEARCH_D: [&str; 4] = [
+    "/lib/firmware/updates/{}",
+    "/lib/firmware/updates",
+    "/lib/firmware/{}",
+    FIRMWARE_D,
+];
+pub static KCONFIG_F: &str = ".config";
+pub static BOOT_D: &str = "/boot";
+pub static PROC_CONFIG_GZ: &str = "/proc/config.gz";
+pub static MOD_BUILTIN_F: &str = "modules.builtin";
+pub static MOD_BUILTIN_MODINFO_F: &str = "modules.builtin.modinfo";
+
+// Unknown field of a modalias, which only a wildcard matches
+static UNKNOWN_FIELD: &str = "\x01";
+
+pub static GPL_COMPATIBLE_LICENSES: [&str; 6] = [
+    "GPL",
+    "GPL v2",
+    "GPL and additional rights",
+    "Dual BSD/GPL",
+    "Dual MIT/GPL",
+    "Dual MPL/GPL",
+];
+pub static MOD_INFO_EXE: &str = "/usr/sbin/modinfo";
+pub static MOD_INFO_LOCATIONS: [&str; 4] = ["/usr/sbin", "/sbin", "/usr/bin", "/bin"];
+pub static MOD_INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Build information of a kernel.
+/// Fields are `None` if none of the sources (modules, kernel config) has them.
+#[derive(Debug, Clone, Default)]
+pub struct KernelBuild {
+    /// Kernel release, e.g. "6.1.0-13-amd64"
+    pub release: Option<String>,
+
+    /// Full vermagic string, e.g. "6.1.0-13-amd64 SMP preempt mod_unload modversions"
+    pub vermagic: Option<String>,
+
+    /// Compiler the kernel was built with, e.g. "gcc (Debian 12.2.0-14) 12.2.0"
+    pub compiler: Option<String>,
+
+    /// Kernel is built with SMP support
+    pub smp: Option<bool>,
+
+    /// Kernel is built with preemption
+    pub preempt: Option<bool>,
+}
+
+/// Estimated memory footprint of a loaded module by its ELF sections
+#[derive(Debug, Clone, Default)]
+pub struct MemEstimate {
+    /// Loadable sections (e.g. ".text", ".data", ".bss") with their sizes
+    pub sections: Vec<(String, u64)>,
+
+    /// Total size of the sections, which stay in the memory after the module is loaded.
+    /// This approximates its size in /proc/modules.
+    pub total: u64,
+
+    /// Size of the ".init" sections, which are freed after the module is initialised
+    pub init_bytes: u64,
+}
+
+/// Modules, packed into a CPIO archive (see `KernelInfo::write_cpio`)
+#[derive(Debug, Clone, Default)]
+pub struct CpioReport {
+    /// Packed modules in a load order
+    pub written: Vec<String>,
+
+    /// Modules in a load order, which were skipped as already present
+    pub skipped: Vec<String>,
+
+    /// Size of the archive in bytes
+    pub bytes: u64,
+}
+
+/// Progress of a long operation over the modules, e.g. `KernelInfo::write_cpio_with_progress`
+#[derive(Debug, Clone)]
+pub struct Progress<'a> {
+    /// Number of the modules, which are done, including the current one
+    pub done: usize,
+
+    /// Number of all the modules
+    pub total: usize,
+
+    /// Module, which is just done
+    pub current_module: &'a str,
+}
+
+/// Estimated size of a module set with all its dependencies and firmware,
+/// as it would be added to an initramfs.
+#[derive(Debug, Clone, Default)]
+pub struct Footprint {
+    /// Total on-disk size of the modules
+    pub module_bytes: u64,
+
+    /// Total on-disk size of the firmware files
+    pub firmware_bytes: u64,
+
+    /// Number of the modules
+    pub module_count: usize,
+
+    /// Number of the found firmware files
+    pub firmware_count: usize,
+
+    /// Firmware files, required by the modules, but not found. They are not counted.
+    pub missing_firmware: Vec<String>,
+
+    /// Found firmware files with the firmware directories, they were found in
+    pub firmware_roots: HashMap<String, PathBuf>,
+}
+
+/// Firmware file, required by a module, and where it was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareFile {
+    /// Firmware name, as in the modinfo "firmware" field, e.g. "iwlwifi-3160-17.ucode"
+    pub name: String,
+
+    /// Firmware directory, where the file was found first. `None` if it is missing.
+    pub root: Option<PathBuf>,
+
+    /// Actual file, maybe compressed. `None` if it is missing.
+    pub path: Option<PathBuf>,
+}
+
+/// Difference between direct dependencies of a module in its own modinfo "depends"
+/// field and in modules.dep. Names are normalised short module names.
+#[derive(Debug, Clone, Default)]
+pub struct DependsMismatch {
+    /// Module path, relative to the kernel modules directory
+    pub module: String,
+
+    /// Dependencies, which are declared by the module, but missing in modules.dep
+    pub only_in_modinfo: Vec<String>,
+
+    /// Dependencies, which are in modules.dep, but not declared by the module
+    pub only_in_depfile: Vec<String>,
+}
+
+impl DependsMismatch {
+    /// Return true if both sources agree
+    pub fn is_consistent(&self) -> bool {
+        self.only_in_modinfo.is_empty() && self.only_in_depfile.is_empty()
+    }
+}
+
+/// What modprobe would do for a module, without doing it
+#[derive(Debug, Clone, Default)]
+pub struct ModprobePlan {
+    /// Modules, which would be inserted, in the order of insertion
+    pub modules: Vec<String>,
+
+    /// Firmware files, requested by the inserted modules
+    pub firmware: Vec<String>,
+
+    /// Modules or names with "install" directives, and their commands,
+    /// which would be run instead of inserting them, in the order of running
+    pub install: Vec<(String, String)>,
+
+    /// Modules, which match the requested alias, but are skipped as blacklisted
+    pub blacklisted: Vec<String>,
+}
+
+/// Resolution of a requested module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModResolution {
+    /// Module is found, with all its dependencies
+    Resolved {
+        full_path: String,
+        deps: Vec<String>,
+    },
+
+    /// Module is compiled into the kernel (modules.builtin)
+    Builtin,
+
+    /// Module is blacklisted in modprobe.d or on the kernel command line
+    Blacklisted,
+
+    /// Module is not found
+    NotFound,
+}
+
+/// Resolution of the requested modules
+#[derive(Debug, Clone, Default)]
+pub struct ResolveReport {
+    /// Requested names with their resolution, in the order of the request
+    pub modules: Vec<(String, ModResolution)>,
+}
+
+impl ResolveReport {
+    /// Get requested names, which were not found
+    pub fn not_found(&self) -> Vec<&str> {
+        self.modules
+            .iter()
+            .filter(|(_, r)| *r == ModResolution::NotFound)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+/// Result of the modules.dep consistency check against the disk
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Modules from modules.dep, which have no file on the disk
+    pub missing_files: Vec<String>,
+
+    /// Dependencies, which are not modules.dep entries themselves
+    pub dangling_deps: Vec<String>,
+}
+
+/// Diagnostics of a standalone modules.dep check (see `lint_modules_dep`). All lists are sorted.
+#[derive(Debug, Clone, Default)]
+pub struct LintReport {
+    /// Modules, which have no file in the modules directory
+    pub missing_files: Vec<String>,
+
+    /// Dependencies, which are not modules.dep entries themselves
+    pub dangling_deps: Vec<String>,
+
+    /// Modules, which depend on each other, each cycle sorted
+    pub cycles: Vec<Vec<String>>,
+
+    /// Modules, which have more than one entry
+    pub duplicate_keys: Vec<String>,
+}
+
+impl LintReport {
+    /// Return true if nothing is wrong
+    pub fn is_clean(&self) -> bool {
+        self.missing_files.is_empty()
+            && self.dangling_deps.is_empty()
+            && self.cycles.is_empty()
+            && self.duplicate_keys.is_empty()
+    }
+}
+
+/// Changed dependencies of a module (see `diff_deps`). Both lists are sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DepChange {
+    /// Dependencies, which are only in the new modules.dep
+    pub added: Vec<String>,
+
+    /// Dependencies, which are only in the old modules.dep
+    pub removed: Vec<String>,
+}
+
+/// Modules with changed dependencies between two modules.dep (see `diff_deps`)
+#[derive(Debug, Clone, Default)]
+pub struct DepDiff {
+    /// Changed dependencies by module paths, sorted
+    pub changed: BTreeMap<String, DepChange>,
+}
+
+impl DepDiff {
+    /// Return true if no module has changed dependencies
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty()
+    }
+}
+
+/// Modules with all their dependencies in a load order, which can be extended
+/// one module at a time without resolving the whole set again.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedSet {
+    order: Vec<String>,
+    seen: HashSet<String>,
+}
+
+impl ResolvedSet {
+    /// Add a module with its dependencies, which are not in the set yet.
+    /// Returns false if the module was not found.
+    pub fn add(&mut self, kernel: &KernelInfo, name: &str) -> bool {
+        let name = name.to_string();
+        let r_name = kernel.expand_module_name(&name);
+        if !kernel.is_resolved(r_name) {
+            return false;
+        }
+
+        kernel.get_mod_order(r_name, &mut self.seen, &mut self.order);
+        true
+    }
+
+    /// Get modules in a load order: every module comes after all its dependencies
+    pub fn modules(&self) -> &[String] {
+        &self.order
+    }
+
+    /// Return true if a module with a given path is in the set
+    pub fn contains(&self, path: &str) -> bool {
+        self.seen.contains(path)
+    }
+
+    /// Get number of modules in the set
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Return true if the set has no modules
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Parsed modules.dep
+#[derive(Debug, Clone, Default)]
+pub struct ParsedDeps {
+    /// Dependencies in a format:
+    ///     "kernel/path/to/a/module.ko.zst" -> ["kernel/path/to/a/dep.ko.zst", ...]
+    pub deplist: HashMap<String, Vec<String>>,
+
+    /// Short names of the modules, which are dependencies to something else, e.g. "i2c-core"
+    pub lookup_deplist: HashSet<String>,
+}
+
+impl ParsedDeps {
+    /// Add a module with its dependencies.
+    /// This is an internal method
+    fn add(&mut self, modpath: &str, moddeps: &[&str]) {
+        self.lookup_deplist.extend(moddeps.iter().map(|x| {
+            let fname = x.split('/').next_back().unwrap_or(x);
+            fname.split_once('.').map_or(fname, |x| x.0).to_string()
+        }));
+        self.deplist.insert(
+            modpath.to_owned(),
+            moddeps.iter().map(|x| x.to_string()).collect(),
+        );
+    }
+}
+
+/// Builder for the KernelInfo, if its defaults need to be changed.
+/// Defaults are the same as of `KernelInfo::new` for the host filesystem.
+#[derive(Debug, Clone)]
+pub struct KernelInfoBuilder {
+    rootfs: String,
+    modules_dir: String,
+    dep_file: String,
+    top_dir: String,
+    modinfo_path: PathBuf,
+    modinfo_timeout: Duration,
+    config_root: Option<PathBuf>,
+    cmdline_path: Option<PathBuf>,
+    sys_root: PathBuf,
+    mounts_path: PathBuf,
+    firmware_dirs: Option<Vec<PathBuf>>,
+    cache_dir: Option<PathBuf>,
+    deps: Option<ParsedDeps>,
+    case_insensitive: bool,
+    metadata_only: bool,
+    concurrency: usize,
+}
+
+impl Default for KernelInfoBuilder {
+    fn default() -> Self {
+        KernelInfoBuilder {
+            rootfs: "".to_string(),
+            modules_dir: MOD_D.to_string(),
+            dep_file: MOD_DEP_F.to_string(),
+            top_dir: MOD_TOP_D.to_string(),
+            modinfo_path: PathBuf::from(MOD_INFO_EXE),
+            modinfo_timeout: MOD_INFO_TIMEOUT,
+            config_root: None,
+            cmdline_path: None,
+            sys_root: PathBuf::from(SYS_D),
+            mounts_path: PathBuf::from(PROC_MOUNTS),
+            firmware_dirs: None,
+            cache_dir: None,
+            deps: None,
+            case_insensitive: false,
+            metadata_only: false,
+            concurrency: 0,
+        }
+    }
+}
+
+impl KernelInfoBuilder {
+    /// Root path is either "/" for the host filesystem or a mountpoint
+    /// to the root filesystem.
+    pub fn rootfs(mut self, rootfs: &str) -> Self {
+        self.rootfs = rootfs.to_string();
+        self
+    }
+
+    /// Modules directory within the root filesystem. Default is "/lib/modules".
+    pub fn modules_dir(mut self, modules_dir: &str) -> Self {
+        self.modules_dir = modules_dir.to_string();
+        self
+    }
+
+    /// Name of the dependencies file within the kernel modules directory.
+    /// Default is "modules.dep", but it can be e.g. a staging "modules.dep.new".
+    /// An absolute path points to a dependencies file elsewhere.
+    pub fn dep_file(mut self, dep_file: &str) -> Self {
+        self.dep_file = dep_file.to_string();
+        self
+    }
+
+    /// Top directory of the in-tree modules within the kernel modules directory,
+    /// e.g. for a build tree with another layout. Default is "kernel".
+    pub fn top_dir(mut self, top_dir: &str) -> Self {
+        self.top_dir = top_dir.trim_matches('/').to_string();
+        self
+    }
+
+    /// Path to the external "modinfo" utility. Default is "/usr/sbin/modinfo".
+    /// If it does not exist, "modinfo" is searched in the standard locations and PATH.
+    pub fn modinfo_path(mut self, modinfo_path: &Path) -> Self {
+        self.modinfo_path = modinfo_path.to_path_buf();
+        self
+    }
+
+    /// Timeout for the external "modinfo" utility. Default is 5 seconds.
+    /// Timed out modinfo is treated as if the module was not resolved.
+    pub fn modinfo_timeout(mut self, timeout: Duration) -> Self {
+        self.modinfo_timeout = timeout;
+        self
+    }
+
+    /// Root of the module configuration (etc/modprobe.d etc). Default is the root filesystem.
+    pub fn config_root(mut self, config_root: &Path) -> Self {
+        self.config_root = Some(config_root.to_path_buf());
+        self
+    }
+
+    /// Kernel command line file, to take module blacklists from. Default is "/proc/cmdline",
+    /// but only for the running kernel on the host filesystem. Other kernels,
+    /// e.g. in a mounted root filesystem, have no command line by default.
+    pub fn cmdline_path(mut self, cmdline_path: &Path) -> Self {
+        self.cmdline_path = Some(cmdline_path.to_path_buf());
+        self
+    }
+
+    /// Root of the sysfs, e.g. a captured copy of it. Default is "/sys".
+    pub fn sys_root(mut self, sys_root: &Path) -> Self {
+        self.sys_root = sys_root.to_path_buf();
+        self
+    }
+
+    /// Mounted filesystems file, to take filesystem types from. Default is "/proc/mounts".
+    pub fn mounts_path(mut self, mounts_path: &Path) -> Self {
+        self.mounts_path = mounts_path.to_path_buf();
+        self
+    }
+
+    /// Firmware directory. Same as `firmware_dirs` with only one directory.
+    pub fn firmware_dir(mut self, firmware_dir: &Path) -> Self {
+        self.firmware_dirs = Some(vec![firmware_dir.to_path_buf()]);
+        self
+    }
+
+    /// Firmware directories in the search order, as the kernel firmware loader has them,
+    /// e.g. with its "path" parameter first. Default is `FIRMWARE_SEARCH_D` of the root filesystem.
+    pub fn firmware_dirs(mut self, firmware_dirs: &[PathBuf]) -> Self {
+        self.firmware_dirs = Some(firmware_dirs.to_vec());
+        self
+    }
+
+    /// Directory, where cached data can be kept between the runs. Default is none.
+    pub fn cache_dir(mut self, cache_dir: &Path) -> Self {
+        self.cache_dir = Some(cache_dir.to_path_buf());
+        self
+    }
+
+    /// Match module names regardless of their case, so "EXT4" is "ext4". Default is off.
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Set up only the version and the paths, without loading the modules.
+    /// Default is off. See `KernelInfo::metadata_only`.
+    pub fn metadata_only(mut self, metadata_only: bool) -> Self {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    /// Maximum number of the module files, which are read, decompressed and hashed at once
+    /// by the batch methods, e.g. `hash_modules` or `get_firmware_for`. 1 reads them one
+    /// by one, e.g. to not thrash a slow flash storage. Default (0) is the number of CPUs.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Already parsed dependencies (see `parse_deps`), e.g. of a modules.dep, fetched
+    /// over the network. Then modules.dep is not read and the kernel is always valid.
+    pub fn deps(mut self, deps: ParsedDeps) -> Self {
+        self.deps = Some(deps);
+        self
+    }
+
+    /// Create a KernelInfo for the kernel version.
+    pub fn build(self, kver: &str) -> Result<KernelInfo, Error> {
+        let rootpath = PathBuf::from(if self.rootfs.is_empty() {
+            "/"
+        } else {
+            &self.rootfs
+        });
+        KernelInfo {
+            version: kver.to_owned(),
+            path: crate::join_rootfs(&self.rootfs, &self.modules_dir),
+            modinfo_path: find_modinfo(&self.modinfo_path),
+            modinfo_timeout: self.modinfo_timeout,
+            config_root: self.config_root.unwrap_or_else(|| rootpath.clone()),
+            cmdline_path: self.cmdline_path.or_else(|| {
+                is_running_kernel(&rootpath, kver).then(|| PathBuf::from(PROC_CMDLINE))
+            }),
+            sys_root: self.sys_root,
+            mounts_path: self.mounts_path,
+            firmware_dirs: self.firmware_dirs.unwrap_or_else(|| {
+                FIRMWARE_SEARCH_D
+                    .iter()
+                    .map(|x| rootpath.join(x.replace("{}", kver).trim_start_matches('/')))
+                    .collect()
+            }),
+            cache_dir: self.cache_dir,
+            rootpath,
+            dep_path: PathBuf::from(self.dep_file),
+            top_dir: self.top_dir,
+            case_insensitive: self.case_insensitive,
+            metadata_only: self.metadata_only,
+            concurrency: match self.concurrency {
+                0 => thread::available_parallelism().map_or(1, |x| x.get()),
+                concurrency => concurrency,
+            },
+            tables: OnceLock::new(),
+        }
+        .init(self.deps)
+    }
+}
+
+/// Modules of a kernel from modules.dep and the rest, loaded on the first use.
+#[derive(Debug, Clone, Default)]
+struct ModTables {
+    is_valid: bool,
+    invalid_reason: Option<String>,
+    loaded: bool,
+    _ext: String,
+
+    // Modules by their file names with dashes and underscores unified:
+    //     "i2c_core.ko.zst" -> ["kernel/drivers/i2c/i2c-core.ko.zst"]
+    name_index: HashMap<String, Vec<String>>,
+    case_insensitive: bool,
+    top_dir: String,
+
+    // Module extensions, most used first: [".ko.zst", ".ko"]
+    extensions: Vec<String>,
+
+    // Dependencies list in a format:
+    //     "modulename" -> ["kernel/path/to/a/module.ko.zst", "kernel/other.ko.zst"]
+    deplist: HashMap<String, Vec<String>>,
+
+    // Dependencies list in a format:
+    //     "modulename" -> ["module", "other"]
+    lookup_deplist: HashSet<String>,
+
+    // Build order of modules from modules.order in a format:
+    //     "kernel/path/to/a/module.ko" -> rank
+    order: HashMap<String, usize>,
+
+    // Aliases from modules.alias in a format:
+    //     ("pci:v00008086d000010D3sv*sd*bc*sc*i*", "e1000e")
+    aliases: Vec<(String, String)>,
+
+    // Exported symbols from modules.symbols in a format:
+    //     "ptp_clock_register" -> "ptp"
+    symbols: HashMap<String, String>,
+
+    // Aliases from modules.alias and modprobe.d by modules in a format:
+    //     "kernel/drivers/net/e1000e.ko" -> ["pci:v00008086d000010D3sv*sd*bc*sc*i*"]
+    alias_index: HashMap<String, Vec<String>>,
+
+    // Modules, compiled into the kernel, from modules.builtin:
+    //     "kernel/fs/btrfs/btrfs.ko"
+    builtin: HashSet<String>,
+
+    // Number of modules, which have dependencies
+    with_deps: usize,
+
+    // Module configuration from modprobe.d and the kernel command line
+    modprobe: ModprobeConfig,
+}
+
+impl ModTables {
+    /// Index aliases from modules.alias and modprobe.d by the modules they resolve to.
+    /// Aliases of the modules, which are not in the tree, are skipped.
+    fn index_aliases(&mut self) {
+        let mut alias_index: HashMap<String, Vec<String>> = HashMap::default();
+        for (pattern, module) in self.aliases.iter().chain(&self.modprobe.aliases) {
+            if let Some(fmodname) = self.find_candidates(module).first() {
+                let aliases = alias_index.entry(fmodname.to_string()).or_default();
+                if !aliases.contains(pattern) {
+                    aliases.push(pattern.to_owned());
+                }
+            }
+        }
+
+        self.alias_index = alias_index;
+    }
+
+    /// Find all full paths to a module that match a given name or a partial path.
+    /// Example: "dup" may be resolved as both "kernel/drivers/a/dup.ko"
+    /// and "kernel/drivers/b/dup.ko".
+    fn find_candidates(&self, name: &str) -> Vec<&String> {
+        // Modules may have different extensions, so try all that are present:
+        // "sunrpc" or "sunrpc.ko" -> "sunrpc.ko", "sunrpc.ko.zst"
+        // Names with compression already, e.g. "sunrpc.ko.xz", are taken as is.
+        let m_names: Vec<String> = match name.strip_suffix(".ko") {
+            None if name.contains(".ko") => vec![name.to_owned()],
+            stripped => {
+                let base = stripped.unwrap_or(name);
+                let mut m_names: Vec<String> = self
+                    .extensions
+                    .iter()
+                    .map(|fext| format!("{}{}", base, fext))
+                    .collect();
+                if m_names.is_empty() {
+                    m_names.push(format!("{}{}", base, self._ext));
+                }
+                m_names
+            }
+        };
+
+        let mut candidates: Vec<&String> = vec![];
+        for mut m_name in m_names {
+            if m_name.starts_with(&format!("{}/", self.top_dir)) {
+                if let Some((fmodname, _)) = self.deplist.get_key_value(&m_name) {
+                    candidates.push(fmodname);
+                }
+            } else {
+                // name or partial path, matched by whole directories:
+                // "sunrpc.ko" -> "/sunrpc.ko", "net/sunrpc.ko" -> "/net/sunrpc.ko"
+                if !m_name.starts_with('/') {
+                    m_name = format!("/{}", m_name);
+                }
+
+                // Dashes and underscores in module file names are the same for the kernel,
+                // also mixed: "foo_bar_baz" is "foo_bar-baz.ko". The index has them unified,
+                // so only the directory part is left to match.
+                let (m_dir, m_base) = m_name.split_at(m_name.rfind('/').unwrap_or(0) + 1);
+                for fmodname in self
+                    .name_index
+                    .get(&self.index_key(m_base))
+                    .into_iter()
+                    .flatten()
+                {
+                    // Modules directly at the root have no directory, but match "/" too
+                    let f_dir =
+                        format!("/{}", &fmodname[..fmodname.rfind('/').map_or(0, |x| x + 1)]);
+                    if f_dir.ends_with(m_dir)
+                        || (self.case_insensitive
+                            && f_dir.to_lowercase().ends_with(&m_dir.to_lowercase()))
+                    {
+                        candidates.push(fmodname);
+                    }
+                }
+            }
+        }
+        candidates.sort_by_key(|x| (self.order_rank(x).unwrap_or(usize::MAX), *x));
+
+        candidates
+    }
+
+    /// Get rank of a module in the build order (see `KernelInfo::module_order_rank`).
+    /// This is an internal method
+    fn order_rank(&self, path: &str) -> Option<usize> {
+        self.order.get(strip_compression(path)).copied()
+    }
+
+    /// Get a key of a module in the name index.
+    /// This is an internal method
+    fn index_key(&self, path: &str) -> String {
+        if self.case_insensitive {
+            index_name(path).to_lowercase()
+        } else {
+            index_name(path)
+        }
+    }
+}
+
+/// Metadata about the kernel and details about it
+#[derive(Debug, Clone)]
+pub struct KernelInfo {
+    pub version: String,
+    rootpath: PathBuf,
+    path: PathBuf,
+    modinfo_path: Option<PathBuf>,
+    modinfo_timeout: Duration,
+    config_root: PathBuf,
+    cmdline_path: Option<PathBuf>,
+    sys_root: PathBuf,
+    mounts_path: PathBuf,
+    firmware_dirs: Vec<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    dep_path: PathBuf,
+    top_dir: String,
+    case_insensitive: bool,
+    metadata_only: bool,
+    concurrency: usize,
+    tables: OnceLock<ModTables>,
+}
+
+impl KernelInfo {
+    /// Creates an instance of a KernelInfo struct with the version
+    /// of the kernel and paths to required points for module analysis.
+    ///
+    /// Root path is either "/" for the host filesystem or a mountpoint
+    /// to the root filesystem.
+    ///
+    /// NOTE: The module resolver is very simple here and won't scale that much
+    ///       if a kernel will have millions of modules. But as of 2024 it
+    ///       works OK with those dozen of thousands as for a generator.
+    ///       Generated CPIO anyway will contain already sorted list.
+    ///
+    /// Modules are loaded on the first use, so this is cheap. A kernel, which modules
+    /// fail to load, is not valid with the error as the reason; `load` returns the error instead.
+    pub fn new(rootpath: &str, kver: &str) -> Result<Self, Error> {
+        KernelInfoBuilder::default().rootfs(rootpath).build(kver)
+    }
+
+    /// Create a KernelInfo with only the version and the paths, without parsing modules.dep
+    /// and the rest, e.g. just to list the kernels. Validity is then only a guess
+    /// by the kernel directory content (see `is_valid`).
+    ///
+    /// Methods, which resolve modules, have nothing to resolve, and those,
+    /// which fail, fail with `NotLoaded`, until the modules are loaded (see `load`).
+    pub fn metadata_only(rootpath: &str, kver: &str) -> Result<Self, Error> {
+        KernelInfoBuilder::default()
+            .rootfs(rootpath)
+            .metadata_only(true)
+            .build(kver)
+    }
+
+    /// Load the modules of a KernelInfo now, instead of on the first use, e.g. to get
+    /// the error, why they cannot be loaded. This also loads a KernelInfo, created
+    /// for the metadata only. Loaded KernelInfo is returned as is.
+    pub fn load(mut self) -> Result<Self, Error> {
+        if self.deps_loaded() {
+            return Ok(self);
+        }
+
+        self.metadata_only = false;
+        self.tables = OnceLock::from(self.load_all(None)?);
+
+        Ok(self)
+    }
+
+    /// Returns true if the modules are loaded already. They are loaded on the first use,
+    /// unless the KernelInfo is for the metadata only (see `load`).
+    pub fn deps_loaded(&self) -> bool {
+        self.tables.get().is_some_and(|x| x.loaded)
+    }
+
+    /// Initialise the KernelInfo. This can be ran only once per an instance.
+    /// Already parsed dependencies are loaded at once.
+    fn init(mut self, deps: Option<ParsedDeps>) -> Result<Self, Error> {
+        self.path = self.path.join(&self.version);
+        self.dep_path = self.path.join(&self.dep_path);
+        self.find_dep_path();
+        if deps.is_some() {
+            self.tables = OnceLock::from(self.load_all(deps)?);
+        }
+
+        Ok(self)
+    }
+
+    /// Get modules of the kernel, loading them on the first use.
+    /// If they fail to load, the kernel is not valid and the error is the reason.
+    /// This is an internal method
+    fn t(&self) -> &ModTables {
+        self.tables.get_or_init(|| {
+            if self.metadata_only {
+                return ModTables {
+                    is_valid: self.path.join(&self.top_dir).is_dir()
+                        || self.dep_path.is_file()
+                        || self.path.join(MOD_BUILTIN_F).is_file(),
+                    ..Default::default()
+                };
+            }
+
+            self.load_all(None).unwrap_or_else(|err| ModTables {
+                invalid_reason: Some(err.to_string()),
+                ..Default::default()
+            })
+        })
+    }
+
+    /// Load modules.dep and all the rest.
+    /// This is an internal method
+    fn load_all(&self, deps: Option<ParsedDeps>) -> Result<ModTables, Error> {
+        let mut t = ModTables {
+            case_insensitive: self.case_insensitive,
+            top_dir: self.top_dir.to_owned(),
+            ..Default::default()
+        };
+        self.load_deps(&mut t, deps)?;
+        self.load_order(&mut t)?;
+        self.load_aliases(&mut t)?;
+        self.load_symbols(&mut t)?;
+        self.load_builtin(&mut t)?;
+        self.load_modprobe_config(&mut t)?;
+        t.index_aliases();
+        t.loaded = true;
+
+        Ok(t)
+    }
+
+    /// Load module configuration from modprobe.d and the kernel command line.
+    /// Skip what is missing.
+    fn load_modprobe_config(&self, t: &mut ModTables) -> Result<(), Error> {
+        t.modprobe = ModprobeConfig::load(&self.config_root)?;
+
+        // Soft and weak dependencies from the modules themselves, modprobe.d overrides them
+        for dep_f in [MOD_SOFTDEP_F, MOD_WEAKDEP_F] {
+            let dep_path = self.path.join(dep_f);
+            if dep_path.is_file() {
+                t.modprobe.parse(&read_to_string(dep_path)?);
+            }
+        }
+
+        if let Some(Ok(cmdline)) = self.cmdline_path.as_ref().map(read_to_string) {
+            t.modprobe.parse_cmdline(&cmdline);
+        }
+
+        Ok(())
+    }
+
+    /// Return true if the module is blacklisted in modprobe.d or
+    /// on the kernel command line (modprobe.blacklist=, module_blacklist=).
+    pub fn is_blacklisted(&self, name: &str) -> bool {
+        self.t()
+            .modprobe
+            .blacklist
+            .contains(&normalize_module_name(name))
+    }
+
+    /// Load module aliases.
+    /// Skip if there is no modules.alias
+    fn load_aliases(&self, t: &mut ModTables) -> Result<(), Error> {
+        let alias_path = self.path.join(MOD_ALIAS_F);
+        if alias_path.is_file() {
+            t.aliases = parse_aliases(&read_to_string(alias_path)?);
+        }
+
+        Ok(())
+    }
+
+    /// Load symbols, exported by the modules.
+    /// Skip if there is no modules.symbols
+    fn load_symbols(&self, t: &mut ModTables) -> Result<(), Error> {
+        let symbols_path = self.path.join(MOD_SYMBOLS_F);
+        if symbols_path.is_file() {
+            // Same format as modules.alias: "alias symbol:<name> <module>"
+            for (pattern, module) in parse_aliases(&read_to_string(symbols_path)?) {
+                if let Some(symbol) = pattern.strip_prefix("symbol:") {
+                    t.symbols.entry(symbol.to_string()).or_insert(module);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get name of the module, which exports a symbol, as modules.symbols has it, e.g. "ptp"
+    /// for "ptp_clock_register". The symbol may also be given as "symbol:ptp_clock_register".
+    ///
+    /// Returns `None` if no module exports the symbol or the kernel ships no modules.symbols.
+    pub fn module_for_symbol(&self, symbol: &str) -> Option<String> {
+        self.t()
+            .symbols
+            .get(symbol.strip_prefix("symbol:").unwrap_or(symbol))
+            .cloned()
+    }
+
+    /// Load modules, compiled into the kernel.
+    /// Skip if there is no modules.builtin
+    fn load_builtin(&self, t: &mut ModTables) -> Result<(), Error> {
+        let builtin_path = self.path.join(MOD_BUILTIN_F);
+        if builtin_path.is_file() {
+            t.builtin = read_to_string(builtin_path)?
+                .lines()
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .collect();
+        }
+
+        Ok(())
+    }
+
+    /// Load build order of the modules.
+    /// Skip if there is no modules.order, which is the case for some kernels.
+    fn load_order(&self, t: &mut ModTables) -> Result<(), Error> {
+        let order_path = self.path.join(MOD_ORDER_F);
+        if !order_path.is_file() {
+            return Ok(());
+        }
+
+        for (rank, line) in read_to_string(order_path)?.lines().enumerate() {
+            let line = line.trim();
+            if !line.is_empty() {
+                t.order
+                    .entry(strip_compression(line).to_string())
+                    .or_insert(rank);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get rank of a module in the build order (modules.order), lower comes first.
+    /// The path is relative to the kernel modules directory, e.g. "kernel/fs/nfs/nfs.ko.zst",
+    /// and the compression extension is not taken into account.
+    ///
+    /// Returns `None` if the module is not in modules.order or the kernel ships none.
+    pub fn module_order_rank(&self, path: &str) -> Option<usize> {
+        self.t().order_rank(path)
+    }
+
+    /// Return current kernel info root path.
+    pub fn get_kernel_path(&self) -> PathBuf {
+        PathBuf::from(&self.path)
+    }
+
+    /// Return path to the external "modinfo" utility, used to resolve modules,
+    /// which are named differently on the disk. `None` means that modinfo is not
+    /// available on this system and such modules won't be resolved.
+    pub fn get_modinfo_path(&self) -> Option<&Path> {
+        self.modinfo_path.as_deref()
+    }
+
+    /// Return root of the module configuration (etc/modprobe.d etc).
+    pub fn get_config_root(&self) -> &Path {
+        &self.config_root
+    }
+
+    /// Return root of the sysfs (see `KernelInfoBuilder::sys_root`).
+    pub fn get_sys_root(&self) -> &Path {
+        &self.sys_root
+    }
+
+    /// Return maximum number of the module files, read at once
+    /// (see `KernelInfoBuilder::concurrency`).
+    pub fn get_concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Return directory for the cached data, if configured.
+    pub fn get_cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+
+    /// Get modules extension, e.g. ".ko" or ".ko.zst".
+    /// This is the same as `dominant_extension`.
+    pub fn extension(&self) -> &str {
+        self.dominant_extension()
+    }
+
+    /// Get the most used modules extension, e.g. ".ko.zst".
+    /// Usually all modules have the same extension, but partially recompressed trees exist.
+    pub fn dominant_extension(&self) -> &str {
+        &self.t()._ext
+    }
+
+    /// Get all modules extensions, most used first.
+    pub fn extensions(&self) -> &[String] {
+        &self.t().extensions
+    }
+
+    /// Get extension of one module file name: ".ko" with its compression, if any,
+    /// e.g. ".ko.zst". All the extensions of a tree are counted by it (see `extensions`).
+    ///
+    /// Names without ".ko" segment (or no name at all) get the plain ".ko".
+    fn get_fext(&self, fname: Option<&OsStr>) -> String {
+        format!(
+            ".ko{}",
+            fname
+                .map(|x| x.to_string_lossy())
+                .unwrap_or_default()
+                .rsplit_once(".ko")
+                .map_or("", |(_, l)| l)
+        )
+    }
+
+    /// Load module dependencies, unless they are already parsed.
+    /// Skip if there is no /lib/modules/<version>/kernel directory
+    /// (see `KernelInfoBuilder::top_dir`) and no modules.builtin
+    fn load_deps(&self, t: &mut ModTables, deps: Option<ParsedDeps>) -> Result<(), Error> {
+        let deps = match deps {
+            Some(deps) => deps,
+            None => {
+                // Other than the stock layout needs at least one existing module in modules.dep,
+                // unless all the modules are compiled into the kernel
+                let stock = self.get_kernel_path().join(&self.top_dir).is_dir()
+                    || self.path.join(MOD_BUILTIN_F).is_file();
+                if !stock && !self.dep_path.is_file() {
+                    t.invalid_reason = Some(format!(
+                        "No \"{}\" directory and no modules.dep",
+                        self.top_dir
+                    ));
+                    return Ok(());
+                }
+
+                let deps = if self.dep_path.is_file() {
+                    self.read_deps()?
+                } else {
+                    ParsedDeps::default()
+                };
+                if !stock
+                    && !deps
+                        .deplist
+                        .keys()
+                        .any(|x| self.get_kernel_path().join(x).is_file())
+                {
+                    t.invalid_reason = Some("No module of modules.dep exists".to_string());
+                    return Ok(());
+                }
+                deps
+            }
+        };
+
+        t.is_valid = true;
+        t.deplist = deps.deplist;
+        t.lookup_deplist = deps.lookup_deplist;
+
+        // Odd entries without .ko segment should not count as an extension
+        let mut ext_count: HashMap<String, usize> = HashMap::default();
+        for modpath in t.deplist.keys().filter(|x| x.contains(".ko")) {
+            let fext = self.get_fext(PathBuf::from(modpath).file_name());
+            *ext_count.entry(fext).or_insert(0) += 1;
+        }
+
+        t.with_deps = t.deplist.values().filter(|x| !x.is_empty()).count();
+        for modname in t.deplist.keys() {
+            t.name_index
+                .entry(t.index_key(modname))
+                .or_default()
+                .push(modname.to_owned());
+        }
+
+        let mut extensions: Vec<(String, usize)> = ext_count.into_iter().collect();
+        extensions.sort_by(|(ae, ac), (be, bc)| bc.cmp(ac).then(ae.cmp(be)));
+        t.extensions = extensions.into_iter().map(|(fext, _)| fext).collect();
+        t._ext = match t.extensions.first() {
+            Some(fext) => fext.to_owned(),
+            None => self.get_fext(None),
+        };
+
+        Ok(())
+    }
+
+    /// Find the dependencies file, if it is not there, then its compressed variant.
+    /// With "kmod-index" feature its binary index (modules.dep.bin) is preferred, if supported.
+    /// Returns true if any is found.
+    /// This is an internal method
+    fn find_dep_path(&mut self) -> bool {
+        #[cfg(feature = "kmod-index")]
+        {
+            use std::io::Read;
+
+            let bin_path = PathBuf::from(format!(
+                "{}{}",
+                self.dep_path.to_string_lossy(),
+                MOD_INDEX_EXT
+            ));
+            let mut header = [0u8; 8];
+            if File::open(&bin_path).is_ok_and(|mut x| x.read_exact(&mut header).is_ok())
+                && is_kmod_index(&header)
+            {
+                self.dep_path = bin_path;
+                return true;
+            }
+        }
+
+        if self.dep_path.is_file() {
+            return true;
+        }
+
+        match MOD_DEP_EXT
+            .iter()
+            .map(|fext| PathBuf::from(format!("{}{}", self.dep_path.to_string_lossy(), fext)))
+            .find(|x| x.is_file())
+        {
+            Some(dep_path) => {
+                self.dep_path = dep_path;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Read and parse modules.dep. If there is no such file,
+    /// its compressed version is used instead, e.g. modules.dep.zst.
+    /// A malformed binary index falls back to modules.dep, if there is one.
+    /// This is an internal method
+    fn read_deps(&self) -> Result<ParsedDeps, Error> {
+        #[cfg(feature = "kmod-index")]
+        if let Some(txt_path) = self
+            .dep_path
+            .to_str()
+            .and_then(|x| x.strip_suffix(MOD_INDEX_EXT))
+        {
+            let err = match parse_deps_index(&read(&self.dep_path)?) {
+                Ok(deps) => return Ok(deps),
+                Err(err) => err,
+            };
+            return match [""]
+                .into_iter()
+                .chain(MOD_DEP_EXT)
+                .map(|fext| PathBuf::from(format!("{}{}", txt_path, fext)))
+                .find(|x| x.is_file())
+            {
+                Some(txt_path) => read_deps_file(&txt_path),
+                None => Err(err),
+            };
+        }
+
+        read_deps_file(&self.dep_path)
+    }
+
+    /// Returns true if there are actual modules on the media for this kernel.
+    /// There are often kernel paths left after a kernel was not completely purged.
+    ///
+    /// A kernel is valid with the stock "kernel" directory (see `KernelInfoBuilder::top_dir`),
+    /// or, for other layouts (e.g. only "extra" or "updates"), if at least one module
+    /// of its modules.dep exists. A kernel with all the modules compiled in
+    /// is valid with just modules.builtin, also without modules.dep.
+    pub fn is_valid(&self) -> bool {
+        self.t().is_valid
+    }
+
+    /// Get the reason, why the kernel is not valid (see `is_valid`)
+    pub fn invalid_reason(&self) -> Option<&str> {
+        self.t().invalid_reason.as_deref()
+    }
+
+    /// Get number of the modules in modules.dep
+    pub fn module_count(&self) -> usize {
+        self.t().deplist.len()
+    }
+
+    /// Get number of the modules, compiled into the kernel (modules.builtin)
+    pub fn builtin_count(&self) -> usize {
+        self.t().builtin.len()
+    }
+
+    /// Get sorted modules, compiled into the kernel, as modules.builtin has them,
+    /// e.g. "kernel/fs/ext4/ext4.ko". See `builtin_count` for just their number.
+    pub fn builtin_modules(&self) -> Vec<String> {
+        let mut mods: Vec<String> = self.t().builtin.iter().map(|x| x.to_owned()).collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Get number of the modules, which have at least one dependency
+    pub fn with_deps_count(&self) -> usize {
+        self.t().with_deps
+    }
+
+    /// Get path of dependencies file, which is actually used:
+    /// modules.dep, its compressed version, e.g. modules.dep.zst, or its binary index
+    #[allow(dead_code)]
+    pub fn get_dep_path(&self) -> &Path {
+        &self.dep_path
+    }
+
+    /// Find a full path to a module
+    /// Example: "sunrpc.ko" will be resolved as "kernel/net/sunrpc/sunrpc.ko"
+    ///
+    /// Some modules are named differently on the disk than in the memory.
+    /// In this case they are tried to be resolved via external "modinfo".
+    fn expand_module_name<'a>(&'a self, name: &'a String) -> &'a String {
+        if let Some(fmodname) = self.find_candidates(name).first() {
+            return fmodname;
+        }
+
+        // No modinfo on the system: the best in-tree guess is all we have
+        let modinfo_path = match &self.modinfo_path {
+            Some(modinfo_path) => modinfo_path,
+            None => return name,
+        };
+
+        // Resolve against the analysed kernel, not the running one
+        let mut cmd = Command::new(modinfo_path);
+        cmd.arg("-k").arg(&self.version);
+        if self.rootpath != Path::new("/") {
+            cmd.arg("-b").arg(&self.rootpath);
+        }
+
+        // Failed or timed out modinfo is the same as not resolved
+        if let Ok(Some(out)) = run_with_timeout(cmd.arg(name), self.modinfo_timeout) {
+            let kernel_path = self
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| self.path.clone());
+            for line in String::from_utf8_lossy(&out).lines() {
+                let filename = match line.strip_prefix("filename:") {
+                    Some(filename) => PathBuf::from(filename.trim()),
+                    None => continue,
+                };
+
+                // A module of another kernel or from elsewhere is not a match
+                let filename = filename.canonicalize().unwrap_or(filename);
+                if let Ok(t_modname) = filename.strip_prefix(&kernel_path) {
+                    if let Some((fmodname, _)) = self
+                        .t()
+                        .deplist
+                        .get_key_value(t_modname.to_string_lossy().as_ref())
+                    {
+                        return fmodname;
+                    }
+                }
+            }
+        }
+
+        name
+    }
+
+    /// Returns true if a name, returned by `expand_module_name`, is resolved to a module
+    /// of modules.dep. Modules at the root of the tree have no directory in their paths,
+    /// so a path separator tells nothing.
+    /// This is an internal method
+    fn is_resolved(&self, r_name: &str) -> bool {
+        self.t().deplist.contains_key(r_name)
+    }
+
+    /// Find all full paths to a module that match a given name or a partial path.
+    /// Example: "dup" may be resolved as both "kernel/drivers/a/dup.ko"
+    /// and "kernel/drivers/b/dup.ko".
+    ///
+    /// This is an internal method
+    fn find_candidates(&self, name: &str) -> Vec<&String> {
+        self.t().find_candidates(name)
+    }
+
+    /// Suggest up to 5 modules with names close to the given one, e.g. for
+    /// "did you mean ...?" when the module is not found. Names are compared
+    /// normalised (see `normalize_module_name`) and regardless of their case.
+    ///
+    /// Returns normalised module names, the closest first.
+    pub fn suggest(&self, name: &str) -> Vec<String> {
+        let name = normalize_module_name(name).to_lowercase();
+        let max_distance = (name.chars().count() / 3).max(2);
+
+        let mut names: Vec<String> = self
+            .t()
+            .deplist
+            .keys()
+            .map(|x| normalize_module_name(x))
+            .collect();
+        names.sort();
+        names.dedup();
+
+        let mut matches: Vec<(usize, String)> = names
+            .into_iter()
+            .map(|x| (edit_distance(&name, &x.to_lowercase()), x))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect();
+        matches.sort();
+
+        matches.into_iter().take(5).map(|(_, x)| x).collect()
+    }
+
+    /// Find all full paths to a module that match a given name or a partial path.
+    ///
+    /// Resolution picks the first one of these, so if more than one candidate
+    /// is returned, the name is ambiguous and should be qualified
+    /// with a longer path, e.g. "a/dup" instead of "dup".
+    ///
+    /// Dashes and underscores are the same in any mix, so "snd_hda_intel" has
+    /// both "kernel/sound/pci/snd_hda_intel.ko" and "kernel/sound/snd-hda-intel.ko"
+    /// as candidates, if both are there.
+    ///
+    /// Candidates are sorted by their rank in modules.order, just like modprobe does.
+    pub fn resolve_candidates(&self, name: &str) -> Vec<String> {
+        self.find_candidates(name)
+            .into_iter()
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
+    /// Find modules, which share the same name in different paths,
+    /// e.g. "kernel/drivers/a/dup.ko" and "kernel/drivers/b/dup.ko".
+    /// Requests for such modules by their short names are ambiguous.
+    ///
+    /// Returns normalised module names (see `normalize_module_name`),
+    /// mapped to their sorted module paths.
+    pub fn duplicate_basenames(&self) -> HashMap<String, Vec<String>> {
+        let mut names: HashMap<String, Vec<String>> = HashMap::default();
+        for modname in self.t().deplist.keys() {
+            names
+                .entry(normalize_module_name(modname))
+                .or_default()
+                .push(modname.to_owned());
+        }
+
+        names.retain(|_, paths| paths.len() > 1);
+        for paths in names.values_mut() {
+            paths.sort();
+        }
+
+        names
+    }
+
+    /// Resolve dependencies for one module, walking up to `max_depth` levels of direct dependencies.
+    /// Modules, whose dependencies were cut off by the limit, are added to `truncated`.
+    /// This is an internal method
+    fn get_mod_dep(
+        &self,
+        name: &str,
+        max_depth: usize,
+        truncated: &mut HashSet<String>,
+    ) -> Vec<String> {
+        self.get_mod_dep_shared(name, max_depth, truncated, &mut HashMap::default())
+    }
+
+    /// Same as `get_mod_dep`, but the direct dependencies of the visited modules
+    /// are memoized in `edges`, so resolving several modules with shared subtrees
+    /// drops the indirect dependencies of each module only once (see `get_direct_deps`).
+    /// Each module still walks its whole subtree, only over the memoized edges,
+    /// so the walk and its order are the same.
+    /// This is an internal method
+    fn get_mod_dep_shared<'a: 'b, 'b>(
+        &'a self,
+        name: &'b str,
+        max_depth: usize,
+        truncated: &mut HashSet<String>,
+        edges: &mut HashMap<&'b str, Vec<&'a String>>,
+    ) -> Vec<String> {
+        let mut mods: Vec<String> = vec![];
+        let mut seen: HashSet<&str> = HashSet::from([name]);
+        let mut level: Vec<&str> = vec![name];
+
+        for depth in 0.. {
+            let mut next: Vec<&str> = vec![];
+            for mname in level {
+                let mdeps: Vec<&String> = edges
+                    .entry(mname)
+                    .or_insert_with(|| self.get_direct_deps(mname))
+                    .iter()
+                    .copied()
+                    .filter(|x| !seen.contains(x.as_str()))
+                    .collect();
+                if mdeps.is_empty() {
+                    continue;
+                }
+
+                if depth == max_depth {
+                    truncated.insert(mname.to_string());
+                    continue;
+                }
+
+                for mdep in mdeps {
+                    if seen.insert(mdep) {
+                        mods.push(mdep.to_owned());
+                        next.push(mdep);
+                    }
+                }
+            }
+
+            if next.is_empty() {
+                break;
+            }
+            level = next;
+        }
+
+        mods
+    }
+
+    /// Resolve all module dependencies
+    ///
+    /// An ambiguous name (see `resolve_candidates`) is resolved to its first candidate.
+    /// Modules, compiled into the kernel, are omitted, also if modules.dep still lists them
+    /// as dependencies, so they are satisfied for all the resolution (see `get_load_order`).
+    pub fn get_deps_for(&self, names: &[String]) -> HashMap<String, Vec<String>> {
+        self.get_deps_for_bounded(names, usize::MAX).0
+    }
+
+    /// Get all dependencies of one module, sorted, without the module itself.
+    /// The name is resolved the same way as for `get_deps_for`.
+    ///
+    /// Returns `None` if the module cannot be resolved to a module on the disk.
+    pub fn module_closure(&self, name: &str) -> Option<Vec<String>> {
+        let name = name.to_string();
+        let r_name = self.expand_module_name(&name);
+        if !self.is_resolved(r_name) || self.is_builtin_path(r_name) {
+            return None;
+        }
+
+        let mut deps = self.get_mod_dep(r_name, usize::MAX, &mut HashSet::default());
+        deps.sort();
+
+        Some(deps)
+    }
+
+    /// Resolve labelled lists of modules, e.g. by their sources, and tell for every resolved
+    /// module, which lists pulled it in, also through the dependencies.
+    /// Names are resolved the same way as for `get_deps_for`.
+    ///
+    /// Returns module paths with their labels in the order of the lists.
+    pub fn resolve_with_provenance(
+        &self,
+        labeled: &[(String, Vec<String>)],
+    ) -> HashMap<String, Vec<String>> {
+        let mut provenance: HashMap<String, Vec<String>> = HashMap::default();
+        for (label, names) in labeled {
+            for (modname, moddeps) in self.get_deps_for(names) {
+                for modname in moddeps.into_iter().chain([modname]) {
+                    let labels = provenance.entry(modname).or_default();
+                    if !labels.contains(label) {
+                        labels.push(label.to_owned());
+                    }
+                }
+            }
+        }
+
+        provenance
+    }
+
+    /// Same as `get_deps_for`, but fails if any requested module is not found
+    /// and is neither compiled into the kernel, nor blacklisted (see `resolve_report`).
+    /// Blacklisted modules are left out, as they must not be loaded by a name.
+    ///
+    /// Fails with `ModuleNotFound` for one such module,
+    /// or with `ModulesNotFound` listing all of them.
+    pub fn get_deps_for_strict(
+        &self,
+        names: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, Error> {
+        let report = self.resolve_report(names);
+        let mut unresolved: Vec<String> = vec![];
+        let mut allowed: Vec<String> = vec![];
+        for (name, resolution) in report.modules {
+            match resolution {
+                ModResolution::NotFound => unresolved.push(name),
+                ModResolution::Blacklisted => {}
+                _ => allowed.push(name),
+            }
+        }
+
+        match unresolved.len() {
+            0 => Ok(self.get_deps_for(&allowed)),
+            1 => Err(Error::ModuleNotFound(unresolved.remove(0))),
+            _ => Err(Error::ModulesNotFound(unresolved)),
+        }
+    }
+
+    /// Same as `get_deps_for`, except every requested name is accounted for:
+    /// it is resolved with its dependencies, builtin, blacklisted or not found.
+    pub fn resolve_report(&self, names: &[String]) -> ResolveReport {
+        let builtin: HashSet<String> = self
+            .t()
+            .builtin
+            .iter()
+            .map(|x| normalize_module_name(x))
+            .collect();
+
+        let mut report = ResolveReport::default();
+        for kmodname in names {
+            let r_kmodname = self.expand_module_name(kmodname);
+            let resolution = if self.is_blacklisted(kmodname) {
+                ModResolution::Blacklisted
+            } else if self.is_resolved(r_kmodname) && !self.is_builtin_path(r_kmodname) {
+                ModResolution::Resolved {
+                    full_path: r_kmodname.to_owned(),
+                    deps: self.get_mod_dep(r_kmodname, usize::MAX, &mut HashSet::default()),
+                }
+            } else if self.is_resolved(r_kmodname)
+                || builtin.contains(&normalize_module_name(kmodname))
+            {
+                ModResolution::Builtin
+            } else {
+                ModResolution::NotFound
+            };
+            report.modules.push((kmodname.to_owned(), resolution));
+        }
+
+        report
+    }
+
+    /// Returns true if a module can be fully assembled from the disk: the module
+    /// and all its dependencies have their files or are compiled into the kernel.
+    /// The name is resolved the same way as for `get_deps_for`.
+    pub fn is_resolvable(&self, name: &str) -> bool {
+        let builtin = |modname: &str| {
+            let modname = normalize_module_name(modname);
+            self.t()
+                .builtin
+                .iter()
+                .any(|x| normalize_module_name(x) == modname)
+        };
+
+        let r_name = self.expand_module_name(&name.to_string()).to_owned();
+        if !self.is_resolved(&r_name) {
+            return builtin(&r_name);
+        }
+
+        let kpath = self.get_kernel_path();
+        let mut mods = self.get_mod_dep(&r_name, usize::MAX, &mut HashSet::default());
+        mods.push(r_name);
+
+        mods.iter().all(|x| kpath.join(x).is_file() || builtin(x))
+    }
+
+    /// Check that a set of modules, e.g. a hand-picked list for an archive, has all
+    /// the dependencies of its modules. Unlike `get_deps_for`, the set is taken as it is
+    /// and is not expanded. Names are resolved the same way as for `get_deps_for`.
+    /// Modules, compiled into the kernel, need nothing and are always there as dependencies.
+    ///
+    /// Returns whether the set is closed and the sorted dependencies out of it.
+    /// Fails with `ModuleNotFound` for the first name, which is not a module.
+    pub fn is_closed(&self, names: &[String]) -> Result<(bool, Vec<String>), Error> {
+        let mut mods: HashSet<&String> = HashSet::default();
+        for name in names {
+            let r_name = self.expand_module_name(name);
+            if self.is_resolved(r_name) {
+                mods.insert(r_name);
+            } else if !self
+                .t()
+                .builtin
+                .iter()
+                .any(|x| normalize_module_name(x) == normalize_module_name(name))
+            {
+                return Err(Error::ModuleNotFound(name.to_owned()));
+            }
+        }
+
+        let mut outside: Vec<String> = mods
+            .iter()
+            .filter_map(|x| self.t().deplist.get(*x))
+            .flatten()
+            .filter(|x| !mods.contains(x) && !self.is_builtin_path(x))
+            .cloned()
+            .collect();
+        outside.sort();
+        outside.dedup();
+
+        Ok((outside.is_empty(), outside))
+    }
+
+    /// Same as `get_deps_for`, except the resolution stops after `max_depth` levels
+    /// of direct dependencies, which bounds the work on unexpectedly deep or untrusted trees.
+    ///
+    /// Returns the resolved tree and a sorted list of modules, whose dependencies
+    /// were truncated at the limit.
+    pub fn get_deps_for_bounded(
+        &self,
+        names: &[String],
+        max_depth: usize,
+    ) -> (HashMap<String, Vec<String>>, Vec<String>) {
+        let mut mod_tree: HashMap<String, Vec<String>> = HashMap::new();
+        let mut truncated: HashSet<String> = HashSet::default();
+        let mut edges: HashMap<&str, Vec<&String>> = HashMap::default();
+        for kmodname in names {
+            let r_kmodname = self.expand_module_name(kmodname);
+            if !self.is_resolved(r_kmodname)
+                || self.is_builtin_path(r_kmodname)
+                || mod_tree.contains_key(r_kmodname)
+            {
+                continue;
+            }
+
+            let r_deps = self.get_mod_dep_shared(r_kmodname, max_depth, &mut truncated, &mut edges);
+            mod_tree.insert(r_kmodname.to_owned(), r_deps);
+        }
+
+        let mut truncated: Vec<String> = truncated.into_iter().collect();
+        truncated.sort();
+
+        (mod_tree, truncated)
+    }
+
+    /// Get direct dependencies of a module.
+    ///
+    /// modules.dep lists all the dependencies of a module, including the dependencies
+    /// of its dependencies. Those are dropped here, so only the actual edges remain.
+    /// Dependencies, compiled into the kernel, are always there, so they are dropped too,
+    /// also if modules.dep still lists them. All the resolution walks these edges.
+    /// This is an internal method
+    fn get_direct_deps(&self, name: &str) -> Vec<&String> {
+        let mdeps: Vec<&String> = match self.t().deplist.get(name) {
+            Some(mdeps) => mdeps.iter().filter(|x| !self.is_builtin_path(x)).collect(),
+            None => return vec![],
+        };
+
+        // Dependencies, reachable from each of the dependencies through the listed ones
+        let listed: HashSet<&String> = mdeps.iter().copied().collect();
+        let reach: HashMap<&String, HashSet<&String>> = mdeps
+            .iter()
+            .map(|&mdep| {
+                let mut seen: HashSet<&String> = HashSet::default();
+                let mut stack: Vec<&String> = vec![mdep];
+                while let Some(mname) = stack.pop() {
+                    for d_mdep in self.t().deplist.get(mname).into_iter().flatten() {
+                        if listed.contains(d_mdep) && seen.insert(d_mdep) {
+                            stack.push(d_mdep);
+                        }
+                    }
+                }
+                (mdep, seen)
+            })
+            .collect();
+
+        // A dependency is indirect, if it comes through another dependency.
+        // Dependencies in a cycle (broken modules.dep) reach each other, so they are all kept
+        // to not lose them.
+        mdeps
+            .iter()
+            .filter(|x| {
+                !mdeps
+                    .iter()
+                    .any(|y| y != *x && reach[y].contains(*x) && !reach[*x].contains(y))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Same as `get_deps_for`, except modules from the `exclude` list are pruned
+    /// from the resolution together with the dependencies reachable only through them.
+    /// Dependencies, that are also reachable by another path, stay.
+    ///
+    /// Requested modules, that are excluded themselves, are omitted altogether.
+    pub fn get_deps_for_excluding(
+        &self,
+        names: &[String],
+        exclude: &[String],
+    ) -> HashMap<String, Vec<String>> {
+        let excluded: HashSet<&String> = exclude
+            .iter()
+            .map(|x| self.expand_module_name(x))
+            .filter(|x| self.is_resolved(x))
+            .collect();
+
+        let mut mod_tree: HashMap<String, Vec<String>> = HashMap::new();
+        for kmodname in names {
+            let r_kmodname = self.expand_module_name(kmodname);
+            if !self.is_resolved(r_kmodname) || excluded.contains(r_kmodname) {
+                continue;
+            }
+
+            let mut mod_deps: HashSet<&String> = HashSet::default();
+            let mut stack: Vec<&String> = self.get_direct_deps(r_kmodname);
+            while let Some(mdep) = stack.pop() {
+                if !excluded.contains(mdep) && mod_deps.insert(mdep) {
+                    stack.extend(self.get_direct_deps(mdep));
+                }
+            }
+
+            mod_tree.insert(
+                r_kmodname.to_owned(),
+                mod_deps.into_iter().map(|x| x.to_owned()).collect(),
+            );
+        }
+
+        mod_tree
+    }
+
+    /// Resolve dependencies for a module file. If the file is within this kernel
+    /// modules directory, its modules.dep entry is used. Otherwise (e.g. a freshly built
+    /// out-of-tree module) dependencies are taken from its own modinfo "depends" field
+    /// and resolved against this kernel.
+    ///
+    /// Returns sorted flattened dependency closure, without the module itself.
+    pub fn get_deps_for_path(&self, ko_path: &Path) -> Result<Vec<String>, Error> {
+        let kpath = self.get_kernel_path();
+        let kpath = kpath.canonicalize().unwrap_or(kpath);
+        let ko_path = ko_path.canonicalize()?;
+
+        let mut deps: Vec<String> = match ko_path
+            .strip_prefix(&kpath)
+            .ok()
+            .and_then(|x| x.to_str())
+            .filter(|x| self.t().deplist.contains_key(*x))
+        {
+            Some(modname) => self
+                .get_deps_for(&[modname.to_string()])
+                .into_values()
+                .flatten()
+                .collect(),
+            None => {
+                let details = ModDetails {
+                    name: ko_path.to_string_lossy().to_string(),
+                    fields: read_modinfo(&read_module(&ko_path)?)?,
+                };
+                let names: Vec<String> = details
+                    .get("depends")
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string())
+                    .collect();
+                self.get_deps_for_flatten(&names)
+            }
+        };
+        deps.sort();
+
+        Ok(deps)
+    }
+
+    /// Return true if a given module is a dependency to something else
+    pub fn is_dep(&self, name: &str) -> bool {
+        self.t().lookup_deplist.contains(name)
+    }
+
+    /// Get sorted short names of all modules, that are dependencies to something else.
+    /// These are short names as in modules.dep file names, e.g. "i2c-core",
+    /// not full paths. This is the set behind `is_dep`.
+    pub fn referenced_modules(&self) -> Vec<String> {
+        let mut mods: Vec<String> = self
+            .t()
+            .lookup_deplist
+            .iter()
+            .map(|x| x.to_owned())
+            .collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Get sorted short names of all modules, that are not dependencies to anything else,
+    /// i.e. top-level modules, such as drivers and filesystems, rather than libraries.
+    /// Short names are as in `referenced_modules`, for full paths see `leaf_module_paths`.
+    pub fn leaf_modules(&self) -> Vec<String> {
+        let mut mods: Vec<String> = self
+            .leaf_module_paths()
+            .iter()
+            .map(|x| x.split('/').next_back().unwrap_or(x))
+            .map(|x| x.split_once('.').map(|x| x.0).unwrap_or(x).to_string())
+            .collect();
+        mods.sort();
+        mods.dedup();
+
+        mods
+    }
+
+    /// Same as `leaf_modules`, except returns sorted full module paths.
+    pub fn leaf_module_paths(&self) -> Vec<String> {
+        let referenced: HashSet<&String> = self.t().deplist.values().flatten().collect();
+        let mut mods: Vec<String> = self
+            .t()
+            .deplist
+            .keys()
+            .filter(|x| !referenced.contains(x))
+            .map(|x| x.to_owned())
+            .collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Same as `get_deps_for`, except returns flattened list
+    /// for all modules with their dependencies.
+    ///
+    /// The list is sorted, so it is the same between the runs.
+    /// For the modules in a load order, see `get_load_order`.
+    pub fn get_deps_for_flatten(&self, names: &[String]) -> Vec<String> {
+        let mut mods: Vec<String> = self.get_deps_closure(names).into_iter().collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Resolve all the modules with their dependencies at once, as `get_deps_for_flatten`
+    /// does, but unsorted. Dependencies, shared by the modules, are walked only once.
+    /// Modules, compiled into the kernel, have no files, so they are omitted,
+    /// also if modules.dep still lists them as dependencies (see `get_load_order`).
+    pub fn get_deps_closure(&self, names: &[String]) -> HashSet<String> {
+        let mut seen: HashSet<&str> = HashSet::default();
+        let mut level: Vec<&str> = vec![];
+        for kmodname in names {
+            let r_kmodname = self.expand_module_name(kmodname);
+            if self.is_resolved(r_kmodname)
+                && !self.is_builtin_path(r_kmodname)
+                && seen.insert(r_kmodname)
+            {
+                level.push(r_kmodname);
+            }
+        }
+
+        while !level.is_empty() {
+            let mut next: Vec<&str> = vec![];
+            for mname in level {
+                for mdep in self.get_direct_deps(mname) {
+                    if seen.insert(mdep) {
+                        next.push(mdep);
+                    }
+                }
+            }
+            level = next;
+        }
+
+        seen.into_iter().map(|x| x.to_string()).collect()
+    }
+
+    /// Get weak dependencies of a module: modules, which modprobe loads with it,
+    /// if they are available. These are from modules.weakdep and modprobe.d,
+    /// otherwise from the modinfo "weakdep" field of the module file.
+    ///
+    /// Returns full module paths. Weak dependencies, which are not found, are skipped.
+    pub fn get_weakdeps(&self, name: &str) -> Vec<String> {
+        let (modname, path) = match self.get_module_file(name) {
+            Ok(modfile) => modfile,
+            Err(_) => return vec![],
+        };
+
+        let weakdeps: Vec<String> = match self
+            .t()
+            .modprobe
+            .weakdeps
+            .get(&normalize_module_name(&modname))
+        {
+            Some(weakdeps) => weakdeps.to_owned(),
+            None => read_module(&path)
+                .ok()
+                .and_then(|data| read_modinfo(&data).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(key, _)| key == "weakdep")
+                .map(|(_, value)| value)
+                .collect(),
+        };
+
+        let mut mods: Vec<String> = vec![];
+        for weakdep in weakdeps {
+            if let Some(fmodname) = self.find_candidates(&weakdep).first() {
+                if !mods.contains(fmodname) {
+                    mods.push(fmodname.to_string());
+                }
+            }
+        }
+
+        mods
+    }
+
+    /// Same as `get_deps_for_flatten`, but also with the weak dependencies
+    /// of all the modules (see `get_weakdeps`) and their dependencies.
+    pub fn get_deps_for_flatten_with_weakdeps(&self, names: &[String]) -> Vec<String> {
+        let mut mods: Vec<String> = self.get_deps_for_flatten(names);
+        let mut seen: HashSet<String> = mods.iter().cloned().collect();
+        let mut idx = 0;
+        while idx < mods.len() {
+            for weakdep in self.get_weakdeps(&mods[idx]) {
+                if !seen.contains(&weakdep) {
+                    for fmodname in self.get_deps_for_flatten(&[weakdep]) {
+                        if seen.insert(fmodname.to_owned()) {
+                            mods.push(fmodname);
+                        }
+                    }
+                }
+            }
+            idx += 1;
+        }
+
+        mods
+    }
+
+    /// Resolve all module dependencies and return them as a flat list
+    /// in a load order: every module comes after all its dependencies.
+    /// Modules, compiled into the kernel, cannot be loaded, so they are omitted,
+    /// also if modules.dep still lists them as dependencies.
+    ///
+    /// To extend the list with more modules later, see `ResolvedSet`.
+    pub fn get_load_order(&self, names: &[String]) -> Vec<String> {
+        let mut order: Vec<String> = vec![];
+        let mut seen: HashSet<String> = HashSet::default();
+        for kmodname in names {
+            let r_kmodname = self.expand_module_name(kmodname);
+            if !self.is_resolved(r_kmodname) {
+                continue;
+            }
+
+            self.get_mod_order(r_kmodname, &mut seen, &mut order);
+        }
+
+        order
+    }
+
+    /// Same as `get_load_order`, except the list is in an unload order:
+    /// dependents are removed before their dependencies.
+    ///
+    /// This is always the exact reverse of `get_load_order` for the same input.
+    /// NOTE: for a cyclic dependency graph (broken modules.dep) the order among
+    ///       the modules within a cycle is arbitrary, but still mirrored.
+    pub fn get_unload_order(&self, names: &[String]) -> Vec<String> {
+        let mut order = self.get_load_order(names);
+        order.reverse();
+
+        order
+    }
+
+    /// Split a load order by named groups of the modules, e.g. for the separate
+    /// modules-load.d files. Each group gets its modules with their dependencies
+    /// in a load order (see `get_load_order`), but modules, which more than one group needs,
+    /// are in the "common" group instead, which comes first. Other groups are sorted by names.
+    ///
+    /// Dependencies of a common module are common too, so every group can be loaded
+    /// after the "common" one on its own. There is no "common" group, if it is empty.
+    pub fn load_order_grouped(
+        &self,
+        groups: &HashMap<String, Vec<String>>,
+    ) -> Vec<(String, Vec<String>)> {
+        let mut gnames: Vec<&String> = groups.keys().collect();
+        gnames.sort();
+
+        let mut owners: HashMap<String, usize> = HashMap::default();
+        let mut orders: Vec<(String, Vec<String>)> = vec![];
+        for gname in gnames {
+            let order = self.get_load_order(&groups[gname]);
+            for modname in &order {
+                *owners.entry(modname.to_owned()).or_default() += 1;
+            }
+            orders.push((gname.to_owned(), order));
+        }
+
+        // Dependencies of a shared module are shared as well, so the order is complete
+        let mut common: Vec<String> = owners
+            .iter()
+            .filter(|(_, count)| **count > 1)
+            .map(|(modname, _)| modname.to_owned())
+            .collect();
+        common.sort();
+        let common = self.get_load_order(&common);
+        for (_, order) in &mut orders {
+            order.retain(|x| owners[x] == 1);
+        }
+
+        if !common.is_empty() {
+            orders.insert(0, ("common".to_string(), common));
+        }
+
+        orders
+    }
+
+    /// Get all modules, which depend on a module, also through the other modules,
+    /// i.e. what breaks if the module is removed or blacklisted.
+    /// The name is resolved the same way as for `get_deps_for`.
+    ///
+    /// Returns sorted module paths without the module itself. A module, which is not found,
+    /// has no dependents.
+    pub fn reverse_closure(&self, name: &str) -> Vec<String> {
+        let r_name = match self.get_module_file(name) {
+            Ok((r_name, _)) => r_name,
+            Err(_) => return vec![],
+        };
+
+        let mut rdeps: HashMap<&str, Vec<&str>> = HashMap::default();
+        for (modname, moddeps) in &self.t().deplist {
+            for moddep in moddeps {
+                rdeps.entry(moddep).or_default().push(modname);
+            }
+        }
+
+        let mut seen: HashSet<&str> = HashSet::from([r_name.as_str()]);
+        let mut level: Vec<&str> = vec![&r_name];
+        while !level.is_empty() {
+            let mut next: Vec<&str> = vec![];
+            for mname in level {
+                for rdep in rdeps.get(mname).into_iter().flatten() {
+                    if seen.insert(rdep) {
+                        next.push(rdep);
+                    }
+                }
+            }
+            level = next;
+        }
+
+        let mut mods: Vec<String> = seen
+            .into_iter()
+            .filter(|x| *x != r_name)
+            .map(|x| x.to_string())
+            .collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Get the shortest chain of direct dependencies from one module to another,
+    /// e.g. to explain why a module pulls in the other one. Both names are resolved
+    /// the same way as for `get_deps_for`.
+    ///
+    /// Returns module paths from `from` to `to`, both included, or `None` if `to`
+    /// is not a dependency of `from` or any of them is not found. The same module
+    /// for both is a chain of just that module.
+    pub fn dependency_path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        let (r_from, _) = self.get_module_file(from).ok()?;
+        let (r_to, _) = self.get_module_file(to).ok()?;
+
+        // Module -> the module, it was reached from
+        let mut parents: HashMap<&str, &str> = HashMap::default();
+        let mut seen: HashSet<&str> = HashSet::from([r_from.as_str()]);
+        let mut level: Vec<&str> = vec![&r_from];
+        while !level.is_empty() && !seen.contains(r_to.as_str()) {
+            let mut next: Vec<&str> = vec![];
+            for mname in level {
+                for mdep in self.get_direct_deps(mname) {
+                    if seen.insert(mdep) {
+                        parents.insert(mdep, mname);
+                        next.push(mdep);
+                    }
+                }
+            }
+            level = next;
+        }
+
+        if !seen.contains(r_to.as_str()) {
+            return None;
+        }
+
+        let mut path: Vec<String> = vec![r_to.to_owned()];
+        let mut mname = r_to.as_str();
+        while let Some(parent) = parents.get(mname) {
+            path.push(parent.to_string());
+            mname = parent;
+        }
+        path.reverse();
+
+        Some(path)
+    }
+
+    /// Rank modules by the number of the modules, which depend on them directly,
+    /// e.g. to find the libraries, most of the modules pull in. Modules are sorted
+    /// by the number of their dependents, descending, then by their paths.
+    /// All modules are ranked, also those without dependents.
+    pub fn modules_by_dependent_count(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<&str, usize> = HashMap::default();
+        for (modname, moddeps) in &self.t().deplist {
+            counts.entry(modname).or_default();
+            for moddep in moddeps {
+                counts.entry(moddep).or_default();
+            }
+            for moddep in self.get_direct_deps(modname) {
+                *counts.entry(moddep).or_default() += 1;
+            }
+        }
+
+        let mut mods: Vec<(String, usize)> = counts
+            .into_iter()
+            .map(|(modname, count)| (modname.to_string(), count))
+            .collect();
+        mods.sort_by(|(an, ac), (bn, bc)| bc.cmp(ac).then(an.cmp(bn)));
+
+        mods
+    }
+
+    /// Post-order walk of the dependencies for one module.
+    /// Modules, compiled into the kernel, are skipped.
+    /// This is an internal method
+    fn get_mod_order(&self, name: &String, seen: &mut HashSet<String>, order: &mut Vec<String>) {
+        if self.is_builtin_path(name) || !seen.insert(name.to_owned()) {
+            return;
+        }
+
+        if let Some(mdeps) = self.t().deplist.get(name) {
+            for mdep in mdeps {
+                self.get_mod_order(mdep, seen, order);
+            }
+        }
+
+        order.push(name.to_owned());
+    }
+
+    /// Return true if a module path is compiled into the kernel. modules.builtin
+    /// has the paths without the compression extension.
+    /// This is an internal method
+    fn is_builtin_path(&self, modname: &str) -> bool {
+        let modname = MOD_DEP_EXT
+            .iter()
+            .find_map(|fext| modname.strip_suffix(fext))
+            .unwrap_or(modname);
+        self.t().builtin.contains(modname)
+    }
+
+    /// Format resolved dependencies of the modules as an indented tree, e.g.:
+    ///
+    /// ```text
+    /// kernel/fs/ext4/ext4.ko
+    /// ├── kernel/fs/jbd2/jbd2.ko
+    /// └── kernel/fs/mbcache.ko
+    /// ```
+    ///
+    /// Only direct dependencies are shown for each module, sorted by their path.
+    /// A module, that was already shown, is marked with "(*)" and its dependencies
+    /// are not repeated.
+    pub fn format_tree(&self, names: &[String]) -> String {
+        let mut out = String::new();
+        let mut seen: HashSet<String> = HashSet::default();
+        for kmodname in names {
+            let r_kmodname = self.expand_module_name(kmodname);
+            if !self.is_resolved(r_kmodname) {
+                continue;
+            }
+
+            self.format_tree_node(r_kmodname, "", "", &mut seen, &mut out);
+        }
+
+        out
+    }
+
+    /// Format one module of the tree with its dependencies.
+    /// This is an internal method
+    fn format_tree_node(
+        &self,
+        name: &str,
+        prefix: &str,
+        child_prefix: &str,
+        seen: &mut HashSet<String>,
+        out: &mut String,
+    ) {
+        out.push_str(prefix);
+        out.push_str(name);
+
+        let mut mdeps = self.get_direct_deps(name);
+        if !seen.insert(name.to_string()) {
+            if !mdeps.is_empty() {
+                out.push_str(" (*)");
+            }
+            out.push('\n');
+            return;
+        }
+        out.push('\n');
+
+        mdeps.sort();
+        for (idx, mdep) in mdeps.iter().enumerate() {
+            let (branch, next) = if idx + 1 == mdeps.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            self.format_tree_node(
+                mdep,
+                &format!("{}{}", child_prefix, branch),
+                &format!("{}{}", child_prefix, next),
+                seen,
+                out,
+            );
+        }
+    }
+
+    /// Write modules.dep for the modules with all their dependencies,
+    /// e.g. for a trimmed module set, installed without depmod.
+    ///
+    /// Modules are written in a load order, their dependencies are listed as in the
+    /// original modules.dep, except those, which are not in the module set.
+    pub fn write_modules_dep<W: Write>(&self, names: &[String], mut out: W) -> Result<(), Error> {
+        let mods = self.get_load_order(names);
+        let mod_set: HashSet<&String> = mods.iter().collect();
+        for modname in &mods {
+            let mdeps: Vec<&str> = self
+                .t()
+                .deplist
+                .get(modname)
+                .into_iter()
+                .flatten()
+                .filter(|x| mod_set.contains(x))
+                .map(|x| x.as_str())
+                .collect();
+
+            write_dep_line(&mut out, modname, &mdeps)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `write_modules_dep`, but the output depends only on the module set,
+    /// so it is byte for byte the same regardless of the order of the names and
+    /// of the original modules.dep, e.g. to compare or checksum it.
+    ///
+    /// Modules are sorted by their paths. Dependencies of a module come before their
+    /// own dependencies, as depmod lists them, and are sorted by their paths otherwise.
+    pub fn write_modules_dep_normalized<W: Write>(
+        &self,
+        names: &[String],
+        mut out: W,
+    ) -> Result<(), Error> {
+        let mut mods = self.get_load_order(names);
+        mods.sort();
+        let mod_set: HashSet<&String> = mods.iter().collect();
+        let mut mod_deps: HashMap<&String, Vec<&String>> = HashMap::default();
+        let mut dependents: HashMap<&String, Vec<&String>> = HashMap::default();
+        let mut pending: HashMap<&String, usize> = HashMap::default();
+        for modname in &mods {
+            let mut mdeps: Vec<&String> = self
+                .t()
+                .deplist
+                .get(modname)
+                .into_iter()
+                .flatten()
+                .filter(|x| mod_set.contains(x) && *x != modname)
+                .collect();
+            mdeps.sort();
+            mdeps.dedup();
+            for mdep in &mdeps {
+                dependents.entry(mdep).or_default().push(modname);
+            }
+            pending.insert(modname, mdeps.len());
+            mod_deps.insert(modname, mdeps);
+        }
+
+        // Rank in a load order: the first module by path, which dependencies are all loaded.
+        // A cycle (broken modules.dep) is broken at its first module by path.
+        let mut ready: BTreeSet<&String> = mods.iter().filter(|x| pending[x] == 0).collect();
+        let mut rank: HashMap<&String, usize> = HashMap::default();
+        while rank.len() < mods.len() {
+            let modname = match ready.pop_first() {
+                Some(modname) => modname,
+                None => mods.iter().find(|x| !rank.contains_key(x)).unwrap(),
+            };
+            if rank.contains_key(modname) {
+                continue;
+            }
+            rank.insert(modname, rank.len());
+            for dependent in dependents.get(modname).into_iter().flatten() {
+                let count = pending.get_mut(dependent).unwrap();
+                *count = count.saturating_sub(1);
+                if *count == 0 && !rank.contains_key(dependent) {
+                    ready.insert(dependent);
+                }
+            }
+        }
+
+        for modname in &mods {
+            let mut mdeps: Vec<&str> = mod_deps[modname].iter().map(|x| x.as_str()).collect();
+            mdeps.sort_by_key(|x| std::cmp::Reverse(rank[&x.to_string()]));
+            write_dep_line(&mut out, modname, &mdeps)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get path of the kernel modules directory within the root filesystem,
+    /// as it is in an archive: "lib/modules/<version>".
+    pub fn get_archive_path(&self) -> String {
+        self.path
+            .strip_prefix(&self.rootpath)
+            .unwrap_or(&self.path)
+            .to_string_lossy()
+            .trim_matches('/')
+            .to_string()
+    }
+
+    /// Find modules, which are already present in a base layer of an initramfs,
+    /// unpacked into a directory, so they can be excluded from an upper layer
+    /// (see `write_cpio`). Returns module paths, relative to the kernel modules directory.
+    pub fn present_modules_in(&self, base_dir: &Path) -> HashSet<String> {
+        let mod_d = base_dir.join(self.get_archive_path());
+        self.t()
+            .deplist
+            .keys()
+            .filter(|x| mod_d.join(x).is_file())
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
+    /// Resolve all dependencies for the modules and find those, which are missing
+    /// in a directory, e.g. an unpacked initramfs (see `modules_in_tree`).
+    /// Modules are matched by their short names, regardless of the layout.
+    ///
+    /// Returns sorted module paths, so an empty list means the directory has all the modules.
+    pub fn missing_in_tree(&self, names: &[String], dir: &Path) -> Result<Vec<String>, Error> {
+        let present: HashSet<String> = modules_in_tree(dir)?.into_iter().collect();
+        Ok(self
+            .get_deps_for_flatten(names)
+            .into_iter()
+            .filter(|x| !present.contains(&normalize_module_name(x)))
+            .collect())
+    }
+
+    /// Find module files in the kernel modules directory, which modules.dep does not list
+    /// at all, neither as modules, nor as dependencies, e.g. after a failed depmod.
+    /// Modprobe cannot load them by their names, so their presence means a stale index.
+    ///
+    /// Only the top module directory (see `KernelInfoBuilder::top_dir`) is searched.
+    /// Returns sorted paths, relative to the kernel modules directory.
+    pub fn orphan_files(&self) -> Result<Vec<String>, Error> {
+        let top_d = self.path.join(&self.top_dir);
+        if !top_d.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut known: HashSet<&String> = HashSet::default();
+        for (modname, moddeps) in &self.t().deplist {
+            known.insert(modname);
+            known.extend(moddeps);
+        }
+
+        let mut files: Vec<PathBuf> = vec![];
+        find_module_files(&top_d, &mut files)?;
+
+        let mut orphans: Vec<String> = files
+            .iter()
+            .filter_map(|x| x.strip_prefix(&self.path).ok())
+            .map(|x| x.to_string_lossy().to_string())
+            .filter(|x| !known.contains(x))
+            .collect();
+        orphans.sort();
+
+        Ok(orphans)
+    }
+
+    /// Returns true if any module file in the top module directory
+    /// (see `KernelInfoBuilder::top_dir`) is newer than the dependencies file,
+    /// so depmod should be run again.
+    ///
+    /// This is a heuristic by the modification times only, the content is not checked
+    /// (see `orphan_files` for that). Without the dependencies file, any module makes
+    /// it stale. Without the top module directory, it is never stale.
+    pub fn is_depmod_stale(&self) -> Result<bool, Error> {
+        let top_d = self.path.join(&self.top_dir);
+        if !top_d.is_dir() {
+            return Ok(false);
+        }
+
+        let mut files: Vec<PathBuf> = vec![];
+        find_module_files(&top_d, &mut files)?;
+
+        let dep_mtime = match metadata(&self.dep_path) {
+            Ok(meta) => meta.modified()?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(!files.is_empty()),
+            Err(err) => return Err(err.into()),
+        };
+
+        for fpath in files {
+            if metadata(fpath)?.modified()? > dep_mtime {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Resolve all dependencies for the modules and pack them as they are on the disk
+    /// into a CPIO archive, under "lib/modules/<version>" (see `get_archive_path`).
+    ///
+    /// Modules in `exclude` (paths, relative to the kernel modules directory),
+    /// e.g. already present in a base layer (see `present_modules_in`), are still resolved,
+    /// but not packed. The archive has the modules only, without modules.dep.
+    pub fn write_cpio<W: Write>(
+        &self,
+        names: &[String],
+        exclude: &HashSet<String>,
+        out: W,
+    ) -> Result<CpioReport, Error> {
+        self.write_cpio_with_progress(names, exclude, out, |_| {})
+    }
+
+    /// Same as `write_cpio`, but the progress is called back for each module,
+    /// also for a skipped one.
+    pub fn write_cpio_with_progress<W: Write, F: Fn(Progress)>(
+        &self,
+        names: &[String],
+        exclude: &HashSet<String>,
+        out: W,
+        progress: F,
+    ) -> Result<CpioReport, Error> {
+        let mut cpio = CpioWriter::new(out);
+        let mut report =
+            self.add_cpio_modules(&mut cpio, &self.get_load_order(names), exclude, progress)?;
+        report.bytes = cpio.finish()?;
+
+        Ok(report)
+    }
+
+    /// Pack modules as they are on the disk into a CPIO archive, which is being written,
+    /// under "lib/modules/<version>" (see `get_archive_path`), e.g. to add more files to it.
+    /// Modules are paths, relative to the kernel modules directory, packed in their order,
+    /// so usually a load order (see `get_load_order`). Modules in `exclude` are skipped,
+    /// the progress is called back for each module, as for `write_cpio_with_progress`.
+    ///
+    /// Returns the packed and skipped modules with the size of the archive so far.
+    pub fn add_cpio_modules<W: Write, F: Fn(Progress)>(
+        &self,
+        cpio: &mut CpioWriter<W>,
+        modnames: &[String],
+        exclude: &HashSet<String>,
+        progress: F,
+    ) -> Result<CpioReport, Error> {
+        let mut report = CpioReport::default();
+        let archive_path = self.get_archive_path();
+        for (idx, modname) in modnames.iter().enumerate() {
+            if exclude.contains(modname) {
+                report.skipped.push(modname.to_owned());
+            } else {
+                cpio.add_file(
+                    &format!("{}/{}", archive_path, modname),
+                    0o644,
+                    &read(self.get_kernel_path().join(modname))?,
+                )?;
+                report.written.push(modname.to_owned());
+            }
+
+            progress(Progress {
+                done: idx + 1,
+                total: modnames.len(),
+                current_module: modname,
+            });
+        }
+        report.bytes = cpio.written();
+
+        Ok(report)
+    }
+
+    /// Get all found modules, sorted.
+    ///
+    /// These are all modules listed in modules.dep, also those that appear only
+    /// as dependencies of other modules. To get just the modules themselves,
+    /// see `get_top_level_modules`.
+    pub fn get_disk_modules(&self) -> Vec<String> {
+        let mut buff: HashSet<String> = HashSet::default();
+
+        for (modname, moddeps) in &self.t().deplist {
+            buff.insert(modname.to_owned());
+            buff.extend(moddeps.to_owned());
+        }
+
+        let mut mods: Vec<String> = buff.iter().map(|x| x.to_string()).collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Get the installed modules: the modules.dep entries, sorted, without the entries,
+    /// which appear only as dependencies of other modules, as `get_disk_modules` has them.
+    /// Same as `get_disk_module_paths`.
+    pub fn get_top_level_modules(&self) -> Vec<String> {
+        self.get_disk_module_paths()
+    }
+
+    /// Get sorted full paths of all modules, relative to the kernel modules directory,
+    /// e.g. "kernel/fs/nfs/nfs.ko.zst". These are the modules.dep entries themselves,
+    /// without their dependencies mixed in, as `get_disk_modules` does.
+    ///
+    /// Join them with `get_kernel_path` to stat or hash every module on the disk.
+    pub fn get_disk_module_paths(&self) -> Vec<String> {
+        let mut mods: Vec<String> = self.t().deplist.keys().map(|x| x.to_owned()).collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Get all modules of a subsystem, sorted, e.g. "fs" for "kernel/fs/..."
+    /// or "drivers/net" for "kernel/drivers/net/...".
+    ///
+    /// The prefix matches whole path segments, so "net" does not match "kernel/network/...".
+    /// An empty prefix matches all in-tree modules.
+    pub fn modules_under(&self, prefix: &str) -> Vec<String> {
+        let prefix = match prefix.trim_matches('/') {
+            "" => format!("{}/", self.top_dir),
+            prefix => format!("{}/{}/", self.top_dir, prefix),
+        };
+        let mut mods: Vec<String> = self
+            .t()
+            .deplist
+            .keys()
+            .filter(|x| x.starts_with(&prefix))
+            .map(|x| x.to_owned())
+            .collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Group all modules by their subsystems, as `modules_under` takes them: "net" for
+    /// "kernel/net/...", but "drivers/gpu" for "kernel/drivers/gpu/...", as "drivers" is too broad.
+    /// Other modules are grouped by their top directory,
+    /// e.g. "extra", "updates" or "kernel" itself.
+    /// Modules, compiled into the kernel, have no paths and are not grouped.
+    ///
+    /// Modules in each group are sorted.
+    pub fn modules_by_subsystem(&self) -> BTreeMap<String, Vec<String>> {
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::default();
+        for modname in self.t().deplist.keys() {
+            let mut dirs: Vec<&str> = modname.split('/').filter(|x| !x.is_empty()).collect();
+            dirs.pop();
+            let group = match dirs.as_slice() {
+                [top, "drivers", sub, ..] if *top == self.top_dir => format!("drivers/{}", sub),
+                [top, sub, ..] if *top == self.top_dir => sub.to_string(),
+                [top, ..] => top.to_string(),
+                [] => "".to_string(),
+            };
+            groups.entry(group).or_default().push(modname.to_owned());
+        }
+
+        for mods in groups.values_mut() {
+            mods.sort();
+        }
+
+        groups
+    }
+
+    /// Same as `get_deps_for`, except for all modules of a subsystem (see `modules_under`).
+    pub fn get_deps_for_subsystem(&self, prefix: &str) -> HashMap<String, Vec<String>> {
+        self.get_deps_for(&self.modules_under(prefix))
+    }
+
+    /// Verify that modules.dep is consistent with the disk: every module has its file
+    /// and every dependency is a module itself. Inconsistencies usually mean
+    /// modules.dep is out of sync after a partial upgrade.
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+        let kpath = self.get_kernel_path();
+        for (modname, moddeps) in &self.t().deplist {
+            if !kpath.join(modname).try_exists()? {
+                report.missing_files.push(modname.to_owned());
+            }
+
+            for moddep in moddeps {
+                if !self.t().deplist.contains_key(moddep) && !report.dangling_deps.contains(moddep)
+                {
+                    report.dangling_deps.push(moddep.to_owned());
+                }
+            }
+        }
+        report.missing_files.sort();
+        report.dangling_deps.sort();
+
+        Ok(report)
+    }
+
+    /// Get full path on the disk to a module and its resolved name.
+    /// This is an internal method
+    fn get_module_file(&self, name: &str) -> Result<(String, PathBuf), Error> {
+        if !self.t().loaded {
+            if self.metadata_only {
+                return Err(Error::NotLoaded(self.version.to_owned()));
+            }
+
+            return Err(Error::LoadFailed(
+                self.version.to_owned(),
+                self.invalid_reason().unwrap_or_default().to_string(),
+            ));
+        }
+
+        let name = name.to_string();
+        let r_name = match self.t().deplist.get_key_value(&name) {
+            Some((r_name, _)) => r_name,
+            None => self.expand_module_name(&name),
+        };
+        if !self.t().deplist.contains_key(r_name) {
+            return Err(Error::ModuleNotFound(name));
+        }
+
+        Ok((r_name.to_owned(), self.get_kernel_path().join(r_name)))
+    }
+
+    /// Get full path on the disk to a module, resolved the same way as for `get_deps_for`.
+    /// Returns `None` if the module is not found or is compiled into the kernel.
+    pub fn resolve_path(&self, name: &str) -> Option<PathBuf> {
+        self.get_module_file(name).ok().map(|(_, path)| path)
+    }
+
+    /// Get static module information from the `.modinfo` section of the module file.
+    pub fn get_details(&self, name: &str) -> Result<ModDetails, Error> {
+        self.get_details_cached(name, &mut ModinfoCache::default())
+    }
+
+    /// Same as `get_details`, but the module file is read only if the cache has no details
+    /// for it yet. Then they are added to the cache.
+    pub fn get_details_cached(
+        &self,
+        name: &str,
+        cache: &mut ModinfoCache,
+    ) -> Result<ModDetails, Error> {
+        let (key, details) = self.lookup_details(name, cache)?;
+        cache.entry(key).or_insert_with(|| details.clone());
+
+        Ok(details)
+    }
+
+    /// Get details of the modules at once, as many as allowed (see `KernelInfoBuilder::concurrency`),
+    /// as `get_details_cached` does, in the same order, calling back the progress for each module.
+    /// This is an internal method
+    fn details_for<F: Fn(Progress) + Sync>(
+        &self,
+        names: &[String],
+        cache: &mut ModinfoCache,
+        progress: F,
+    ) -> Result<Vec<ModDetails>, Error> {
+        let found = {
+            let cache: &ModinfoCache = cache;
+            self.map_modules(names, |name| self.lookup_details(name, cache), progress)?
+        };
+
+        let mut details: Vec<ModDetails> = vec![];
+        for (key, mdetails) in found {
+            cache.entry(key).or_insert_with(|| mdetails.clone());
+            details.push(mdetails);
+        }
+
+        Ok(details)
+    }
+
+    /// Get details of a module from the cache, otherwise from its module file,
+    /// with the cache key.
+    /// This is an internal method
+    fn lookup_details(
+        &self,
+        name: &str,
+        cache: &ModinfoCache,
+    ) -> Result<(String, ModDetails), Error> {
+        let (r_name, path) = self.get_module_file(name)?;
+        let key = path.to_string_lossy().to_string();
+        if let Some(details) = cache.get(&key) {
+            return Ok((key, details.to_owned()));
+        }
+
+        let details = ModDetails {
+            name: r_name,
+            fields: read_modinfo(&read_module(&path)?)?,
+        };
+
+        Ok((key, details))
+    }
+
+    /// Run a job for each module with at most `concurrency` jobs at once
+    /// (see `KernelInfoBuilder::concurrency`). Results are in the order of the modules,
+    /// the progress is called back as the jobs are done. The first failed job fails all,
+    /// the jobs, which are not started yet, are not run.
+    /// This is an internal method
+    fn map_modules<T, J, F>(&self, names: &[String], job: J, progress: F) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        J: Fn(&str) -> Result<T, Error> + Sync,
+        F: Fn(Progress) + Sync,
+    {
+        let done = Mutex::new(0);
+        let run = |idx: usize| {
+            let res = job(&names[idx]);
+            let mut done = done.lock().unwrap_or_else(|err| err.into_inner());
+            *done += 1;
+            progress(Progress {
+                done: *done,
+                total: names.len(),
+                current_module: &names[idx],
+            });
+            res
+        };
+
+        let workers = self.concurrency.min(names.len());
+        if workers <= 1 {
+            return (0..names.len()).map(run).collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let results: Mutex<Vec<Option<Result<T, Error>>>> =
+            Mutex::new((0..names.len()).map(|_| None).collect());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let idx = next.fetch_add(1, AtomicOrdering::Relaxed);
+                    if idx >= names.len() || failed.load(AtomicOrdering::Relaxed) {
+                        break;
+                    }
+
+                    let res = run(idx);
+                    failed.fetch_or(res.is_err(), AtomicOrdering::Relaxed);
+                    results.lock().unwrap_or_else(|err| err.into_inner())[idx] = Some(res);
+                });
+            }
+        });
+
+        results
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Get architecture of a module from its ELF header, e.g. "x86_64" or "aarch64".
+    /// The module is read in-process, so a foreign architecture rootfs can be analysed on any host.
+    pub fn module_arch(&self, name: &str) -> Result<String, Error> {
+        let (_, path) = self.get_module_file(name)?;
+        read_arch(&read_module(&path)?)
+    }
+
+    /// Get sorted symbols, exported by a module, e.g. to find what it offers to the others
+    /// (for the other way round, see `module_for_symbol`). For GPL only exports,
+    /// see `read_exported_symbols`. A module with no symbol table has none.
+    pub fn exported_symbols(&self, name: &str) -> Result<Vec<String>, Error> {
+        let (_, path) = self.get_module_file(name)?;
+        Ok(read_exported_symbols(&read_module(&path)?)?
+            .into_iter()
+            .map(|(symbol, _)| symbol)
+            .collect())
+    }
+
+    /// Resolve all dependencies of a module and get the edges, where a proprietary module
+    /// (see `proprietary_modules`) uses GPL only exports of its dependency, as "module -> dependency".
+    /// The kernel refuses to load such a module.
+    ///
+    /// Edges are taken from the symbols the module uses (see `read_undefined_symbols`).
+    /// A module with no undefined symbols (e.g. stripped) is taken as using any exports,
+    /// so its edge is flagged, if the dependency exports only GPL symbols.
+    /// Returns sorted edges, so an empty list means no violations. Modules, which are missing on the disk, are skipped.
+    pub fn check_gpl_linkage(&self, name: &str) -> Result<Vec<String>, Error> {
+        let (r_name, _) = self.get_module_file(name)?;
+        let mut edges: Vec<String> = vec![];
+        for modname in self.get_deps_for_flatten(&[r_name]) {
+            let path = self.get_kernel_path().join(&modname);
+            if !path.is_file() {
+                continue;
+            }
+
+            let data = read_module(&path)?;
+            let license = read_modinfo(&data)?
+                .into_iter()
+                .find(|(key, _)| key == "license")
+                .map(|(_, license)| license)
+                .unwrap_or_else(|| "unknown".to_string());
+            if GPL_COMPATIBLE_LICENSES.contains(&license.as_str()) {
+                continue;
+            }
+
+            let undefined: HashSet<String> = read_undefined_symbols(&data)?.into_iter().collect();
+            for mdep in self.get_direct_deps(&modname) {
+                let dpath = self.get_kernel_path().join(mdep);
+                if !dpath.is_file() {
+                    continue;
+                }
+
+                let exports = read_exported_symbols(&read_module(&dpath)?)?;
+                let violation = if undefined.is_empty() {
+                    !exports.is_empty() && exports.iter().all(|(_, gpl)| *gpl)
+                } else {
+                    exports
+                        .iter()
+                        .any(|(symbol, gpl)| *gpl && undefined.contains(symbol))
+                };
+                if violation {
+                    edges.push(format!("{} -> {}", modname, mdep));
+                }
+            }
+        }
+        edges.sort();
+
+        Ok(edges)
+    }
+
+    /// Get modules of another architecture than expected, e.g. "x86_64" (see `module_arch`),
+    /// as a mixed tree would not boot. An empty expected architecture means the one
+    /// of the most modules. Modules, which are not ELF files, are of the "unknown" one.
+    ///
+    /// Returns sorted module paths. Modules, which are missing on the disk, are skipped.
+    pub fn verify_architecture(&self, expected: &str) -> Result<Vec<String>, Error> {
+        let mut modnames: Vec<&String> = self.t().deplist.keys().collect();
+        modnames.sort();
+
+        let mut archs: Vec<(&String, String)> = vec![];
+        let mut arch_count: HashMap<String, usize> = HashMap::default();
+        for modname in modnames {
+            let path = self.get_kernel_path().join(modname);
+            if !path.is_file() {
+                continue;
+            }
+
+            let arch = read_arch(&read_module(&path)?).unwrap_or_else(|_| "unknown".to_string());
+            *arch_count.entry(arch.to_owned()).or_insert(0) += 1;
+            archs.push((modname, arch));
+        }
+
+        let expected = if expected.is_empty() {
+            match arch_count
+                .into_iter()
+                .max_by(|(aa, ac), (ba, bc)| ac.cmp(bc).then(ba.cmp(aa)))
+            {
+                Some((arch, _)) => arch,
+                None => return Ok(vec![]),
+            }
+        } else {
+            expected.to_string()
+        };
+
+        Ok(archs
+            .into_iter()
+            .filter(|(_, arch)| *arch != expected)
+            .map(|(modname, _)| modname.to_owned())
+            .collect())
+    }
+
+    /// Estimate memory footprint of a module before it is loaded, by its loadable ELF sections.
+    /// Sections are not aligned, so the actual size is usually a bit bigger.
+    pub fn module_memory_estimate(&self, name: &str) -> Result<MemEstimate, Error> {
+        let (_, path) = self.get_module_file(name)?;
+        let mut estimate = MemEstimate {
+            sections: read_alloc_sections(&read_module(&path)?)?,
+            ..Default::default()
+        };
+        for (section, size) in &estimate.sections {
+            if section.starts_with(".init") {
+                estimate.init_bytes += size;
+            } else {
+                estimate.total += size;
+            }
+        }
+
+        Ok(estimate)
+    }
+
+    /// Get sorted firmware files, required by the modules, as in their modinfo "firmware" field.
+    /// Dependencies are not resolved, see `get_deps_for_flatten` for that.
+    /// To find the files in the firmware directories, see `locate_firmware`.
+    pub fn get_firmware_for(&self, names: &[String]) -> Result<Vec<String>, Error> {
+        self.get_firmware_for_cached(names, &mut ModinfoCache::default())
+    }
+
+    /// Same as `get_firmware_for`, but with the module details cached (see `get_details_cached`).
+    pub fn get_firmware_for_cached(
+        &self,
+        names: &[String],
+        cache: &mut ModinfoCache,
+    ) -> Result<Vec<String>, Error> {
+        self.firmware_for(names, cache, |_| {})
+    }
+
+    /// Same as `get_firmware_for`, but the progress is called back for each module.
+    pub fn get_firmware_for_with_progress<F: Fn(Progress) + Sync>(
+        &self,
+        names: &[String],
+        progress: F,
+    ) -> Result<Vec<String>, Error> {
+        self.firmware_for(names, &mut ModinfoCache::default(), progress)
+    }
+
+    /// Get firmware files, required by the modules, with the module details cached
+    /// and the progress called back for each module.
+    /// This is an internal method
+    fn firmware_for<F: Fn(Progress) + Sync>(
+        &self,
+        names: &[String],
+        cache: &mut ModinfoCache,
+        progress: F,
+    ) -> Result<Vec<String>, Error> {
+        let mut firmware: HashSet<String> = HashSet::default();
+        for details in self.details_for(names, cache, progress)? {
+            firmware.extend(
+                details
+                    .get_all("firmware")
+                    .into_iter()
+                    .map(|x| x.to_string()),
+            );
+        }
+
+        let mut firmware: Vec<String> = firmware.into_iter().collect();
+        firmware.sort();
+
+        Ok(firmware)
+    }
+
+    /// Get the firmware directories in the search order (see `KernelInfoBuilder::firmware_dirs`).
+    pub fn get_firmware_dirs(&self) -> &[PathBuf] {
+        &self.firmware_dirs
+    }
+
+    /// Get all firmware files, available in a firmware directory, sorted, e.g. "rtl_nic/rtl8153a-4.fw".
+    /// Compressed files are listed by their uncompressed names, as the modules require them.
+    ///
+    /// Firmware in "<firmware_root>/<kver>" is for this kernel version only and is listed
+    /// relative to it. Directories of the other installed kernel versions are skipped.
+    pub fn get_disk_firmware(&self, firmware_root: &Path) -> Result<Vec<String>, Error> {
+        let mut skip: HashSet<String> = HashSet::from([self.version.to_owned()]);
+        for fres in read_dir(firmware_root)? {
+            let fname = fres?.file_name().to_string_lossy().to_string();
+            if self.path.with_file_name(&fname).is_dir() {
+                skip.insert(fname);
+            }
+        }
+
+        let mut firmware: HashSet<String> = HashSet::default();
+        find_firmware_files(firmware_root, "", &skip, &mut firmware)?;
+
+        let kver_root = firmware_root.join(&self.version);
+        if kver_root.is_dir() {
+            find_firmware_files(&kver_root, "", &HashSet::default(), &mut firmware)?;
+        }
+
+        let mut firmware: Vec<String> = firmware.into_iter().collect();
+        firmware.sort();
+
+        Ok(firmware)
+    }
+
+    /// Find a firmware file in the first firmware directory, which has it, also compressed.
+    /// Returns the directory and the actual file.
+    /// This is an internal method
+    fn find_firmware(&self, fw_roots: &[PathBuf], name: &str) -> Option<(PathBuf, PathBuf)> {
+        fw_roots.iter().find_map(|fw_root| {
+            FIRMWARE_EXT
+                .iter()
+                .map(|fext| fw_root.join(format!("{}{}", name, fext)))
+                .find(|x| x.is_file())
+                .map(|x| (fw_root.to_owned(), x))
+        })
+    }
+
+    /// Get sorted firmware files, required by the modules (see `get_firmware_for`),
+    /// and find each of them in the firmware directories, in their order.
+    /// An empty list of the directories means the kernel ones (see `get_firmware_dirs`).
+    pub fn locate_firmware(
+        &self,
+        names: &[String],
+        firmware_roots: &[PathBuf],
+    ) -> Result<Vec<FirmwareFile>, Error> {
+        let firmware_roots = if firmware_roots.is_empty() {
+            &self.firmware_dirs
+        } else {
+            firmware_roots
+        };
+
+        Ok(self
+            .get_firmware_for(names)?
+            .into_iter()
+            .map(|name| {
+                let found = self.find_firmware(firmware_roots, &name);
+                FirmwareFile {
+                    root: found.as_ref().map(|(root, _)| root.to_owned()),
+                    path: found.map(|(_, path)| path),
+                    name,
+                }
+            })
+            .collect())
+    }
+
+    /// Resolve all dependencies for the modules and find firmware files, required by them,
+    /// which are missing in all the firmware directories, also as compressed.
+    /// An empty list of the directories means the kernel ones (see `get_firmware_dirs`).
+    ///
+    /// Returns modules with their sorted missing firmware files.
+    /// Modules without missing firmware are omitted, so an empty map means all is found.
+    pub fn missing_firmware(
+        &self,
+        names: &[String],
+        firmware_roots: &[PathBuf],
+    ) -> Result<HashMap<String, Vec<String>>, Error> {
+        let firmware_roots = if firmware_roots.is_empty() {
+            &self.firmware_dirs
+        } else {
+            firmware_roots
+        };
+
+        let mut missing: HashMap<String, Vec<String>> = HashMap::default();
+        for modname in self.get_deps_for_flatten(names) {
+            let mut fws: Vec<String> = self
+                .get_details(&modname)?
+                .get_all("firmware")
+                .into_iter()
+                .filter(|fw| self.find_firmware(firmware_roots, fw).is_none())
+                .map(|fw| fw.to_string())
+                .collect();
+            if !fws.is_empty() {
+                fws.sort();
+                fws.dedup();
+                missing.insert(modname, fws);
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Resolve all dependencies for the modules and drop those, which cannot work:
+    /// none of their required firmware files is found in "<firmware_root>/<version>"
+    /// or the firmware root itself, also compressed. Modules, which depend on a dropped one,
+    /// are dropped too, and so are the dependencies needed only by the dropped modules
+    /// (see `get_deps_for_excluding`). Modules, which require no firmware, are kept.
+    ///
+    /// Returns sorted kept and dropped module paths. To only report the missing firmware,
+    /// see `missing_firmware`.
+    pub fn prune_unsupported(
+        &self,
+        names: &[String],
+        firmware_root: &Path,
+    ) -> Result<(Vec<String>, Vec<String>), Error> {
+        let firmware_roots = [
+            firmware_root.join(&self.version),
+            firmware_root.to_path_buf(),
+        ];
+        let mods = self.get_deps_for_flatten(names);
+
+        let mut unsupported: HashSet<String> = HashSet::default();
+        for modname in &mods {
+            let details = self.get_details(modname)?;
+            let fws = details.get_all("firmware");
+            if !fws.is_empty()
+                && fws
+                    .iter()
+                    .all(|fw| self.find_firmware(&firmware_roots, fw).is_none())
+            {
+                unsupported.insert(modname.to_owned());
+            }
+        }
+
+        let exclude: Vec<String> = mods
+            .iter()
+            .filter(|x| {
+                unsupported.contains(*x)
+                    || self
+                        .t()
+                        .deplist
+                        .get(*x)
+                        .is_some_and(|mdeps| mdeps.iter().any(|y| unsupported.contains(y)))
+            })
+            .map(|x| x.to_owned())
+            .collect();
+
+        let mut kept: HashSet<String> = HashSet::default();
+        for (modname, mdeps) in self.get_deps_for_excluding(names, &exclude) {
+            kept.insert(modname);
+            kept.extend(mdeps);
+        }
+
+        let dropped: Vec<String> = mods
+            .iter()
+            .filter(|x| !kept.contains(*x))
+            .cloned()
+            .collect();
+        let mut kept: Vec<String> = kept.into_iter().collect();
+        kept.sort();
+
+        Ok((kept, dropped))
+    }
+
+    /// Resolve all dependencies for the modules and get their licenses (modinfo "license").
+    /// Modules without a license have "unknown".
+    pub fn licenses_for(&self, names: &[String]) -> Result<HashMap<String, String>, Error> {
+        self.licenses_for_cached(names, &mut ModinfoCache::default())
+    }
+
+    /// Same as `licenses_for`, but with the module details cached (see `get_details_cached`).
+    pub fn licenses_for_cached(
+        &self,
+        names: &[String],
+        cache: &mut ModinfoCache,
+    ) -> Result<HashMap<String, String>, Error> {
+        let mods = self.get_deps_for_flatten(names);
+        let mut licenses: HashMap<String, String> = HashMap::default();
+        for (modname, details) in mods.iter().zip(self.details_for(&mods, cache, |_| {})?) {
+            licenses.insert(
+                modname.to_owned(),
+                details.get("license").unwrap_or("unknown").to_string(),
+            );
+        }
+
+        Ok(licenses)
+    }
+
+    /// Resolve all dependencies for the modules and get those, which are proprietary
+    /// and taint the kernel: their license is not GPL-compatible, as the kernel sees it
+    /// (see `GPL_COMPATIBLE_LICENSES`), or unknown. Returns sorted module paths.
+    pub fn proprietary_modules(&self, names: &[String]) -> Result<Vec<String>, Error> {
+        let mut mods: Vec<String> = self
+            .licenses_for(names)?
+            .into_iter()
+            .filter(|(_, license)| !GPL_COMPATIBLE_LICENSES.contains(&license.as_str()))
+            .map(|(modname, _)| modname)
+            .collect();
+        mods.sort();
+
+        Ok(mods)
+    }
+
+    /// Resolve all dependencies of a module and get those, which are out-of-tree:
+    /// their module file has no modinfo "intree" field, as the kernel sees it.
+    /// The module itself is not taken into account.
+    ///
+    /// Returns sorted module paths, so an empty list means the module depends
+    /// only on the in-tree ones.
+    pub fn depends_on_out_of_tree(&self, name: &str) -> Result<Vec<String>, Error> {
+        let (r_name, _) = self.get_module_file(name)?;
+        let mut mods: Vec<String> = vec![];
+        for modname in self.get_deps_for_flatten(&[r_name.to_owned()]) {
+            if modname != r_name && self.get_details(&modname)?.get("intree") != Some("Y") {
+                mods.push(modname);
+            }
+        }
+
+        Ok(mods)
+    }
+
+    /// Estimate how much a module set adds to an initramfs: the modules,
+    /// all their dependencies and firmware, by their on-disk sizes.
+    /// Firmware is searched in the kernel firmware directories (see `get_firmware_dirs`).
+    pub fn estimated_footprint(&self, names: &[String]) -> Result<Footprint, Error> {
+        let mut footprint = Footprint::default();
+        let mods = self.get_deps_for_flatten(names);
+        for modname in &mods {
+            let (_, path) = self.get_module_file(modname)?;
+            footprint.module_bytes += metadata(path)?.len();
+            footprint.module_count += 1;
+        }
+
+        for fw in self.locate_firmware(&mods, &[])? {
+            match (fw.root, fw.path) {
+                (Some(fw_root), Some(fw_path)) => {
+                    footprint.firmware_bytes += metadata(fw_path)?.len();
+                    footprint.firmware_count += 1;
+                    footprint.firmware_roots.insert(fw.name, fw_root);
+                }
+                _ => footprint.missing_firmware.push(fw.name),
+            }
+        }
+
+        Ok(footprint)
+    }
+
+    /// Compare the modinfo "depends" field of a module with its dependencies in modules.dep.
+    /// A mismatch usually means that modules.dep is out of date and depmod should be ran again.
+    ///
+    /// NOTE: modules.dep also lists dependencies of the dependencies, so those are
+    ///       not a mismatch, as long as they come through a declared dependency.
+    pub fn check_depends_consistency(&self, name: &str) -> Result<DependsMismatch, Error> {
+        let details = self.get_details(name)?;
+        let declared: Vec<&str> = details
+            .get("depends")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .collect();
+        let listed: HashSet<String> = self
+            .t()
+            .deplist
+            .get(&details.name)
+            .map(|x| x.iter().map(|x| normalize_module_name(x)).collect())
+            .unwrap_or_default();
+
+        let mut mismatch = DependsMismatch {
+            module: details.name.to_owned(),
+            ..Default::default()
+        };
+
+        let mut reachable: HashSet<String> = HashSet::default();
+        for mdep in declared {
+            let mdep = normalize_module_name(mdep);
+            if !listed.contains(&mdep) {
+                mismatch.only_in_modinfo.push(mdep.to_owned());
+            }
+
+            let r_mdep = self.find_candidates(&mdep).first().copied();
+            if let Some(d_mdeps) = r_mdep.and_then(|x| self.t().deplist.get(x)) {
+                reachable.extend(d_mdeps.iter().map(|x| normalize_module_name(x)));
+            }
+            reachable.insert(mdep);
+        }
+        mismatch.only_in_depfile = listed.difference(&reachable).cloned().collect();
+        mismatch.only_in_modinfo.sort();
+        mismatch.only_in_depfile.sort();
+
+        Ok(mismatch)
+    }
+
+    /// Same as `check_depends_consistency`, but for all modules.
+    /// Returns only inconsistent modules, sorted.
+    pub fn check_all_depends_consistency(&self) -> Result<Vec<DependsMismatch>, Error> {
+        let mut mismatches: Vec<DependsMismatch> = vec![];
+        for modname in self.get_disk_module_paths() {
+            let mismatch = self.check_depends_consistency(&modname)?;
+            if !mismatch.is_consistent() {
+                mismatches.push(mismatch);
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Get module "srcversion", which is a checksum of the module source code.
+    /// It is used to find out if a binary module matches its sources,
+    /// and together with `hash_modules` is a suitable cache key.
+    ///
+    /// Some modules (stripped, builtin-wrapped) have no srcversion, which is not an error.
+    pub fn srcversion(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .get_details(name)?
+            .get("srcversion")
+            .map(|x| x.to_string()))
+    }
+
+    /// Get SHA-256 hashes of the decompressed module files, so the same module
+    /// has the same hash regardless of its compression.
+    ///
+    /// Returns a map "kernel/path/to/module.ko" -> "hex digest".
+    pub fn hash_modules(&self, names: &[String]) -> Result<HashMap<String, String>, Error> {
+        self.hash_modules_with_progress(names, |_| {})
+    }
+
+    /// Same as `hash_modules`, but the progress is called back for each module.
+    pub fn hash_modules_with_progress<F: Fn(Progress) + Sync>(
+        &self,
+        names: &[String],
+        progress: F,
+    ) -> Result<HashMap<String, String>, Error> {
+        Ok(self
+            .map_modules(
+                names,
+                |name| {
+                    let (r_name, path) = self.get_module_file(name)?;
+                    Ok((r_name, format!("{:x}", Sha256::digest(read_module(&path)?))))
+                },
+                progress,
+            )?
+            .into_iter()
+            .collect())
+    }
+
+    /// Returns true if a module has the same decompressed content in another kernel
+    /// module tree, e.g. an upgrade, which recompressed the modules from xz to zstd.
+    /// The module is resolved in each tree on its own, as its file name may differ.
+    ///
+    /// Fails with `ModuleNotFound` if any of the trees has no such module.
+    pub fn content_identical(&self, other: &KernelInfo, module: &str) -> Result<bool, Error> {
+        let (_, path) = self.get_module_file(module)?;
+        let (_, other_path) = other.get_module_file(module)?;
+
+        Ok(read_module(&path)? == read_module(&other_path)?)
+    }
+
+    /// Get modinfo of the modules compiled into the kernel from modules.builtin.modinfo,
+    /// as a list of (module, key, value).
+    ///
+    /// Kernels without modules.builtin.modinfo have no entries.
+    /// This is an internal method
+    fn get_builtin_modinfo(&self) -> Vec<(String, String, String)> {
+        let data = read(self.path.join(MOD_BUILTIN_MODINFO_F)).unwrap_or_default();
+        parse_modinfo(&data)
+            .into_iter()
+            .filter_map(|(mkey, value)| {
+                mkey.split_once('.')
+                    .map(|(module, key)| (module.to_string(), key.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// Get parameters of a module (see `ModDetails::parameters`).
+    ///
+    /// A module, compiled into the kernel, has its parameters in modules.builtin.modinfo
+    /// and takes them on the kernel command line as "<module>.<parameter>=<value>".
+    /// Fails with `ModuleNotFound` if the module is neither on the disk, nor builtin.
+    pub fn get_parameters(&self, name: &str) -> Result<Vec<ModParam>, Error> {
+        match self.get_details(name) {
+            Err(Error::ModuleNotFound(_)) => {}
+            details => return Ok(details?.parameters()),
+        }
+
+        let modname = normalize_module_name(name);
+        let fields: Vec<(String, String)> = self
+            .get_builtin_modinfo()
+            .into_iter()
+            .filter(|(module, _, _)| normalize_module_name(module) == modname)
+            .map(|(_, key, value)| (key, value))
+            .collect();
+        if fields.is_empty()
+            && !self
+                .t()
+                .builtin
+                .iter()
+                .any(|x| normalize_module_name(x) == modname)
+        {
+            return Err(Error::ModuleNotFound(name.to_string()));
+        }
+
+        Ok(ModDetails {
+            name: modname,
+            fields,
+        }
+        .parameters())
+    }
+
+    /// Get firmware files, required by the modules compiled into the kernel.
+    /// These are needed in an initramfs even when no loadable module requests them.
+    pub fn builtin_firmware(&self) -> Vec<String> {
+        let mut firmware: Vec<String> = self
+            .get_builtin_modinfo()
+            .into_iter()
+            .filter(|(_, key, _)| key == "firmware")
+            .map(|(_, _, value)| value)
+            .collect();
+        firmware.sort();
+        firmware.dedup();
+
+        firmware
+    }
+
+    /// Resolve a device modalias (e.g. "pci:v00008086d000010D3sv...") to the modules
+    /// that claim it in modules.alias. Returns full module paths, sorted by modules.order.
+    ///
+    /// Aliases of the modules compiled into the kernel are skipped, as they have no path.
+    pub fn resolve_alias(&self, modalias: &str) -> Vec<String> {
+        let mut mods: Vec<&String> = vec![];
+        for (pattern, module) in &self.t().aliases {
+            if fnmatch(pattern, modalias) {
+                if let Some(fmodname) = self.find_candidates(module).first() {
+                    if !mods.contains(fmodname) {
+                        mods.push(fmodname);
+                    }
+                }
+            }
+        }
+        mods.sort_by_key(|x| (self.module_order_rank(x).unwrap_or(usize::MAX), *x));
+
+        mods.into_iter().map(|x| x.to_owned()).collect()
+    }
+
+    /// Resolve a PCI device by its vendor and device IDs to the modules, which claim it
+    /// in modules.alias. Other fields of the device (subsystem, class) are unknown,
+    /// so only the aliases with wildcards in these fields match.
+    /// Returns full module paths, sorted by modules.order.
+    pub fn modules_for_pci_id(&self, vendor: u16, device: u16) -> Vec<String> {
+        self.resolve_alias(&format!(
+            "pci:v{:08X}d{:08X}sv{u}sd{u}bc{u}sc{u}i{u}",
+            vendor,
+            device,
+            u = UNKNOWN_FIELD
+        ))
+    }
+
+    /// Resolve a USB device by its vendor and product IDs to the modules, which claim it
+    /// in modules.alias. Same as `modules_for_pci_id`, only the aliases with wildcards
+    /// in the other fields (device release, classes) match.
+    pub fn modules_for_usb_id(&self, vendor: u16, product: u16) -> Vec<String> {
+        self.resolve_alias(&format!(
+            "usb:v{:04X}p{:04X}d{u}dc{u}dsc{u}dp{u}ic{u}isc{u}ip{u}in{u}",
+            vendor,
+            product,
+            u = UNKNOWN_FIELD
+        ))
+    }
+
+    /// Get modules, which provide a feature on their own, e.g. to offer them to enable,
+    /// as opposed to the helper libraries, other modules pull in. Sorted.
+    ///
+    /// This is a heuristic: a module is a feature, if no other module depends on it
+    /// and it has at least one alias, by which it is loaded on demand: a device
+    /// (e.g. "pci:...", "usb:..."), a device node ("devname:...", as modules.devname
+    /// is made of), a filesystem ("fs-...") etc, from modules.alias or modprobe.d.
+    pub fn feature_modules(&self) -> Vec<String> {
+        let referenced: HashSet<&String> = self.t().deplist.values().flatten().collect();
+        let mut mods: Vec<String> = self
+            .t()
+            .deplist
+            .keys()
+            .filter(|x| !referenced.contains(x))
+            .filter(|x| self.t().alias_index.get(*x).is_some_and(|x| !x.is_empty()))
+            .map(|x| x.to_owned())
+            .collect();
+        mods.sort();
+
+        mods
+    }
+
+    /// Get all aliases of a module from modules.alias and modprobe.d, sorted.
+    /// If there is no modules.alias, aliases are taken from the modinfo "alias" field
+    /// of the module file instead.
+    ///
+    /// Fails with `ModuleNotFound` if there is no such module.
+    pub fn aliases_for(&self, name: &str) -> Result<Vec<String>, Error> {
+        let (modname, _) = self.get_module_file(name)?;
+        let mut aliases = self
+            .t()
+            .alias_index
+            .get(&modname)
+            .cloned()
+            .unwrap_or_default();
+        if self.t().aliases.is_empty() {
+            aliases.extend(
+                self.get_details(&modname)?
+                    .get_all("alias")
+                    .into_iter()
+                    .map(|x| x.to_string()),
+            );
+        }
+        aliases.sort();
+        aliases.dedup();
+
+        Ok(aliases)
+    }
+
+    /// Get all aliases the kernel knows as (alias, module), e.g. to ship a hardware
+    /// to driver map: modules.alias and the modinfo "alias" fields of the module files,
+    /// with the module names as modules.alias has them (see `normalize_module_name`).
+    ///
+    /// Returns them sorted and deduplicated. Modules, which cannot be read, are skipped.
+    pub fn all_aliases(&self) -> Vec<(String, String)> {
+        let modnames: Vec<String> = self
+            .get_disk_module_paths()
+            .into_iter()
+            .filter(|x| self.get_kernel_path().join(x).is_file())
+            .collect();
+        let modinfo = self
+            .map_modules(
+                &modnames,
+                |modname| {
+                    let fmodname = normalize_module_name(modname);
+                    Ok(read_module(&self.get_kernel_path().join(modname))
+                        .and_then(|data| read_modinfo(&data))
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|(key, _)| key == "alias")
+                        .map(|(_, alias)| (alias, fmodname.to_owned()))
+                        .collect::<Vec<(String, String)>>())
+                },
+                |_| {},
+            )
+            .unwrap_or_default();
+
+        let mut aliases: Vec<(String, String)> = self.t().aliases.to_owned();
+        aliases.extend(modinfo.into_iter().flatten());
+        aliases.sort();
+        aliases.dedup();
+
+        aliases
+    }
+
+    /// Get the module path, a name or an alias resolves to, as modprobe would look it up
+    /// (see `simulate_modprobe`). Two names of the same module have the same canonical name.
+    /// If an alias matches more modules, the first one is returned.
+    pub fn canonical_name(&self, alias: &str) -> Option<String> {
+        self.modprobe_lookup(alias).0.first().map(|x| x.to_string())
+    }
+
+    /// Simulate "modprobe <name>" without touching the system: find out which modules
+    /// would be inserted and in what order, with their dependencies and soft dependencies.
+    ///
+    /// The name is looked up as modprobe does: modprobe.d aliases, module names,
+    /// "install" directives and modules.alias. Modules, found by an alias, are skipped
+    /// if they are blacklisted. Modules with an "install" directive are not inserted,
+    /// as its command would run instead. Modules, compiled into the kernel, are already there,
+    /// so they are not inserted, and a builtin name has an empty plan.
+    ///
+    /// Fails with `ModuleNotFound` if nothing matches the name.
+    pub fn simulate_modprobe(&self, name: &str) -> Result<ModprobePlan, Error> {
+        let mut plan = ModprobePlan::default();
+        let (mods, by_alias) = self.modprobe_lookup(name);
+        if mods.is_empty() {
+            let mname = normalize_module_name(name);
+            let builtin = self
+                .t()
+                .builtin
+                .iter()
+                .any(|x| normalize_module_name(x) == mname);
+            match self.t().modprobe.install.get(&mname) {
+                Some(command) => plan.install.push((name.to_string(), command.to_owned())),
+                None if builtin => return Ok(plan),
+                None => return Err(Error::ModuleNotFound(name.to_string())),
+            }
+        }
+
+        let mut seen: HashSet<&String> = HashSet::default();
+        for fmodname in mods {
+            if by_alias && self.is_blacklisted(fmodname) {
+                plan.blacklisted.push(fmodname.to_owned());
+            } else {
+                self.modprobe_insert(fmodname, &mut seen, &mut plan);
+            }
+        }
+        plan.firmware = self.get_firmware_for(&plan.modules)?;
+
+        Ok(plan)
+    }
+
+    /// Look up modules by a name, as modprobe does. Returns modules
+    /// and whether they were found by an alias.
+    /// This is an internal method
+    fn modprobe_lookup(&self, name: &str) -> (Vec<&String>, bool) {
+        let lookup = |aliases: &[(String, String)]| -> Vec<&String> {
+            let mut mods: Vec<&String> = vec![];
+            for (pattern, module) in aliases {
+                if fnmatch(pattern, name) {
+                    if let Some(fmodname) = self.find_candidates(module).first() {
+                        if !mods.contains(fmodname) {
+                            mods.push(fmodname);
+                        }
+                    }
+                }
+            }
+            mods
+        };
+
+        let mods = lookup(&self.t().modprobe.aliases);
+        if !mods.is_empty() {
+            return (mods, true);
+        }
+
+        if let Some(fmodname) = self.find_candidates(name).first() {
+            return (vec![fmodname], false);
+        }
+
+        if self
+            .t()
+            .modprobe
+            .install
+            .contains_key(&normalize_module_name(name))
+        {
+            return (vec![], false);
+        }
+
+        (lookup(&self.t().aliases), true)
+    }
+
+    /// Add a module to the modprobe plan, after its soft "pre" dependencies
+    /// and dependencies, and before its soft "post" dependencies.
+    /// Modules, compiled into the kernel, are skipped.
+    /// This is an internal method
+    fn modprobe_insert<'a>(
+        &'a self,
+        name: &'a String,
+        seen: &mut HashSet<&'a String>,
+        plan: &mut ModprobePlan,
+    ) {
+        if self.is_builtin_path(name) || !seen.insert(name) {
+            return;
+        }
+
+        let mname = normalize_module_name(name);
+        let softdep = self.t().modprobe.softdeps.get(&mname);
+        for pre in softdep.map(|x| x.pre.as_slice()).unwrap_or_default() {
+            for fmodname in self.modprobe_lookup(pre).0 {
+                self.modprobe_insert(fmodname, seen, plan);
+            }
+        }
+
+        let mut mdeps = self.get_direct_deps(name);
+        mdeps.sort();
+        for mdep in mdeps {
+            self.modprobe_insert(mdep, seen, plan);
+        }
+
+        match self.t().modprobe.install.get(&mname) {
+            Some(command) => plan.install.push((name.to_owned(), command.to_owned())),
+            None => plan.modules.push(name.to_owned()),
+        }
+
+        for post in softdep.map(|x| x.post.as_slice()).unwrap_or_default() {
+            for fmodname in self.modprobe_lookup(post).0 {
+                self.modprobe_insert(fmodname, seen, plan);
+            }
+        }
+    }
+
+    /// Resolve modules, needed for the devices in the whole system (/sys/devices).
+    pub fn modules_for_system(&self) -> Result<Vec<String>, Error> {
+        self.modules_for_sysfs_path(&self.sys_root.join("devices"))
+    }
+
+    /// Resolve modules, needed to mount a filesystem type, as in /proc/filesystems or fstab,
+    /// e.g. "xfs", with all their dependencies, sorted. FUSE filesystems, e.g. "fuse.sshfs"
+    /// or "fuseblk", need the "fuse" module.
+    ///
+    /// A filesystem, compiled into the kernel, needs no modules.
+    /// Fails with `ModuleNotFound` if no module provides the filesystem.
+    pub fn modules_for_filesystem(&self, fstype: &str) -> Result<Vec<String>, Error> {
+        let fmodname = match self.fs_module(fstype) {
+            Some(fmodname) => fmodname,
+            None if self.is_builtin_fs(fstype) => return Ok(vec![]),
+            None => return Err(Error::ModuleNotFound(fstype.to_string())),
+        };
+
+        Ok(self.get_deps_for_flatten(&[fmodname]))
+    }
+
+    /// Get the module of a filesystem type. Filesystems are requested by the kernel
+    /// as "fs-<type>", FUSE ones as "fs-fuse".
+    /// This is an internal method
+    fn fs_module(&self, fstype: &str) -> Option<String> {
+        let fstype = fs_base_type(fstype);
+        self.canonical_name(&format!("fs-{}", fstype))
+            .or_else(|| self.canonical_name(fstype))
+    }
+
+    /// Return true if a filesystem type is compiled into the kernel: by its name
+    /// or by its "fs-<type>" alias.
+    /// This is an internal method
+    fn is_builtin_fs(&self, fstype: &str) -> bool {
+        let fstype = fs_base_type(fstype);
+        let fs_alias = format!("fs-{}", fstype);
+        let mut names: Vec<String> = self
+            .t()
+            .aliases
+            .iter()
+            .filter(|(pattern, _)| *pattern == fs_alias)
+            .map(|(_, module)| normalize_module_name(module))
+            .collect();
+        names.push(normalize_module_name(fstype));
+
+        self.t()
+            .builtin
+            .iter()
+            .any(|x| names.contains(&normalize_module_name(x)))
+    }
+
+    /// Resolve modules, needed to reach and mount a block device, e.g. "/dev/nvme0n1p2":
+    /// drivers of its controller and buses up the /sys hierarchy, of the devices
+    /// it is built on (e.g. of an LVM volume), and of its filesystem, with all their dependencies.
+    ///
+    /// The filesystem type is taken from the mounts (see `KernelInfoBuilder::mounts_path`),
+    /// otherwise it is detected by the device superblock.
+    pub fn modules_for_block_device(&self, dev: &Path) -> Result<Vec<String>, Error> {
+        let dev_path = dev.canonicalize().unwrap_or_else(|_| dev.to_path_buf());
+        let block_path = self
+            .sys_root
+            .join("class/block")
+            .join(dev_path.file_name().unwrap_or_default());
+        if !block_path.exists() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("Block device {} was not found", dev.display()),
+            )
+            .into());
+        }
+
+        let mut modaliases: Vec<String> = vec![];
+        get_block_modaliases(&self.sys_root.canonicalize()?, &block_path, &mut modaliases)?;
+
+        let mut mods: Vec<String> = vec![];
+        for modalias in modaliases {
+            mods.extend(self.resolve_alias(&modalias));
+        }
+
+        if let Some(fmodname) = self.get_fstype(dev).and_then(|x| self.fs_module(&x)) {
+            mods.push(fmodname);
+        }
+
+        Ok(self.get_deps_for_flatten(&mods))
+    }
+
+    /// Resolve modules, needed for a network interface, e.g. "eth0" for a diskless boot:
+    /// drivers of its device and buses up the /sys hierarchy, with all their dependencies, sorted.
+    ///
+    /// A virtual interface (no "device" in /sys/class/net/<iface>), e.g. "lo" or a bridge,
+    /// needs no modules. The /sys root can be changed (see `KernelInfoBuilder::sys_root`).
+    pub fn modules_for_netdev(&self, iface: &str) -> Result<Vec<String>, Error> {
+        let net_path = self.sys_root.join("class/net").join(iface);
+        if !net_path.exists() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("Network interface {} was not found", iface),
+            )
+            .into());
+        }
+
+        let dev_path = net_path.join("device");
+        if !dev_path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut modaliases: Vec<String> = vec![];
+        get_block_modaliases(&self.sys_root.canonicalize()?, &dev_path, &mut modaliases)?;
+
+        let mut mods: Vec<String> = vec![];
+        for modalias in modaliases {
+            mods.extend(self.resolve_alias(&modalias));
+        }
+
+        Ok(self.get_deps_for_flatten(&mods))
+    }
+
+    /// Get filesystem type of a block device from the mounts or its superblock.
+    /// This is an internal method
+    fn get_fstype(&self, dev: &Path) -> Option<String> {
+        let dev_path = dev.canonicalize().ok();
+        if let Ok(mounts) = read_to_string(&self.mounts_path) {
+            for line in mounts.lines() {
+                let mut tokens = line.split_whitespace();
+                if let (Some(src), Some(_), Some(fstype)) =
+                    (tokens.next(), tokens.next(), tokens.next())
+                {
+                    let src = Path::new(src);
+                    if src == dev || (dev_path.is_some() && src.canonicalize().ok() == dev_path) {
+                        return Some(fstype.to_string());
+                    }
+                }
+            }
+        }
+
+        probe_fstype(dev)
+    }
+
+    /// Resolve modules, needed for the devices of a /sys subtree,
+    /// e.g. "/sys/devices/pci0000:00/0000:00:1f.2" for just one controller.
+    ///
+    /// All "modalias" files under the path are resolved with their dependencies
+    /// and returned as a deduplicated sorted list of full module paths.
+    pub fn modules_for_sysfs_path(&self, sys_path: &Path) -> Result<Vec<String>, Error> {
+        let mut modaliases: Vec<String> = vec![];
+        get_modaliases(sys_path, &mut modaliases)?;
+
+        let mut mods: Vec<String> = vec![];
+        for modalias in modaliases {
+            mods.extend(self.resolve_alias(&modalias));
+        }
+
+        Ok(self.get_deps_for_flatten(&mods))
+    }
+
+    /// Get kernel build config (CONFIG_*) as a map of the options to their values,
+    /// e.g. "y", "m", "n" or a string, unquoted. Options, which are "not set", are "n".
+    ///
+    /// The config is read from the first found of /boot/config-<version>,
+    /// /lib/modules/<version>/config, /lib/modules/<version>/.config,
+    /// /lib/modules/<version>/build/.config, or from /proc/config.gz, if this kernel
+    /// is the running one of the host root. Compressed ones need the "compression" feature.
+    pub fn kernel_config(&self) -> Result<HashMap<String, String>, Error> {
+        let mut candidates = vec![
+            self.rootpath
+                .join(BOOT_D.trim_start_matches('/'))
+                .join(format!("config-{}", self.version)),
+            self.path.join("config"),
+            self.path.join(KCONFIG_F),
+            self.path.join("build").join(KCONFIG_F),
+        ];
+        if is_running_kernel(&self.rootpath, &self.version) {
+            candidates.push(PathBuf::from(PROC_CONFIG_GZ));
+        }
+
+        let cfg_path = match candidates.into_iter().find(|x| x.is_file()) {
+            Some(cfg_path) => cfg_path,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("No config of kernel {} was found", self.version),
+                )
+                .into())
+            }
+        };
+
+        let mut config: HashMap<String, String> = HashMap::default();
+        for line in String::from_utf8_lossy(&read_module(&cfg_path)?).lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.starts_with("CONFIG_") {
+                    config.insert(key.to_string(), value.trim_matches('"').to_string());
+                }
+            } else if let Some(key) = line
+                .strip_prefix("# ")
+                .and_then(|x| x.strip_suffix(" is not set"))
+                .filter(|x| x.starts_with("CONFIG_"))
+            {
+                config.insert(key.to_string(), "n".to_string());
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Get kernel build config (see `kernel_config`). Missing config means empty map.
+    ///
+    /// This is an internal method
+    fn get_config(&self) -> HashMap<String, String> {
+        self.kernel_config().unwrap_or_default()
+    }
+
+    /// Get vermagic of a reference module, which is any first module that has it.
+    /// This is an internal method
+    fn get_vermagic(&self) -> Option<String> {
+        let mut modnames: Vec<&String> = self.t().deplist.keys().collect();
+        modnames.sort();
+
+        modnames.into_iter().find_map(|x| {
+            self.get_details(x)
+                .ok()
+                .and_then(|x| x.get("vermagic").map(|x| x.trim().to_string()))
+        })
+    }
+
+    /// Get the actual kernel release the modules were built for, as per their vermagic.
+    /// Usually this is the same as the version, but the version is just a directory name.
+    pub fn kernel_release(&self) -> Result<String, Error> {
+        self.build_info()?.release.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("No module of kernel {} has vermagic", self.version),
+            )
+            .into()
+        })
+    }
+
+    /// Get kernel build information from the vermagic of a reference module
+    /// and the kernel config, if available. Missing sources are not an error,
+    /// but leave the corresponding fields empty.
+    pub fn build_info(&self) -> Result<KernelBuild, Error> {
+        let mut kbuild = KernelBuild::default();
+        let config = self.get_config();
+
+        if let Some(vermagic) = self.get_vermagic() {
+            let tokens: Vec<&str> = vermagic.split_whitespace().collect();
+            kbuild.release = tokens.first().map(|x| x.to_string());
+            kbuild.smp = Some(tokens.contains(&"SMP"));
+            kbuild.preempt = Some(tokens.iter().any(|x| x.starts_with("preempt")));
+            kbuild.compiler = tokens
+                .iter()
+                .find(|x| x.starts_with("gcc-"))
+                .map(|x| x.to_string());
+            kbuild.vermagic = Some(vermagic);
+        } else if !config.is_empty() {
+            kbuild.smp = Some(config.get("CONFIG_SMP").is_some_and(|x| x == "y"));
+            kbuild.preempt = Some(
+                ["CONFIG_PREEMPT", "CONFIG_PREEMPT_RT"]
+                    .iter()
+                    .any(|x| config.get(*x).is_some_and(|x| x == "y")),
+            );
+        }
+
+        if let Some(compiler) = config.get("CONFIG_CC_VERSION_TEXT") {
+            kbuild.compiler = Some(compiler.to_owned());
+        }
+
+        Ok(kbuild)
+    }
+}
+
+/// Kernels are the same, if they have the same version in the same modules directory,
+/// regardless of what was loaded for them.
+impl PartialEq for KernelInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.version == other.version && self.path == other.path
+    }
+}
+
+impl Eq for KernelInfo {}
+
+impl Hash for KernelInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.version.hash(state);
+        self.path.hash(state);
+    }
+}
+
+/// Compare dependencies of the modules between two modules.dep of the same kernel,
+/// e.g. before and after depmod. Modules, which are only in one of them, are not compared.
+/// Dependencies are compared regardless of their order.
+pub fn diff_deps(old: &KernelInfo, new: &KernelInfo) -> DepDiff {
+    let mut diff = DepDiff::default();
+    let new_deplist = &new.t().deplist;
+    for (modname, old_deps) in &old.t().deplist {
+        let new_deps = match new_deplist.get(modname) {
+            Some(new_deps) => new_deps,
+            None => continue,
+        };
+
+        let mut change = DepChange {
+            added: new_deps
+                .iter()
+                .filter(|x| !old_deps.contains(x))
+                .cloned()
+                .collect(),
+            removed: old_deps
+                .iter()
+                .filter(|x| !new_deps.contains(x))
+                .cloned()
+                .collect(),
+        };
+        if change != DepChange::default() {
+            change.added.sort();
+            change.added.dedup();
+            change.removed.sort();
+            change.removed.dedup();
+            diff.changed.insert(modname.to_owned(), change);
+        }
+    }
+
+    diff
+}
+
+/// Get the base filesystem type: "fuse" for FUSE filesystems, e.g. "fuse.sshfs" or "fuseblk".
+/// This is an internal method
+fn fs_base_type(fstype: &str) -> &str {
+    if fstype == "fuseblk" || fstype.starts_with("fuse.") {
+        "fuse"
+    } else {
+        fstype
+    }
+}
+
+/// Get canonical short name of a module, as the kernel (and lsmod) names it:
+/// directories and the `.ko[.compression]` extension are stripped and dashes are
+/// replaced with underscores.
+///
+/// Example: "kernel/drivers/i2c/i2c-core.ko.zst" -> "i2c_core"
+pub fn normalize_module_name(name: &str) -> String {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let name = match name.rfind(".ko") {
+        Some(idx) => &name[..idx],
+        None => name,
+    };
+
+    name.replace('-', "_")
+}
+
+/// Parse modules.dep content
+pub fn parse_deps<R: BufRead>(reader: R) -> Result<ParsedDeps, Error> {
+    let mut deps = ParsedDeps::default();
+    read_dep_lines(reader, |modpath, moddeps| deps.add(modpath, moddeps))?;
+
+    Ok(deps)
+}
+
+/// Parse modules.dep content in the memory, e.g. a mapped file, without copying it
+/// line by line, as `parse_deps` does. Fails with `Parse` on a line, which is not UTF-8.
+pub fn parse_deps_slice(data: &[u8]) -> Result<ParsedDeps, Error> {
+    let mut deps = ParsedDeps::default();
+    for (idx, line) in data.split(|b| *b == b'\n').enumerate() {
+        let line = std::str::from_utf8(line).map_err(|err| Error::Parse {
+            line: idx + 1,
+            reason: err.to_string(),
+        })?;
+        if let Some((modpath, moddeps)) = parse_dep_line(line) {
+            deps.add(modpath, &moddeps);
+        }
+    }
+
+    Ok(deps)
+}
+
+/// Read and parse modules.dep, also compressed
+fn read_deps_file(dep_path: &Path) -> Result<ParsedDeps, Error> {
+    if ModCompression::from_path(dep_path) != ModCompression::None {
+        return parse_deps_slice(&read_module(dep_path)?);
+    }
+
+    #[cfg(feature = "mmap")]
+    {
+        // The file is only read, and it is replaced by depmod, not rewritten in place
+        let mmap = unsafe { memmap2::Mmap::map(&File::open(dep_path)?)? };
+        parse_deps_slice(&mmap)
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    parse_deps(BufReader::new(File::open(dep_path)?))
+}
+
+/// Parse modules.dep.bin, the binary index of modules.dep, which libkmod reads
+/// (see `parse_kmod_index`). Its values are the same lines as of modules.dep.
+#[cfg(feature = "kmod-index")]
+pub fn parse_deps_index(data: &[u8]) -> Result<ParsedDeps, Error> {
+    let mut deps = ParsedDeps::default();
+    for (_, lines) in parse_kmod_index(data)? {
+        for line in lines {
+            if let Some((modpath, moddeps)) = parse_dep_line(&line) {
+                deps.add(modpath, &moddeps);
+            }
+        }
+    }
+
+    Ok(deps)
+}
+
+/// Stream a modules.dep file line by line, calling back with each module path and
+/// its dependencies, as they are listed there, without keeping them in memory.
+///
+/// Dependencies in modules.dep are already resolved, so they are not just the direct ones
+/// (see `KernelInfo::get_direct_deps` for these). Compressed files are not supported.
+pub fn for_each_dep_line<F: FnMut(&str, &[&str])>(dep_path: &Path, f: F) -> Result<(), Error> {
+    read_dep_lines(BufReader::new(File::open(dep_path)?), f)
+}
+
+/// Check a modules.dep file against a modules directory, without a whole kernel:
+/// all modules have files, all dependencies are modules, there are no dependency cycles
+/// and no module is listed twice. Module paths are relative to the modules directory.
+pub fn lint_modules_dep(dep_path: &Path, modules_dir: &Path) -> Result<LintReport, Error> {
+    let mut report = LintReport::default();
+    let mut deplist: HashMap<String, Vec<String>> = HashMap::default();
+    for_each_dep_line(dep_path, |modpath, moddeps| {
+        let moddeps = moddeps.iter().map(|x| x.to_string()).collect();
+        if deplist.insert(modpath.to_owned(), moddeps).is_some() {
+            report.duplicate_keys.push(modpath.to_owned());
+        }
+    })?;
+
+    for (modname, moddeps) in &deplist {
+        if !modules_dir.join(modname).try_exists()? {
+            report.missing_files.push(modname.to_owned());
+        }
+
+        for moddep in moddeps {
+            if !deplist.contains_key(moddep) && !report.dangling_deps.contains(moddep) {
+                report.dangling_deps.push(moddep.to_owned());
+            }
+        }
+    }
+
+    report.cycles = find_cycles(&deplist);
+    report.missing_files.sort();
+    report.dangling_deps.sort();
+    report.duplicate_keys.sort();
+    report.duplicate_keys.dedup();
+
+    Ok(report)
+}
+
+/// Find all module files (.ko, also compressed) under a directory, e.g. an unpacked initramfs,
+/// in any layout: "lib/modules/<version>/kernel/...", "kernel/..." or just the files.
+/// Symlinks are not followed.
+///
+/// Returns sorted short module names (see `normalize_module_name`).
+pub fn modules_in_tree(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut files: Vec<PathBuf> = vec![];
+    find_module_files(dir, &mut files)?;
+
+    let mut mods: Vec<String> = files
+        .iter()
+        .map(|x| normalize_module_name(&x.file_name().unwrap_or_default().to_string_lossy()))
+        .collect();
+    mods.sort();
+    mods.dedup();
+
+    Ok(mods)
+}
+
+/// Collect paths of all module files under a directory.
+/// This is an internal method
+fn find_module_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for fres in read_dir(dir)? {
+        let fd = fres?;
+        let ftype = fd.file_type()?;
+        let fname = fd.file_name().to_string_lossy().to_string();
+        if ftype.is_dir() {
+            find_module_files(&fd.path(), files)?;
+        } else if ftype.is_file()
+            && (fname.ends_with(".ko")
+                || MOD_DEP_EXT
+                    .iter()
+                    .any(|fext| fname.ends_with(&format!(".ko{}", fext))))
+        {
+            files.push(fd.path());
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect uncompressed names of all firmware files under a directory, relative to it.
+/// Symlinks to files are followed, symlinks to directories are not.
+/// This is an internal method
+fn find_firmware_files(
+    dir: &Path,
+    prefix: &str,
+    skip: &HashSet<String>,
+    firmware: &mut HashSet<String>,
+) -> Result<(), Error> {
+    for fres in read_dir(dir)? {
+        let fd = fres?;
+        let fname = format!("{}{}", prefix, fd.file_name().to_string_lossy());
+        if skip.contains(&fname) {
+            continue;
+        }
+
+        if fd.file_type()?.is_dir() {
+            find_firmware_files(&fd.path(), &format!("{}/", fname), skip, firmware)?;
+        } else if fd.path().is_file() {
+            firmware.insert(
+                FIRMWARE_EXT
+                    .iter()
+                    .filter(|fext| !fext.is_empty())
+                    .find_map(|fext| fname.strip_suffix(fext))
+                    .unwrap_or(&fname)
+                    .to_string(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Find dependency cycles: strongly connected components of more than one module
+/// or modules, depending on themselves. Returns sorted cycles, each sorted.
+fn find_cycles(deplist: &HashMap<String, Vec<String>>) -> Vec<Vec<String>> {
+    // Tarjan's algorithm state: index, lowlink and the stack of the visited modules
+    #[derive(Default)]
+    struct Scc<'a> {
+        index: HashMap<&'a str, usize>,
+        lowlink: HashMap<&'a str, usize>,
+        stack: Vec<&'a str>,
+        on_stack: HashSet<&'a str>,
+        cycles: Vec<Vec<String>>,
+    }
+
+    fn visit<'a>(deplist: &'a HashMap<String, Vec<String>>, m: &'a str, st: &mut Scc<'a>) {
+        let idx = st.index.len();
+        st.index.insert(m, idx);
+        st.lowlink.insert(m, idx);
+        st.stack.push(m);
+        st.on_stack.insert(m);
+
+        for d in deplist.get(m).into_iter().flatten() {
+            let d = d.as_str();
+            if !deplist.contains_key(d) {
+                continue;
+            }
+
+            if !st.index.contains_key(d) {
+                visit(deplist, d, st);
+                let low = st.lowlink[m].min(st.lowlink[d]);
+                st.lowlink.insert(m, low);
+            } else if st.on_stack.contains(d) {
+                let low = st.lowlink[m].min(st.index[d]);
+                st.lowlink.insert(m, low);
+            }
+        }
+
+        if st.lowlink[m] == st.index[m] {
+            let mut scc: Vec<String> = vec![];
+            while let Some(x) = st.stack.pop() {
+                st.on_stack.remove(x);
+                scc.push(x.to_string());
+                if x == m {
+                    break;
+                }
+            }
+
+            let self_dep = deplist[m].iter().any(|x| x == m);
+            if scc.len() > 1 || self_dep {
+                scc.sort();
+                st.cycles.push(scc);
+            }
+        }
+    }
+
+    let mut st = Scc::default();
+    let mut mods: Vec<&String> = deplist.keys().collect();
+    mods.sort();
+    for m in mods {
+        if !st.index.contains_key(m.as_str()) {
+            visit(deplist, m, &mut st);
+        }
+    }
+    st.cycles.sort();
+
+    st.cycles
+}
 
-/// Metadata about the kernel and details about it
-#[derive(Debug, Clone)]
-pub struct KernelInfo {
-    pub version: String,
-    path: PathBuf,
-    dep_path: PathBuf,
-    is_valid: bool,
-    _ext: String,
+/// Read modules.dep lines and call back with each module path and its dependencies.
+fn read_dep_lines<R: BufRead, F: FnMut(&str, &[&str])>(reader: R, mut f: F) -> Result<(), Error> {
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((modpath, moddeps)) = parse_dep_line(&line) {
+            f(modpath, &moddeps);
+        }
+    }
 
-    // Dependencies list in a format:
-    //     "modulename" -> ["kernel/path/to/a/module.ko.zst", "kernel/other.ko.zst"]
-    deplist: HashMap<String, Vec<String>>,
+    Ok(())
+}
 
-    // Dependencies list in a format:
-    //     "modulename" -> ["module", "other"]
-    lookup_deplist: HashSet<String>,
+/// Parse a modules.dep line into the module path and its dependencies
+fn parse_dep_line(line: &str) -> Option<(&str, Vec<&str>)> {
+    let (modpath, moddeps) = split_dep_line(line)?;
+    let moddeps = moddeps.trim();
+    if moddeps.is_empty() {
+        Some((modpath.trim(), vec![]))
+    } else {
+        Some((modpath.trim(), moddeps.split(' ').collect()))
+    }
 }
 
-impl KernelInfo {
-    /// Creates an instance of a KernelInfo struct with the version
-    /// of the kernel and paths to required points for module analysis.
-    ///
-    /// Root path is either "/" for the host filesystem or a mountpoint
-    /// to the root filesystem.
-    ///
-    /// NOTE: The module resolver is very simple here and won't scale that much
-    ///       if a kernel will have millions of modules. But as of 2024 it
-    ///       works OK with those dozen of thousands as for a generator.
-    ///       Generated CPIO anyway will contain already sorted list.
-    pub fn new(rootpath: &str, kver: &str) -> Result<Self, Error> {
-        Ok(KernelInfo {
-            version: kver.to_owned(),
-            path: PathBuf::from(if ["", "/"].contains(&rootpath) {
-                MOD_D.to_string()
-            } else {
-                format!("{}/{}", rootpath, MOD_D)
-            }),
-            dep_path: PathBuf::from(""),
-            deplist: HashMap::default(),
-            lookup_deplist: HashSet::default(),
-            _ext: "".to_string(),
-            is_valid: false,
+/// Write a modules.dep line of a module with its dependencies
+fn write_dep_line<W: Write>(out: &mut W, modname: &str, mdeps: &[&str]) -> Result<(), Error> {
+    if mdeps.is_empty() {
+        writeln!(out, "{}:", modname)?;
+    } else {
+        writeln!(out, "{}: {}", modname, mdeps.join(" "))?;
+    }
+
+    Ok(())
+}
+
+/// Split a modules.dep line into the module path and its dependencies
+/// at the colon right after the module file: "kernel/a:b/foo.ko.zst: ..." -> "kernel/a:b/foo.ko.zst".
+/// Odd lines without a ".ko" module file are split at the first colon.
+fn split_dep_line(line: &str) -> Option<(&str, &str)> {
+    for (idx, _) in line.match_indices(".ko") {
+        let rest = &line[idx + 3..];
+        let fext_len = match rest.strip_prefix('.') {
+            Some(fext) => {
+                1 + fext
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(fext.len())
+            }
+            None => 0,
+        };
+        if rest[fext_len..].starts_with(':') {
+            let end = idx + 3 + fext_len;
+            return Some((&line[..end], &line[end + 1..]));
         }
-        .init()?)
     }
 
-    /// Initialise the KernelInfo. This can be ran only once per an instance.
-    fn init(mut self) -> Result<Self, Error> {
-        if !self._ext.is_empty() {
-            return Ok(self);
+    line.split_once(':')
+}
+
+/// Get a module file name, as it is indexed for the name lookups:
+/// "kernel/drivers/i2c/i2c-core.ko.zst" -> "i2c_core.ko.zst"
+fn index_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).replace('-', "_")
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diag
+            } else {
+                1 + diag.min(above).min(row[j])
+            };
+            diag = above;
         }
+    }
 
-        self.path = self.path.join(&self.version);
-        self.dep_path = self.dep_path.join(self.path.as_os_str()).join(MOD_DEP_F);
-        self.load_deps()?;
+    row[b.len()]
+}
 
-        Ok(self)
+/// Strip compression extension from a module path: "kernel/foo.ko.zst" -> "kernel/foo.ko"
+fn strip_compression(path: &str) -> &str {
+    match path.rfind(".ko") {
+        Some(idx) => &path[..idx + 3],
+        None => path,
     }
+}
 
-    /// Return current kernel info root path.
-    pub fn get_kernel_path(&self) -> PathBuf {
-        PathBuf::from(&self.path)
+/// Returns true if the kernel is the running one on the host filesystem,
+/// so its runtime files, e.g. /proc/cmdline, describe it.
+fn is_running_kernel(rootpath: &Path, kver: &str) -> bool {
+    rootpath == Path::new("/") && read_to_string(PROC_OSRELEASE).is_ok_and(|x| x.trim() == kver)
+}
+
+/// Find the "modinfo" utility: the configured one, if exists,
+/// otherwise in the standard locations or PATH.
+fn find_modinfo(modinfo_path: &Path) -> Option<PathBuf> {
+    if modinfo_path.is_file() {
+        return Some(modinfo_path.to_path_buf());
     }
 
-    /// Get modules extension system: .ko[.compression]
-    /// NOTE: assumption is that _all modules_ are with the same extension!
-    fn get_fext(&self, fname: Option<&OsStr>) -> String {
-        format!(
-            ".ko{}",
-            fname
-                .unwrap()
-                .to_owned()
-                .to_str()
-                .unwrap()
-                .rsplit_once(".ko")
-                .map_or("", |(_, l)| l)
-        )
+    let path_env = std::env::var_os("PATH").unwrap_or_default();
+    MOD_INFO_LOCATIONS
+        .iter()
+        .map(PathBuf::from)
+        .chain(std::env::split_paths(&path_env))
+        .map(|x| x.join("modinfo"))
+        .find(|x| x.is_file())
+}
+
+/// Run a command and get its stdout, killing it if it does not finish in time.
+/// Returns `None` on timeout.
+fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Read in a thread, so the child is not blocked on a full pipe
+    let mut stdout = child.stdout.take();
+    let reader = thread::spawn(move || {
+        let mut out: Vec<u8> = vec![];
+        if let Some(stdout) = stdout.as_mut() {
+            let _ = std::io::Read::read_to_end(stdout, &mut out);
+        }
+        out
+    });
+
+    let deadline = Instant::now() + timeout;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
     }
 
-    /// Load module dependencies
-    /// Skip if there is no /lib/modules/<version>/kernel directory
-    fn load_deps(&mut self) -> Result<(), Error> {
-        if !self._ext.is_empty() {
-            return Ok(());
+    Ok(Some(reader.join().unwrap_or_default()))
+}
+
+/// Collect "modalias" of a /sys device, e.g. a block device, and of all its parents
+/// up to the /sys root, and of the devices it is built on ("slaves").
+fn get_block_modaliases(
+    sys_root: &Path,
+    block_path: &Path,
+    modaliases: &mut Vec<String>,
+) -> Result<(), Error> {
+    let block_path = block_path.canonicalize()?;
+    for dir in block_path
+        .ancestors()
+        .take_while(|dir| dir.starts_with(sys_root))
+    {
+        if let Ok(modalias) = read_to_string(dir.join("modalias")) {
+            let modalias = modalias.trim().to_string();
+            if !modalias.is_empty() && !modaliases.contains(&modalias) {
+                modaliases.push(modalias);
+            }
         }
+    }
 
-        let modpath = self.get_kernel_path().join("kernel");
-        self.is_valid = Path::new(modpath.to_str().unwrap()).is_dir();
-        if self.is_valid {
-            for line in read_to_string(self.dep_path.as_os_str())?.lines() {
-                if let Some(sl) = line.split_once(':') {
-                    let (modpath, moddeps) = (sl.0.trim(), sl.1.trim());
-                    if self._ext.is_empty() {
-                        self._ext = self.get_fext(PathBuf::from(modpath).file_name());
-                    }
+    if let Ok(slaves) = read_dir(block_path.join("slaves")) {
+        for fres in slaves {
+            get_block_modaliases(sys_root, &fres?.path(), modaliases)?;
+        }
+    }
 
-                    let mut deplist: Vec<String> = vec![];
-                    let mut deplist_idx: Vec<String> = vec![];
-
-                    if !moddeps.is_empty() {
-                        deplist = moddeps.split(' ').map(|x| x.to_owned()).collect();
-                        deplist_idx = deplist
-                            .iter()
-                            .map(|x| {
-                                x.split('/')
-                                    .last()
-                                    .unwrap()
-                                    .split_once('.')
-                                    .unwrap()
-                                    .0
-                                    .to_string()
-                            })
-                            .collect();
-                    }
+    Ok(())
+}
+
+/// Detect filesystem type of a device by its superblock magic.
+/// Only the common root filesystems are known.
+fn probe_fstype(dev: &Path) -> Option<String> {
+    use std::io::Read;
+
+    // The farthest magic is of btrfs, at 64K
+    let mut sb: Vec<u8> = vec![];
+    File::open(dev)
+        .ok()?
+        .take(0x10048)
+        .read_to_end(&mut sb)
+        .ok()?;
+    let at = |off: usize, magic: &[u8]| sb.get(off..off + magic.len()) == Some(magic);
+
+    Some(
+        if at(0x438, b"\x53\xef") {
+            "ext4"
+        } else if at(0, b"XFSB") {
+            "xfs"
+        } else if at(0x10040, b"_BHRfS_M") {
+            "btrfs"
+        } else if at(0x400, b"\x10\x20\xf5\xf2") {
+            "f2fs"
+        } else if at(0x400, b"\xe2\xe1\xf5\xe0") {
+            "erofs"
+        } else if at(0, b"hsqs") {
+            "squashfs"
+        } else {
+            return None;
+        }
+        .to_string(),
+    )
+}
 
-                    self.deplist.insert(modpath.to_owned(), deplist);
-                    self.lookup_deplist.extend(deplist_idx.into_iter());
+/// Collect content of all "modalias" files under a /sys path.
+/// Symlinks are not followed, as /sys is full of loops.
+fn get_modaliases(sys_path: &Path, modaliases: &mut Vec<String>) -> Result<(), Error> {
+    for fres in read_dir(sys_path)? {
+        let fd = fres?;
+        let ftype = fd.file_type()?;
+        if ftype.is_dir() {
+            // Some /sys branches are not readable, which is fine
+            let _ = get_modaliases(&fd.path(), modaliases);
+        } else if ftype.is_file() && fd.file_name() == "modalias" {
+            if let Ok(modalias) = read_to_string(fd.path()) {
+                let modalias = modalias.trim();
+                if !modalias.is_empty() {
+                    modaliases.push(modalias.to_string());
                 }
             }
         }
+    }
 
-        Ok(())
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    // a -> b -> d, a -> c -> d, e standalone
+    static DIAMOND: &str = "kernel/a.ko: kernel/b.ko kernel/c.ko kernel/d.ko\n\
+                            kernel/b.ko: kernel/d.ko\n\
+                            kernel/c.ko: kernel/d.ko\n\
+                            kernel/d.ko:\n\
+                            kernel/e.ko:\n";
+
+    fn position(order: &[String], modname: &str) -> usize {
+        order
+            .iter()
+            .position(|x| x.ends_with(&format!("/{}.ko", modname)))
+            .unwrap()
     }
 
-    /// Returns true if there are actual modules on the media for this kernel.
-    /// There are often kernel paths left after a kernel was not completely purged.
-    pub fn is_valid(&self) -> bool {
-        self.is_valid
+    #[test]
+    fn load_order_deps_first() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", DIAMOND);
+
+        let order = ki.get_load_order(&names(&["a", "e"]));
+        assert_eq!(order.len(), 5);
+        assert!(position(&order, "d") < position(&order, "b"));
+        assert!(position(&order, "d") < position(&order, "c"));
+        assert!(position(&order, "b") < position(&order, "a"));
+        assert!(position(&order, "c") < position(&order, "a"));
     }
 
-    /// Get path of dependencies file
-    #[allow(dead_code)]
-    pub fn get_dep_path(&self) -> &str {
-        self.dep_path.to_str().unwrap()
+    #[test]
+    fn unload_order_is_reverse() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", DIAMOND);
+
+        for input in [vec!["a"], vec!["a", "e"], vec!["c", "b"], vec!["d"], vec![]] {
+            let mut order = ki.get_load_order(&names(&input));
+            order.reverse();
+            assert_eq!(ki.get_unload_order(&names(&input)), order);
+        }
+
+        let order = ki.get_unload_order(&names(&["a"]));
+        assert!(position(&order, "a") < position(&order, "b"));
+        assert!(position(&order, "b") < position(&order, "d"));
     }
 
-    /// Find a full path to a module
-    /// Example: "sunrpc.ko" will be resolved as "kernel/net/sunrpc/sunrpc.ko"
-    ///
-    /// Some modules are named differently on the disk than in the memory.
-    /// In this case they are tried to be resolved via external "modinfo".
-    fn expand_module_name<'a>(&'a self, name: &'a String) -> &String {
-        let mut m_name: String;
-        if !name.ends_with(self._ext.as_str()) {
-            m_name = format!("{}{}", name, self._ext); // "sunrpc" -> "sunrpc.ko"
-        } else {
-            m_name = name.to_owned();
+    #[test]
+    fn unload_order_cycle_mirrored() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/a.ko: kernel/b.ko\nkernel/b.ko: kernel/a.ko\n",
+        );
+
+        let mut order = ki.get_load_order(&names(&["a"]));
+        assert_eq!(order.len(), 2);
+        order.reverse();
+        assert_eq!(ki.get_unload_order(&names(&["a"])), order);
+    }
+
+    static DUPS: &str = "kernel/drivers/a/dup.ko: kernel/drivers/a/helper_a.ko\n\
+                         kernel/drivers/a/helper_a.ko:\n\
+                         kernel/drivers/b/dup.ko: kernel/drivers/b/helper_b.ko\n\
+                         kernel/drivers/b/helper_b.ko:\n";
+
+    #[test]
+    fn ambiguous_candidates() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", DUPS);
+
+        let mut candidates = ki.resolve_candidates("dup");
+        candidates.sort();
+        assert_eq!(
+            candidates,
+            names(&["kernel/drivers/a/dup.ko", "kernel/drivers/b/dup.ko"])
+        );
+        assert_eq!(
+            ki.resolve_candidates("b/dup"),
+            names(&["kernel/drivers/b/dup.ko"])
+        );
+        assert_eq!(
+            ki.resolve_candidates("helper_a"),
+            names(&["kernel/drivers/a/helper_a.ko"])
+        );
+        assert!(ki.resolve_candidates("nope").is_empty());
+
+        let dups = ki.duplicate_basenames();
+        assert_eq!(dups.len(), 1);
+        assert_eq!(dups["dup"], candidates);
+
+        // Ambiguous name is resolved to the first candidate only
+        let deps = ki.get_deps_for(&names(&["dup"]));
+        assert_eq!(deps.len(), 1);
+        let (modname, mdeps) = deps.iter().next().unwrap();
+        assert_eq!(modname, &ki.resolve_candidates("dup")[0]);
+        assert_eq!(mdeps.len(), 1);
+    }
+
+    #[test]
+    fn ambiguous_candidates_by_order() {
+        let fx = Fixture::new();
+        fx.kfile(
+            "6.1.0",
+            MOD_ORDER_F,
+            "kernel/drivers/b/dup.ko\nkernel/drivers/a/dup.ko\n",
+        );
+        let ki = fx.tree("6.1.0", DUPS);
+
+        assert_eq!(
+            ki.resolve_candidates("dup"),
+            names(&["kernel/drivers/b/dup.ko", "kernel/drivers/a/dup.ko"])
+        );
+        assert_eq!(
+            ki.get_deps_for(&names(&["dup"]))["kernel/drivers/b/dup.ko"],
+            names(&["kernel/drivers/b/helper_b.ko"])
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn gzip_module_details() {
+        let fx = Fixture::new();
+        let fields = [
+            "license=GPL",
+            "description=Old Debian style",
+            "firmware=foo/bar.bin",
+        ];
+        let gz = fx.module("6.1.0", "kernel/fs/foo.ko.gz", &fields);
+        let ki = fx.tree("6.1.0", "kernel/fs/foo.ko.gz:\n");
+        assert_eq!(ModCompression::from_path(&gz), ModCompression::Gzip);
+        assert_eq!(
+            ModCompression::from_magic(&std::fs::read(&gz).unwrap()),
+            Some(ModCompression::Gzip)
+        );
+
+        assert_eq!(ki.get_fext(gz.file_name()), ".ko.gz");
+        assert_eq!(
+            ki.resolve_candidates("foo"),
+            names(&["kernel/fs/foo.ko.gz"])
+        );
+        let details = ki.get_details("foo").unwrap();
+        assert_eq!(details.name, "kernel/fs/foo.ko.gz");
+        assert_eq!(details.get("license"), Some("GPL"));
+        assert_eq!(details.get("description"), Some("Old Debian style"));
+        assert_eq!(
+            ki.get_firmware_for(&names(&["foo"])).unwrap(),
+            names(&["foo/bar.bin"])
+        );
+
+        // Same module uncompressed has the same hash and content
+        let fx_plain = Fixture::new();
+        fx_plain.module("6.1.0", "kernel/fs/foo.ko", &fields);
+        let ki_plain = fx_plain.tree("6.1.0", "kernel/fs/foo.ko:\n");
+        assert_eq!(
+            ki.hash_modules(&names(&["foo"])).unwrap()["kernel/fs/foo.ko.gz"],
+            ki_plain.hash_modules(&names(&["foo"])).unwrap()["kernel/fs/foo.ko"]
+        );
+        assert!(ki.content_identical(&ki_plain, "foo").unwrap());
+    }
+
+    #[test]
+    fn foreign_module_arch() {
+        let fx = Fixture::new();
+        fx.write(
+            "lib/modules/6.1.0/kernel/net/foo.ko",
+            ElfModule::new()
+                .machine(21)
+                .be(true)
+                .modinfo(&["license=GPL"])
+                .build(),
+        );
+        let ki = fx.tree("6.1.0", "kernel/net/foo.ko:\n");
+
+        assert_eq!(ki.module_arch("foo").unwrap(), "ppc64");
+        assert_eq!(ki.get_details("foo").unwrap().get("license"), Some("GPL"));
+        assert!(ki.module_arch("bar").is_err());
+    }
+
+    #[test]
+    fn alternate_dep_file() {
+        let fx = Fixture::new();
+        fx.tree("6.1.0", "kernel/a.ko:\n");
+        fx.module("6.1.0", "kernel/b.ko", &[]);
+        fx.kfile(
+            "6.1.0",
+            "modules.dep.new",
+            "kernel/a.ko: kernel/b.ko\nkernel/b.ko:\n",
+        );
+
+        let ki = fx
+            .builder()
+            .dep_file("modules.dep.new")
+            .build("6.1.0")
+            .unwrap();
+        assert!(ki.is_valid());
+        assert!(ki.get_dep_path().ends_with("6.1.0/modules.dep.new"));
+        assert_eq!(ki.module_count(), 2);
+        assert_eq!(
+            ki.get_deps_for(&names(&["a"]))["kernel/a.ko"],
+            names(&["kernel/b.ko"])
+        );
+
+        // Default is still modules.dep
+        let ki = fx.kernel("6.1.0");
+        assert!(ki.get_dep_path().ends_with("6.1.0/modules.dep"));
+        assert_eq!(ki.module_count(), 1);
+
+        // Missing alternate file is not silently replaced by modules.dep
+        let ki = fx
+            .builder()
+            .dep_file("modules.dep.none")
+            .build("6.1.0")
+            .unwrap();
+        assert_eq!(ki.module_count(), 0);
+    }
+
+    #[test]
+    fn fext_malformed_names() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", "kernel/a.ko:\n");
+
+        for (fname, fext) in [
+            (Some("foo.ko.zst"), ".ko.zst"),
+            (Some("foo.ko.xz"), ".ko.xz"),
+            (Some("foo.ko"), ".ko"),
+            (Some("foo.kobj.ko.gz"), ".ko.gz"),
+            (Some("foo"), ".ko"),
+            (Some(""), ".ko"),
+            (None, ".ko"),
+        ] {
+            assert_eq!(ki.get_fext(fname.map(OsStr::new)), fext, "{:?}", fname);
         }
+    }
 
-        if !m_name.starts_with("kernel/") {
-            // name or partial path
-            if !m_name.contains('/') {
-                m_name = format!("/{}", m_name); // "sunrpc.ko" -> "/sunrpc.ko"
-            }
+    #[test]
+    fn fext_odd_entry_does_not_poison() {
+        let fx = Fixture::new();
+        fx.write("lib/modules/6.1.0/kernel/odd", "");
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/odd:\nkernel/a.ko.zst: kernel/b.ko.zst\nkernel/b.ko.zst:\n",
+        );
 
-            for fmodname in self.deplist.keys() {
-                // Eliminate to a minimum 3rd fallback via modinfo by trying replacing underscore with a minus.
-                // This not always works, because some modules called mixed.
-                let mm_name = m_name.replace('_', "-");
-                if fmodname.ends_with(&m_name) || fmodname.ends_with(&mm_name) {
-                    return fmodname;
-                }
-            }
+        assert_eq!(ki.dominant_extension(), ".ko.zst");
+        assert_eq!(ki.extensions(), names(&[".ko.zst"]));
+        assert_eq!(
+            ki.get_deps_for(&names(&["a"]))["kernel/a.ko.zst"],
+            names(&["kernel/b.ko.zst"])
+        );
+
+        // Only odd entries still get the plain extension
+        let fx = Fixture::new();
+        fx.write("lib/modules/6.1.0/kernel/odd", "");
+        let ki = fx.tree("6.1.0", "kernel/odd:\n");
+        assert_eq!(ki.dominant_extension(), ".ko");
+    }
+
+    #[test]
+    fn mixed_extensions() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/net/a.ko: kernel/lib/b.ko.zst\n\
+             kernel/lib/b.ko.zst: kernel/lib/c.ko.zst\n\
+             kernel/lib/c.ko.zst:\n\
+             kernel/fs/d.ko.xz: kernel/net/a.ko\n",
+        );
+
+        assert_eq!(ki.dominant_extension(), ".ko.zst");
+        assert_eq!(ki.extensions(), names(&[".ko.zst", ".ko", ".ko.xz"]));
+        for (name, path) in [
+            ("a", "kernel/net/a.ko"),
+            ("a.ko", "kernel/net/a.ko"),
+            ("b", "kernel/lib/b.ko.zst"),
+            ("b.ko", "kernel/lib/b.ko.zst"),
+            ("b.ko.zst", "kernel/lib/b.ko.zst"),
+            ("lib/c", "kernel/lib/c.ko.zst"),
+            ("d", "kernel/fs/d.ko.xz"),
+            ("kernel/fs/d.ko.xz", "kernel/fs/d.ko.xz"),
+        ] {
+            assert_eq!(ki.resolve_candidates(name), names(&[path]), "{}", name);
         }
+        assert!(ki.resolve_candidates("a.ko.zst").is_empty());
 
-        let out = Command::new(MOD_INFO_EXE).arg(name).output();
-        match out {
-            Ok(_) => match String::from_utf8(out.unwrap().stdout) {
-                Ok(data) => {
-                    for line in data.lines().map(|el| el.replace(' ', "")) {
-                        if line.starts_with("filename:/") && line.contains("/kernel/") {
-                            let t_modname = format!(
-                                "kernel/{}",
-                                line.split("/kernel/").collect::<Vec<&str>>()[1]
-                            );
-                            for fmodname in self.deplist.keys() {
-                                if *fmodname == t_modname {
-                                    return fmodname;
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(_) => todo!(),
-            },
-            Err(_) => todo!(),
+        assert_eq!(
+            ki.get_load_order(&names(&["d"])),
+            names(&[
+                "kernel/lib/c.ko.zst",
+                "kernel/lib/b.ko.zst",
+                "kernel/net/a.ko",
+                "kernel/fs/d.ko.xz"
+            ])
+        );
+    }
+
+    #[test]
+    fn cmdline_blacklist() {
+        let fx = Fixture::new();
+        fx.tree(
+            "6.1.0",
+            "kernel/a.ko:\nkernel/b.ko:\nkernel/c.ko:\nkernel/d.ko:\n",
+        );
+        fx.write(
+            "proc/cmdline",
+            "ro quiet modprobe.blacklist=a,b module_blacklist=c\n",
+        );
+
+        let ki = fx.kernel("6.1.0");
+        for (name, blacklisted) in [("a", true), ("b", true), ("c", true), ("d", false)] {
+            assert_eq!(ki.is_blacklisted(name), blacklisted, "{}", name);
         }
+    }
 
-        name
+    #[test]
+    fn strict_mode() {
+        let fx = Fixture::new();
+        fx.kfile("6.1.0", MOD_BUILTIN_F, "kernel/x.ko\n");
+        fx.tree("6.1.0", "kernel/a.ko:\nkernel/d.ko:\n");
+        fx.write("proc/cmdline", "module_blacklist=a\n");
+        let ki = fx.kernel("6.1.0");
+
+        // Blacklisted and builtin modules are not errors, but blacklisted ones are left out
+        let deps = ki.get_deps_for_strict(&names(&["d", "a", "x"])).unwrap();
+        assert_eq!(deps.keys().collect::<Vec<_>>(), vec!["kernel/d.ko"]);
+
+        assert!(matches!(
+            ki.get_deps_for_strict(&names(&["d", "nope"])),
+            Err(Error::ModuleNotFound(name)) if name == "nope"
+        ));
+        assert!(matches!(
+            ki.get_deps_for_strict(&names(&["nope", "a", "zip"])),
+            Err(Error::ModulesNotFound(unresolved)) if unresolved == names(&["nope", "zip"])
+        ));
     }
 
-    /// Resolve dependencies for one module
-    /// This is an internal method
-    fn get_mod_dep(&self, name: &String, mods: &mut HashSet<String>) {
-        let mdeps = self.deplist.get(name).unwrap();
-        for mdep in mdeps {
-            mods.insert(mdep.to_owned());
+    #[test]
+    fn cmdline_default_host_only() {
+        let fx = Fixture::new();
+        fx.tree("6.1.0", "kernel/a.ko:\n");
+        let builder = KernelInfoBuilder::default().rootfs(fx.root());
+        assert!(builder
+            .clone()
+            .build("6.1.0")
+            .unwrap()
+            .cmdline_path
+            .is_none());
 
-            // If a dependency has its own dependencies
-            let d_mdeps = self.deplist.get(mdep).unwrap();
-            if !d_mdeps.is_empty() {
-                for d_dep in d_mdeps {
-                    mods.insert(d_dep.to_owned());
-                    self.get_mod_dep(d_dep, mods);
-                }
-            }
+        // Explicit one is always taken
+        let cmdline = fx.path("cmdline");
+        let ki = builder.cmdline_path(&cmdline).build("6.1.0").unwrap();
+        assert_eq!(ki.cmdline_path, Some(cmdline));
+
+        let osrelease = match read_to_string(PROC_OSRELEASE) {
+            Ok(osrelease) => osrelease.trim().to_string(),
+            Err(_) => return,
+        };
+        assert!(is_running_kernel(Path::new("/"), &osrelease));
+        assert!(!is_running_kernel(Path::new("/"), "0.0.0-none"));
+        assert!(!is_running_kernel(&fx.path(""), &osrelease));
+        for rootfs in ["", "/"] {
+            let ki = KernelInfoBuilder::default()
+                .rootfs(rootfs)
+                .metadata_only(true)
+                .build(&osrelease)
+                .unwrap();
+            assert_eq!(ki.cmdline_path, Some(PathBuf::from(PROC_CMDLINE)));
         }
     }
 
-    /// Resolve all module dependencies
-    pub fn get_deps_for(&self, names: &[String]) -> HashMap<String, Vec<String>> {
-        let mut mod_tree: HashMap<String, Vec<String>> = HashMap::new();
-        for kmodname in names {
-            let r_kmodname = self.expand_module_name(kmodname);
-            if !r_kmodname.contains('/') {
-                continue;
+    // Hash and Eq do not depend on the lazily loaded tables
+    #[allow(clippy::mutable_key_type)]
+    #[test]
+    fn kernel_infos_in_set() {
+        let fx = Fixture::new();
+        fx.tree("6.1.0", "kernel/a.ko:\n");
+        fx.tree("6.2.0", "kernel/a.ko: kernel/b.ko\nkernel/b.ko:\n");
+        let fx_other = Fixture::new();
+        fx_other.tree("6.1.0", "kernel/a.ko:\n");
+
+        let loaded = fx.kernel("6.1.0");
+        assert!(loaded.is_valid());
+        let kernels: HashSet<KernelInfo> = HashSet::from_iter([
+            loaded,
+            KernelInfo::metadata_only(fx.root(), "6.1.0").unwrap(),
+            fx.kernel("6.1.0"),
+            fx.kernel("6.2.0"),
+            fx_other.kernel("6.1.0"),
+        ]);
+        assert_eq!(kernels.len(), 3);
+        assert!(kernels.contains(&KernelInfo::metadata_only(fx.root(), "6.2.0").unwrap()));
+        assert!(!kernels.contains(&KernelInfo::metadata_only(fx.root(), "6.3.0").unwrap()));
+
+        let mut versions: HashMap<KernelInfo, usize> = HashMap::default();
+        for ki in [fx.kernel("6.1.0"), fx.kernel("6.2.0"), fx.kernel("6.1.0")] {
+            *versions.entry(ki).or_default() += 1;
+        }
+        assert_eq!(versions[&fx.kernel("6.1.0")], 2);
+    }
+
+    #[test]
+    fn write_modules_dep_round_trip() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", DIAMOND);
+
+        let mut out: Vec<u8> = vec![];
+        ki.write_modules_dep(&names(&["b", "c"]), &mut out).unwrap();
+        let parsed = parse_deps_slice(&out).unwrap();
+        assert_eq!(parsed.deplist.len(), 3);
+        assert!(!parsed.deplist.contains_key("kernel/a.ko"));
+        assert!(!parsed.deplist.contains_key("kernel/e.ko"));
+
+        // Every module comes after its dependencies
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        for (idx, line) in lines.iter().enumerate() {
+            let (_, mdeps) = line.split_once(':').unwrap();
+            for mdep in mdeps.split_whitespace() {
+                assert!(lines[..idx]
+                    .iter()
+                    .any(|x| x.starts_with(&format!("{}:", mdep))));
             }
+        }
+
+        let trimmed = fx.builder().deps(parsed).build("6.1.0").unwrap();
+        for input in [vec!["b"], vec!["c"], vec!["b", "c"], vec!["d"]] {
+            assert_eq!(
+                trimmed.get_deps_for(&names(&input)),
+                ki.get_deps_for(&names(&input)),
+                "{:?}",
+                input
+            );
+            assert_eq!(
+                trimmed.get_load_order(&names(&input)),
+                ki.get_load_order(&names(&input))
+            );
+        }
+        assert!(trimmed.get_deps_for(&names(&["a"])).is_empty());
+    }
 
-            let mut mod_deps: HashSet<String> = HashSet::default();
-            let mut r_deps: Vec<String> = vec![];
+    #[test]
+    fn write_modules_dep_drops_outside_deps() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", DIAMOND);
 
-            self.get_mod_dep(r_kmodname, &mut mod_deps);
+        // Dependency outside of the set is not listed, e.g. it is compiled in
+        let fx_builtin = Fixture::new();
+        fx_builtin.kfile("6.1.0", MOD_BUILTIN_F, "kernel/d.ko\n");
+        let partial = fx_builtin.tree("6.1.0", "kernel/b.ko: kernel/d.ko\n");
+        let mut out: Vec<u8> = vec![];
+        partial.write_modules_dep(&names(&["b"]), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "kernel/b.ko:\n");
 
-            for v in mod_deps {
-                r_deps.push(v);
+        let mut out: Vec<u8> = vec![];
+        ki.write_modules_dep(&names(&["b"]), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "kernel/d.ko:\nkernel/b.ko: kernel/d.ko\n"
+        );
+    }
+
+    #[test]
+    fn write_modules_dep_normalized_stable() {
+        let expected = "kernel/a.ko: kernel/c.ko kernel/b.ko kernel/d.ko\n\
+                        kernel/b.ko: kernel/d.ko\n\
+                        kernel/c.ko: kernel/d.ko\n\
+                        kernel/d.ko:\n";
+
+        // Same modules.dep with the lines and the dependencies reordered
+        let reordered = "kernel/e.ko:\n\
+                         kernel/d.ko:\n\
+                         kernel/c.ko: kernel/d.ko\n\
+                         kernel/a.ko: kernel/d.ko kernel/c.ko kernel/b.ko kernel/d.ko\n\
+                         kernel/b.ko: kernel/d.ko\n";
+        for modules_dep in [DIAMOND, reordered] {
+            let fx = Fixture::new();
+            let ki = fx.tree("6.1.0", modules_dep);
+            for input in [&["a"][..], &["d", "a", "b"], &["c", "a", "c"]] {
+                let mut out: Vec<u8> = vec![];
+                ki.write_modules_dep_normalized(&names(input), &mut out)
+                    .unwrap();
+                assert_eq!(String::from_utf8(out).unwrap(), expected, "{:?}", input);
             }
-            mod_tree.insert(r_kmodname.to_owned(), r_deps);
         }
 
-        mod_tree
+        // Output of its own output is the same
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", expected);
+        let mut out: Vec<u8> = vec![];
+        ki.write_modules_dep_normalized(&names(&["a"]), &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
     }
 
-    /// Return true if a given module is a dependency to something else
-    pub fn is_dep(&self, name: &str) -> bool {
-        self.lookup_deplist.contains(name)
+    #[test]
+    fn write_modules_dep_normalized_cycle() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/x.ko: kernel/y.ko\nkernel/y.ko: kernel/x.ko\n",
+        );
+        let mut out: Vec<u8> = vec![];
+        ki.write_modules_dep_normalized(&names(&["y"]), &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "kernel/x.ko: kernel/y.ko\nkernel/y.ko: kernel/x.ko\n"
+        );
     }
 
-    /// Same as `get_deps_for`, except returns flattened list
-    /// for all modules with their dependencies.
-    pub fn get_deps_for_flatten(&self, names: &[String]) -> Vec<String> {
-        let mut buff: HashSet<String> = HashSet::default();
-        for (mname, mdeps) in &self.get_deps_for(names) {
-            buff.insert(mname.to_owned());
-            buff.extend(mdeps.to_owned());
+    #[test]
+    fn dep_line_colons() {
+        for (line, split) in [
+            (
+                "kernel/a.ko: kernel/b.ko",
+                Some(("kernel/a.ko", " kernel/b.ko")),
+            ),
+            ("kernel/a.ko:", Some(("kernel/a.ko", ""))),
+            (
+                "kernel/a.ko.zst: kernel/b.ko.zst",
+                Some(("kernel/a.ko.zst", " kernel/b.ko.zst")),
+            ),
+            (
+                "kernel/a:b/foo.ko.zst: kernel/c:d/bar.ko.zst",
+                Some(("kernel/a:b/foo.ko.zst", " kernel/c:d/bar.ko.zst")),
+            ),
+            (
+                "kernel/x.kobj/foo.ko: kernel/b.ko",
+                Some(("kernel/x.kobj/foo.ko", " kernel/b.ko")),
+            ),
+            (
+                "kernel/odd: kernel/b.ko",
+                Some(("kernel/odd", " kernel/b.ko")),
+            ),
+            ("no colon at all", None),
+        ] {
+            assert_eq!(split_dep_line(line), split, "{}", line);
         }
 
-        buff.iter().map(|x| x.to_owned()).collect()
+        let parsed = parse_deps_slice(
+            b"kernel/a:b/foo.ko.zst: kernel/c:d/bar.ko.zst\nkernel/c:d/bar.ko.zst:\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.deplist["kernel/a:b/foo.ko.zst"],
+            names(&["kernel/c:d/bar.ko.zst"])
+        );
+        assert!(parsed.deplist["kernel/c:d/bar.ko.zst"].is_empty());
+        assert!(parsed.lookup_deplist.contains("bar"));
     }
 
-    /// Get all found modules
-    pub fn get_disk_modules(&self) -> Vec<String> {
-        let mut buff: HashSet<String> = HashSet::default();
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_modules_dep() {
+        let modules_dep = "kernel/a.ko: kernel/b.ko\nkernel/b.ko:\n";
+        for (fext, compression) in [
+            (".gz", ModCompression::Gzip),
+            (".xz", ModCompression::Xz),
+            (".zst", ModCompression::Zstd),
+        ] {
+            let fx = Fixture::new();
+            fx.tree("6.1.0", modules_dep);
+            std::fs::remove_file(fx.kpath("6.1.0", MOD_DEP_F)).unwrap();
+            fx.kfile(
+                "6.1.0",
+                &format!("{}{}", MOD_DEP_F, fext),
+                compress(modules_dep.as_bytes(), compression),
+            );
 
-        for (modname, moddeps) in &self.deplist {
-            buff.insert(modname.to_owned());
-            buff.extend(moddeps.to_owned());
+            let ki = fx.kernel("6.1.0");
+            assert!(ki.is_valid(), "{}", fext);
+            assert!(ki.get_dep_path().ends_with(format!("modules.dep{}", fext)));
+            assert_eq!(
+                ki.get_deps_for(&names(&["a"]))["kernel/a.ko"],
+                names(&["kernel/b.ko"])
+            );
+
+            // Plain one is preferred
+            fx.kfile("6.1.0", MOD_DEP_F, "kernel/b.ko:\n");
+            let ki = fx.kernel("6.1.0");
+            assert!(ki.get_dep_path().ends_with(MOD_DEP_F));
+            assert_eq!(ki.module_count(), 1);
         }
+    }
 
-        let mut mods: Vec<String> = buff.iter().map(|x| x.to_string()).collect();
+    #[test]
+    fn modules_for_device_ids() {
+        let fx = Fixture::new();
+        fx.kfile(
+            "6.1.0",
+            MOD_ALIAS_F,
+            "alias pci:v00008086d000010D3sv*sd*bc*sc*i* e1000e\n\
+             alias pci:v00008086d*sv*sd*bc02sc00i* intel_class\n\
+             alias pci:v*d*sv*sd*bc*sc*i* catch_all\n\
+             alias usb:v0BDAp8153d*dc*dsc*dp*ic*isc*ip*in* r8152\n\
+             alias usb:v0BDAp8153d*dc*dsc*dp*icFFisc*ip*in* r8152_vendor\n",
+        );
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/net/e1000e.ko:\nkernel/net/intel_class.ko:\nkernel/net/catch_all.ko:\n\
+             kernel/net/r8152.ko:\nkernel/net/r8152_vendor.ko:\n",
+        );
+
+        let mut mods = ki.modules_for_pci_id(0x8086, 0x10d3);
         mods.sort();
+        assert_eq!(
+            mods,
+            names(&["kernel/net/catch_all.ko", "kernel/net/e1000e.ko"])
+        );
+        assert_eq!(
+            ki.modules_for_pci_id(0x10ec, 0x8168),
+            names(&["kernel/net/catch_all.ko"])
+        );
+        assert_eq!(
+            ki.modules_for_usb_id(0x0bda, 0x8153),
+            names(&["kernel/net/r8152.ko"])
+        );
+        assert!(ki.modules_for_usb_id(0x0bda, 0x8152).is_empty());
+    }
 
-        mods
+    #[test]
+    fn modules_at_tree_root() {
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", "foo.ko: bar.ko\nbar.ko:\n");
+
+        assert!(ki.is_valid());
+        assert_eq!(ki.resolve_candidates("foo"), names(&["foo.ko"]));
+        assert_eq!(ki.resolve_candidates("foo.ko"), names(&["foo.ko"]));
+        assert_eq!(
+            ki.get_deps_for(&names(&["foo"]))["foo.ko"],
+            names(&["bar.ko"])
+        );
+        assert_eq!(ki.resolve_path("foo"), Some(fx.kpath("6.1.0", "foo.ko")));
+        assert_eq!(
+            ki.get_load_order(&names(&["foo"])),
+            names(&["bar.ko", "foo.ko"])
+        );
+
+        // A path, which is not in modules.dep, is not resolved just by having a directory
+        assert!(ki.get_deps_for(&names(&["kernel/none.ko"])).is_empty());
+        assert!(ki.module_closure("kernel/none.ko").is_none());
+        assert!(ki.get_load_order(&names(&["kernel/none.ko"])).is_empty());
+    }
+
+    #[test]
+    fn partial_paths_by_whole_directories() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "extra/drivers/net/foo.ko:\nupdates/sound/foo.ko:\n",
+        );
+
+        assert_eq!(
+            ki.resolve_candidates("net/foo"),
+            names(&["extra/drivers/net/foo.ko"])
+        );
+        assert_eq!(
+            ki.resolve_candidates("drivers/net/foo.ko"),
+            names(&["extra/drivers/net/foo.ko"])
+        );
+        assert_eq!(
+            ki.resolve_candidates("updates/sound/foo"),
+            names(&["updates/sound/foo.ko"])
+        );
+        assert!(ki.resolve_candidates("et/foo").is_empty());
+        assert!(ki.resolve_candidates("ound/foo").is_empty());
+        assert_eq!(ki.resolve_candidates("foo").len(), 2);
+    }
+
+    #[test]
+    fn flatten_stable_order() {
+        let fx = Fixture::new();
+        let modules_dep: String = (0..50)
+            .map(|idx| match idx {
+                0 => "kernel/m0.ko:\n".to_string(),
+                idx => format!("kernel/m{}.ko: kernel/m{}.ko\n", idx, idx / 3),
+            })
+            .collect();
+        let ki = fx.tree("6.1.0", &modules_dep);
+        let input = names(&["m49", "m31", "m20", "m7"]);
+
+        let mods = ki.get_deps_for_flatten(&input);
+        let mut sorted = mods.clone();
+        sorted.sort();
+        assert_eq!(mods, sorted);
+        assert_eq!(mods.len(), 12);
+        for _ in 0..10 {
+            assert_eq!(ki.get_deps_for_flatten(&input), mods);
+            assert_eq!(fx.kernel("6.1.0").get_deps_for_flatten(&input), mods);
+        }
+
+        let mut reversed = input.clone();
+        reversed.reverse();
+        assert_eq!(ki.get_deps_for_flatten(&reversed), mods);
+    }
+
+    #[test]
+    fn dash_underscore_variants() {
+        let fx = Fixture::new();
+        fx.kfile(
+            "6.1.0",
+            MOD_ORDER_F,
+            "kernel/sound/pci/hda/snd-hda-intel.ko\nkernel/sound/snd_hda-intel.ko\n",
+        );
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/sound/pci/hda/snd-hda-intel.ko: kernel/sound/pci/hda/snd-hda-codec.ko\n\
+             kernel/sound/pci/hda/snd-hda-codec.ko:\n\
+             kernel/sound/snd_hda-intel.ko:\n\
+             kernel/drivers/i2c/i2c_core.ko:\n",
+        );
+
+        let both = names(&[
+            "kernel/sound/pci/hda/snd-hda-intel.ko",
+            "kernel/sound/snd_hda-intel.ko",
+        ]);
+        for name in [
+            "snd_hda_intel",
+            "snd-hda-intel",
+            "snd_hda-intel",
+            "snd-hda_intel.ko",
+        ] {
+            assert_eq!(ki.resolve_candidates(name), both, "{}", name);
+        }
+        assert_eq!(ki.resolve_candidates("hda/snd_hda_intel"), both[..1]);
+        assert_eq!(
+            ki.resolve_candidates("i2c-core"),
+            names(&["kernel/drivers/i2c/i2c_core.ko"])
+        );
+        assert!(ki.resolve_candidates("snd_hda").is_empty());
+        assert!(ki.resolve_candidates("SND_HDA_INTEL").is_empty());
+        assert_eq!(ki.duplicate_basenames()["snd_hda_intel"], both);
+        assert_eq!(
+            ki.get_deps_for(&names(&["snd_hda_intel"]))["kernel/sound/pci/hda/snd-hda-intel.ko"],
+            names(&["kernel/sound/pci/hda/snd-hda-codec.ko"])
+        );
+
+        let ki = fx.builder().case_insensitive(true).build("6.1.0").unwrap();
+        assert_eq!(ki.resolve_candidates("SND_HDA_INTEL"), both);
+        assert_eq!(ki.resolve_candidates("Hda/Snd-Hda-Intel"), both[..1]);
+    }
+
+    #[test]
+    fn extra_top_dir() {
+        let modules_dep = "extra/drivers/net/foo.ko: extra/lib/bar.ko\nextra/lib/bar.ko:\n";
+        let fx = Fixture::new();
+        fx.kfile("6.1.0", MOD_DEP_F, modules_dep);
+        fx.mkdir("lib/modules/6.1.0/extra");
+
+        // No module file exists, so only the configured top directory makes it valid
+        let ki = fx.kernel("6.1.0");
+        assert!(!ki.is_valid());
+        assert!(ki.invalid_reason().is_some());
+
+        let ki = fx.builder().top_dir("extra").build("6.1.0").unwrap();
+        assert!(ki.is_valid());
+        assert_eq!(ki.module_count(), 2);
+        for name in ["foo", "net/foo", "extra/drivers/net/foo.ko"] {
+            assert_eq!(
+                ki.get_deps_for(&names(&[name]))["extra/drivers/net/foo.ko"],
+                names(&["extra/lib/bar.ko"]),
+                "{}",
+                name
+            );
+        }
+        assert!(ki
+            .resolve_candidates("extra/drivers/net/none.ko")
+            .is_empty());
+
+        // Default top directory works too, once the modules are on the disk
+        let ki = fx.tree("6.1.0", modules_dep);
+        assert!(ki.is_valid());
+        assert_eq!(
+            ki.resolve_path("foo"),
+            Some(fx.kpath("6.1.0", "extra/drivers/net/foo.ko"))
+        );
+    }
+
+    static BUILTIN: &str = "kernel/fs/ext4/ext4.ko\n\
+                            kernel/fs/jbd2/jbd2.ko\n\
+                            kernel/drivers/net/virtio_net.ko\n\
+                            kernel/drivers/block/virtio_blk.ko\n\
+                            kernel/drivers/virtio/virtio.ko\n";
+
+    #[test]
+    fn mostly_builtin_kernel() {
+        let fx = Fixture::new();
+        fx.kfile("6.1.0", MOD_BUILTIN_F, BUILTIN);
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/fs/fuse/fuse.ko: kernel/drivers/virtio/virtio.ko\n",
+        );
+
+        assert!(ki.is_valid());
+        assert_eq!(ki.module_count(), 1);
+        assert_eq!(ki.builtin_count(), 5);
+        assert_eq!(
+            ki.builtin_modules(),
+            names(&[
+                "kernel/drivers/block/virtio_blk.ko",
+                "kernel/drivers/net/virtio_net.ko",
+                "kernel/drivers/virtio/virtio.ko",
+                "kernel/fs/ext4/ext4.ko",
+                "kernel/fs/jbd2/jbd2.ko",
+            ])
+        );
+        assert!(ki.is_resolvable("fuse"));
+        assert!(ki.is_resolvable("ext4"));
+        assert!(ki.is_resolvable("virtio-net"));
+        assert!(!ki.is_resolvable("btrfs"));
+
+        let report = ki.resolve_report(&names(&["fuse", "ext4", "btrfs"]));
+        assert!(matches!(
+            report.modules[0].1,
+            ModResolution::Resolved { .. }
+        ));
+        assert_eq!(report.modules[1].1, ModResolution::Builtin);
+        assert_eq!(report.modules[2].1, ModResolution::NotFound);
+    }
+
+    #[test]
+    fn fully_builtin_kernel() {
+        let fx = Fixture::new();
+        fx.kfile("6.1.0", MOD_BUILTIN_F, BUILTIN);
+
+        let ki = fx.kernel("6.1.0");
+        assert!(ki.is_valid());
+        assert_eq!(ki.module_count(), 0);
+        assert_eq!(ki.builtin_count(), 5);
+        assert!(ki.is_resolvable("ext4"));
+        assert!(ki.get_deps_for(&names(&["ext4"])).is_empty());
+        assert!(KernelInfo::metadata_only(fx.root(), "6.1.0")
+            .unwrap()
+            .is_valid());
+        assert_eq!(
+            crate::list_kernel_versions(Some(fx.root())).unwrap(),
+            names(&["6.1.0"])
+        );
+
+        // Empty leftover directory of a purged kernel is still not valid
+        fx.mkdir("lib/modules/6.0.0");
+        assert!(!fx.kernel("6.0.0").is_valid());
+    }
+
+    #[test]
+    fn kernel_config_of_rootfs() {
+        // Version of the host kernel, but in another root: /proc/config.gz is not its config
+        let kver = read_to_string(PROC_OSRELEASE)
+            .map(|x| x.trim().to_string())
+            .unwrap_or("6.1.0".to_string());
+        let fx = Fixture::new();
+        let ki = fx.tree(&kver, "kernel/a.ko:\n");
+        assert!(!is_running_kernel(Path::new(fx.root()), &kver));
+        assert!(
+            matches!(ki.kernel_config(), Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound)
+        );
+
+        fx.write(
+            &format!("boot/config-{}", kver),
+            "CONFIG_MODULES=y\nCONFIG_EXT4_FS=m\n# CONFIG_MODULE_SIG_FORCE is not set\n\
+             CONFIG_LOCALVERSION=\"-test\"\n",
+        );
+        let config = ki.kernel_config().unwrap();
+        assert_eq!(config.len(), 4);
+        assert_eq!(config["CONFIG_MODULES"], "y");
+        assert_eq!(config["CONFIG_EXT4_FS"], "m");
+        assert_eq!(config["CONFIG_MODULE_SIG_FORCE"], "n");
+        assert_eq!(config["CONFIG_LOCALVERSION"], "-test");
+    }
+
+    #[test]
+    fn concurrency_cap() {
+        let fx = Fixture::new();
+        fx.tree("6.1.0", "kernel/a.ko:\n");
+        assert!(fx.kernel("6.1.0").get_concurrency() >= 1);
+
+        let modnames: Vec<String> = (0..24).map(|x| format!("kernel/m{}.ko", x)).collect();
+        for cap in [1, 2, 3] {
+            let ki = fx.builder().concurrency(cap).build("6.1.0").unwrap();
+            assert_eq!(ki.get_concurrency(), cap);
+
+            let (active, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            let done = Mutex::new(vec![]);
+            let results = ki
+                .map_modules(
+                    &modnames,
+                    |modname| {
+                        let now = active.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                        peak.fetch_max(now, AtomicOrdering::SeqCst);
+                        thread::sleep(std::time::Duration::from_millis(2));
+                        active.fetch_sub(1, AtomicOrdering::SeqCst);
+                        Ok(modname.to_string())
+                    },
+                    |p| done.lock().unwrap().push(p.done),
+                )
+                .unwrap();
+            assert!(peak.load(AtomicOrdering::SeqCst) <= cap, "cap {}", cap);
+            assert_eq!(results, modnames);
+            assert_eq!(done.into_inner().unwrap(), (1..=24).collect::<Vec<_>>());
+        }
+
+        // Jobs after the failed one are not run, one at a time
+        let ki = fx.builder().concurrency(1).build("6.1.0").unwrap();
+        let runs = AtomicUsize::new(0);
+        let res: Result<Vec<()>, Error> = ki.map_modules(
+            &modnames,
+            |modname| {
+                runs.fetch_add(1, AtomicOrdering::SeqCst);
+                Err(Error::ModuleNotFound(modname.to_string()))
+            },
+            |_| {},
+        );
+        assert!(matches!(res, Err(Error::ModuleNotFound(name)) if name == "kernel/m0.ko"));
+        assert_eq!(runs.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[test]
+    fn builtin_dependency_plan() {
+        let fx = Fixture::new();
+        fx.kfile("6.1.0", MOD_BUILTIN_F, "kernel/b/bar.ko\n");
+        fx.module("6.1.0", "kernel/a/foo.ko", &["license=GPL", "depends=bar"]);
+        // modules.dep of the kernel, before bar was compiled in
+        let ki = fx.tree("6.1.0", "kernel/a/foo.ko: kernel/b/bar.ko\n");
+        let foo = names(&["kernel/a/foo.ko"]);
+
+        assert_eq!(ki.get_load_order(&names(&["foo"])), foo);
+        assert_eq!(
+            ki.get_deps_for(&names(&["foo", "bar", "kernel/b/bar.ko"])),
+            HashMap::from([("kernel/a/foo.ko".to_string(), vec![])])
+        );
+        assert_eq!(ki.module_closure("foo"), Some(vec![]));
+        assert_eq!(ki.module_closure("kernel/b/bar.ko"), None);
+        assert_eq!(
+            ki.resolve_report(&names(&["kernel/b/bar.ko"])).modules[0].1,
+            ModResolution::Builtin
+        );
+        assert_eq!(ki.get_deps_for_flatten(&names(&["foo"])), foo);
+        assert_eq!(ki.get_deps_for_flatten(&names(&["foo", "bar"])), foo);
+        assert_eq!(ki.simulate_modprobe("foo").unwrap().modules, foo);
+        assert!(ki.simulate_modprobe("bar").unwrap().modules.is_empty());
+        assert_eq!(ki.is_closed(&names(&["foo"])).unwrap(), (true, vec![]));
+
+        // Consumers of the closure do not look for the builtin module file
+        let footprint = ki.estimated_footprint(&names(&["foo"])).unwrap();
+        assert_eq!(footprint.module_count, 1);
+        assert!(ki
+            .missing_firmware(&names(&["foo"]), &[])
+            .unwrap()
+            .is_empty());
+        let licenses = ki.licenses_for(&names(&["foo"])).unwrap();
+        assert_eq!(licenses.len(), 1);
+        assert_eq!(licenses["kernel/a/foo.ko"], "GPL");
+    }
+
+    #[test]
+    fn direct_deps_cycle() {
+        // Not transitive and with a cycle of three
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/a.ko: kernel/b.ko kernel/c.ko kernel/d.ko\n\
+             kernel/b.ko: kernel/c.ko\n\
+             kernel/c.ko: kernel/d.ko\n\
+             kernel/d.ko: kernel/b.ko\n",
+        );
+        let all = names(&["kernel/a.ko", "kernel/b.ko", "kernel/c.ko", "kernel/d.ko"]);
+
+        let mut deps = ki.get_deps_for(&names(&["a"]))["kernel/a.ko"].clone();
+        deps.sort();
+        assert_eq!(deps, all[1..]);
+        assert_eq!(ki.get_deps_for_flatten(&names(&["a"])), all);
+
+        let order = ki.get_load_order(&names(&["a"]));
+        assert_eq!(order.len(), 4);
+        assert_eq!(order.last().unwrap(), "kernel/a.ko");
+
+        // Indirect dependencies of a well-formed modules.dep are still dropped
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", DIAMOND);
+        assert_eq!(
+            ki.get_direct_deps("kernel/a.ko"),
+            vec!["kernel/b.ko", "kernel/c.ko"]
+        );
+    }
+
+    #[test]
+    fn load_failure_reason() {
+        let fx = Fixture::new();
+        fx.mkdir("lib/modules/6.1.0/kernel");
+        fx.kfile("6.1.0", "modules.dep.zst", "not zstd at all");
+
+        let ki = fx.kernel("6.1.0");
+        let reason = ki.invalid_reason().unwrap().to_string();
+        assert!(!reason.is_empty());
+        assert!(matches!(
+            ki.get_details("a"),
+            Err(Error::LoadFailed(kver, err)) if kver == "6.1.0" && err == reason
+        ));
+
+        // Metadata only is not a failure, the modules are just not loaded yet
+        let ki = fx.builder().metadata_only(true).build("6.1.0").unwrap();
+        assert!(matches!(ki.get_details("a"), Err(Error::NotLoaded(kver)) if kver == "6.1.0"));
+    }
+
+    #[cfg(feature = "kmod-index")]
+    #[test]
+    fn kmod_index_with_fallback() {
+        let fx = Fixture::new();
+        let txt = fx.tree("6.1.0", DIAMOND);
+        let index = kmod_index(&[
+            ("a", &["kernel/a.ko: kernel/b.ko kernel/c.ko kernel/d.ko"]),
+            ("b", &["kernel/b.ko: kernel/d.ko"]),
+            ("c", &["kernel/c.ko: kernel/d.ko"]),
+            ("d", &["kernel/d.ko:"]),
+            ("e", &["kernel/e.ko: kernel/d.ko"]),
+        ]);
+        let bin_f = format!("{}{}", MOD_DEP_F, MOD_INDEX_EXT);
+        fx.kfile("6.1.0", &bin_f, &index);
+
+        // Same tree, except the index is preferred, where it differs
+        let ki = fx.kernel("6.1.0");
+        assert!(ki.is_valid());
+        let all = names(&["a", "b", "c", "d"]);
+        assert_eq!(ki.get_deps_for(&all), txt.get_deps_for(&all));
+        assert_eq!(
+            ki.get_deps_for(&names(&["e"]))["kernel/e.ko"],
+            names(&["kernel/d.ko"])
+        );
+
+        // Malformed index with a valid header falls back to modules.dep
+        fx.kfile("6.1.0", &bin_f, &index[..index.len() - 5]);
+        let ki = fx.kernel("6.1.0");
+        assert!(ki.is_valid());
+        assert_eq!(ki.get_deps_for(&all), txt.get_deps_for(&all));
+        assert!(ki.get_deps_for(&names(&["e"]))["kernel/e.ko"].is_empty());
+
+        // Unless there is none
+        std::fs::remove_file(fx.kpath("6.1.0", MOD_DEP_F)).unwrap();
+        let ki = fx.kernel("6.1.0");
+        assert!(ki.invalid_reason().unwrap().contains("kmod index"));
     }
 }
@@ -0,0 +1,299 @@
+use crate::error::Error;
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind},
+};
+
+/// Magic of the kmod binary index, e.g. modules.dep.bin
+pub static KMOD_INDEX_MAGIC: u32 = 0xB007F457;
+
+/// Supported major version of the kmod index format. Minor versions are compatible.
+pub static KMOD_INDEX_VERSION_MAJOR: u32 = 0x0002;
+
+// Flags of a node offset
+static NODE_PREFIX: u32 = 0x80000000;
+static NODE_VALUES: u32 = 0x40000000;
+static NODE_CHILDS: u32 = 0x20000000;
+static NODE_MASK: u32 = 0x0FFFFFFF;
+
+/// Returns true if the data starts with a header of the supported kmod index.
+pub fn is_kmod_index(data: &[u8]) -> bool {
+    let idx = KmodIndex { data };
+    idx.long(0).is_ok_and(|x| x == KMOD_INDEX_MAGIC)
+        && idx
+            .long(4)
+            .is_ok_and(|x| x >> 16 == KMOD_INDEX_VERSION_MAJOR)
+}
+
+/// Parse a kmod binary index, as depmod writes it for libkmod (modules.dep.bin,
+/// modules.alias.bin, modules.symbols.bin etc). Format version 2.x is supported:
+///
+/// ```text
+/// u32     magic (0xB007F457)
+/// u32     version (major << 16 | minor)
+/// u32     root node offset
+///
+/// Each node, which offset flags tell what it has:
+/// char[]  prefix, NUL terminated (0x80000000)
+/// u8      first and last child character, followed by
+/// u32[]   child node offsets for the characters in between, 0 for none (0x20000000)
+/// u32     value count, followed by the values (0x40000000):
+///         u32 priority, char[] value, NUL terminated
+/// ```
+///
+/// All integers are big endian. Returns the keys with their values in the key order,
+/// values are in the priority order.
+pub fn parse_kmod_index(data: &[u8]) -> Result<Vec<(String, Vec<String>)>, Error> {
+    let idx = KmodIndex { data };
+    if !is_kmod_index(data) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a supported kmod index").into());
+    }
+
+    Ok(idx.walk(idx.long(8)?)?)
+}
+
+/// Reader of the kmod index nodes
+struct KmodIndex<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> KmodIndex<'a> {
+    fn bytes(&self, off: usize, len: usize) -> Result<&'a [u8], io::Error> {
+        off.checked_add(len)
+            .and_then(|end| self.data.get(off..end))
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Truncated kmod index"))
+    }
+
+    fn long(&self, off: usize) -> Result<u32, io::Error> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.bytes(off, 4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Get a NUL terminated string and the offset after it
+    fn string(&self, off: usize) -> Result<(&'a [u8], usize), io::Error> {
+        let rest = self.bytes(off, 0).map(|_| &self.data[off..])?;
+        match rest.iter().position(|b| *b == 0) {
+            Some(len) => Ok((&rest[..len], off + len + 1)),
+            None => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unterminated string in kmod index",
+            )),
+        }
+    }
+
+    /// Walk the nodes depth-first from the root, collecting the values by the keys.
+    ///
+    /// Every node has one parent, so a node, which is reached twice, is malformed.
+    /// This keeps a malformed index from looping or walking shared nodes over and over,
+    /// the walk is not recursive, so a deep one does not overflow the stack.
+    fn walk(&self, root: u32) -> Result<Vec<(String, Vec<String>)>, io::Error> {
+        let mut entries: Vec<(String, Vec<String>)> = vec![];
+        let mut seen: HashSet<u32> = HashSet::default();
+
+        // Nodes to walk with their key length of the parent and their character.
+        // The key is of the last walked node, which comes through the parent.
+        let mut key: Vec<u8> = vec![];
+        let mut stack: Vec<(u32, usize, Option<u8>)> = vec![(root, 0, None)];
+        while let Some((node, parent_len, ch)) = stack.pop() {
+            key.truncate(parent_len);
+            key.extend(ch);
+            let noff = node & NODE_MASK;
+            if !seen.insert(noff) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed kmod index node at {}", noff),
+                ));
+            }
+
+            let mut off = noff as usize;
+            if node & NODE_PREFIX != 0 {
+                let (prefix, next) = self.string(off)?;
+                key.extend_from_slice(prefix);
+                off = next;
+            }
+
+            let mut children: Vec<(u8, u32)> = vec![];
+            if node & NODE_CHILDS != 0 {
+                let range = self.bytes(off, 2)?;
+                off += 2;
+                for ch in range[0]..=range[1] {
+                    let child = self.long(off)?;
+                    off += 4;
+                    if child != 0 {
+                        children.push((ch, child));
+                    }
+                }
+            }
+
+            if node & NODE_VALUES != 0 {
+                let mut values: Vec<String> = vec![];
+                let count = self.long(off)?;
+                off += 4;
+                for _ in 0..count {
+                    let (value, next) = self.string(off + 4)?;
+                    values.push(String::from_utf8_lossy(value).to_string());
+                    off = next;
+                }
+                entries.push((String::from_utf8_lossy(&key).to_string(), values));
+            }
+
+            // Reversed, so the children are walked in the key order
+            for (ch, child) in children.into_iter().rev() {
+                stack.push((child, key.len(), Some(ch)));
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    static ENTRIES: &[(&str, &[&str])] = &[
+        ("ext4", &["kernel/fs/ext4/ext4.ko: kernel/fs/jbd2/jbd2.ko"]),
+        ("i2c_core", &["kernel/drivers/i2c/i2c-core.ko:"]),
+        (
+            "i2c_dev",
+            &["kernel/drivers/i2c/i2c-dev.ko: kernel/drivers/i2c/i2c-core.ko"],
+        ),
+        ("jbd2", &["kernel/fs/jbd2/jbd2.ko:"]),
+        ("snd", &["first", "second"]),
+        ("snd_hda", &["kernel/sound/snd-hda.ko: kernel/sound/snd.ko"]),
+    ];
+
+    fn owned(entries: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        entries
+            .iter()
+            .map(|(key, values)| (key.to_string(), names(values)))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = kmod_index(ENTRIES);
+        assert!(is_kmod_index(&data));
+        assert_eq!(parse_kmod_index(&data).unwrap(), owned(ENTRIES));
+    }
+
+    #[test]
+    fn deep_index() {
+        // One node per character, deeper than the stack would take recursively
+        let key = "m".repeat(100_000);
+        let mut data = kmod_index(&[(&key, &["deep"])]);
+        assert_eq!(
+            parse_kmod_index(&data).unwrap(),
+            owned(&[(&key, &["deep"])])
+        );
+
+        // Same without the prefix: a chain of single child nodes, each before its parent
+        data.truncate(12);
+        let mut child: u32 = 0;
+        for idx in 0..100_000 {
+            let offset = data.len() as u32;
+            if idx == 0 {
+                data.extend(1u32.to_be_bytes());
+                data.extend(0u32.to_be_bytes());
+                data.extend(b"deep\0");
+                child = offset | NODE_VALUES;
+            } else {
+                data.extend(b"mm");
+                data.extend(child.to_be_bytes());
+                child = offset | NODE_CHILDS;
+            }
+        }
+        data[8..12].copy_from_slice(&child.to_be_bytes());
+        let entries = parse_kmod_index(&data).unwrap();
+        assert_eq!(entries, owned(&[(&key[1..], &["deep"])]));
+    }
+
+    #[test]
+    fn bad_header() {
+        let data = kmod_index(ENTRIES);
+        for (off, byte) in [(0, 0xB0), (3, 0x00), (5, 0x03)] {
+            let mut bad = data.clone();
+            bad[off] ^= byte | 1;
+            assert!(!is_kmod_index(&bad));
+            assert!(matches!(
+                parse_kmod_index(&bad),
+                Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData
+            ));
+        }
+
+        // Minor versions are compatible
+        let mut minor = data.clone();
+        minor[7] = 0x7f;
+        assert!(parse_kmod_index(&minor).is_ok());
+    }
+
+    #[test]
+    fn truncated() {
+        let data = kmod_index(ENTRIES);
+        for len in 0..data.len() {
+            assert!(parse_kmod_index(&data[..len]).is_err(), "{}", len);
+        }
+    }
+
+    /// Header of an index with a leaf node with "x" value at 12
+    fn with_leaf() -> (Vec<u8>, u32) {
+        let mut data = kmod_index(&[("a", &["x"])]);
+        data.truncate(12);
+        data.extend(1u32.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        data.extend(b"x\0");
+
+        (data, 12 | NODE_VALUES)
+    }
+
+    #[test]
+    fn shared_and_looped_nodes() {
+        // Two children of the root are the same node
+        let (mut data, leaf) = with_leaf();
+        let root = data.len() as u32 | NODE_CHILDS;
+        data.extend(b"ab");
+        data.extend(leaf.to_be_bytes());
+        data.extend(leaf.to_be_bytes());
+        data[8..12].copy_from_slice(&root.to_be_bytes());
+        assert!(matches!(
+            parse_kmod_index(&data),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData
+        ));
+
+        // Each of the nodes has both children at the next one, so there are 2^40 paths
+        let (mut data, mut child) = with_leaf();
+        for _ in 0..40 {
+            let offset = data.len() as u32;
+            data.extend(b"ab");
+            data.extend(child.to_be_bytes());
+            data.extend(child.to_be_bytes());
+            child = offset | NODE_CHILDS;
+        }
+        data[8..12].copy_from_slice(&child.to_be_bytes());
+        assert!(matches!(
+            parse_kmod_index(&data),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData
+        ));
+
+        // Child is its own parent
+        let (mut data, _) = with_leaf();
+        let root = data.len() as u32 | NODE_CHILDS;
+        data.extend(b"aa");
+        data.extend(root.to_be_bytes());
+        data[8..12].copy_from_slice(&root.to_be_bytes());
+        assert!(matches!(
+            parse_kmod_index(&data),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData
+        ));
+
+        // Single child of the leaf is fine
+        let (mut data, leaf) = with_leaf();
+        let root = data.len() as u32 | NODE_CHILDS;
+        data.extend(b"aa");
+        data.extend(leaf.to_be_bytes());
+        data[8..12].copy_from_slice(&root.to_be_bytes());
+        assert_eq!(parse_kmod_index(&data).unwrap(), owned(&[("a", &["x"])]));
+    }
+}
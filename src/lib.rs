@@ -1,12 +1,294 @@
+pub mod cpio;
+pub mod error;
 pub mod kerman;
+#[cfg(feature = "kmod-index")]
+pub mod kmodindex;
+pub mod modalias;
 pub mod moddeps;
+pub mod modfile;
 pub mod modinfo;
+#[cfg(target_os = "linux")]
+pub mod modload;
+pub mod modprobe;
+#[cfg(test)]
+mod testutil;
 
-use kerman::{KernelInfo, MOD_D};
-use std::{fs::read_dir, io::Error};
+use cpio::CpioWriter;
+use error::Error;
+use kerman::{KernelInfo, ModResolution, FIRMWARE_D, MOD_D, MOD_DEP_F};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::{read, read_dir},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// Module archive, made by `build_module_archive`
+#[derive(Debug, Clone, Default)]
+pub struct BuildSummary {
+    /// Number of the packed modules, including the dependencies
+    pub module_count: usize,
+
+    /// Number of the packed firmware files
+    pub firmware_count: usize,
+
+    /// Requested modules, which were not found and are not compiled into the kernel
+    pub missing_modules: Vec<String>,
+
+    /// Firmware files, required by the modules, but not found in the firmware directory
+    pub missing_firmware: Vec<String>,
+
+    /// Size of the archive in bytes
+    pub bytes: u64,
+}
+
+/// Kernels in the system, made by `scan_kernel_infos`
+#[derive(Debug, Clone, Default)]
+pub struct KernelScan {
+    /// Valid kernels (see `KernelInfo::is_valid`)
+    pub kernels: Vec<KernelInfo>,
+
+    /// Directories of the modules directory, which were skipped, as their names are not valid UTF-8
+    pub skipped: Vec<OsString>,
+}
 
 /// Get the list of existing kernels in the system.
+/// Directories, whose names are not valid UTF-8, are skipped (see `scan_kernel_infos`).
 pub fn get_kernel_infos(rootfs: Option<&str>) -> Result<Vec<KernelInfo>, Error> {
+    Ok(scan_kernel_infos(rootfs)?.kernels)
+}
+
+/// Same as `get_kernel_infos`, but also returns names of the directories,
+/// which were skipped, as they are not valid UTF-8, e.g. to warn about them.
+pub fn scan_kernel_infos(rootfs: Option<&str>) -> Result<KernelScan, Error> {
+    let (rfs_path, mod_d) = get_modules_dir(rootfs);
+    let mut scan = KernelScan::default();
+    for fres in read_modules_dir(&mod_d)? {
+        let fd = fres?;
+        if !fd.file_type().is_ok_and(|x| x.is_dir()) {
+            continue;
+        }
+
+        let fname = fd.file_name();
+        let kinfo = match fname.to_str() {
+            Some(kver) => KernelInfo::new(&rfs_path, kver)?,
+            None => {
+                scan.skipped.push(fname);
+                continue;
+            }
+        };
+        if kinfo.is_valid() {
+            scan.kernels.push(kinfo);
+        }
+    }
+
+    Ok(scan)
+}
+
+/// Get modules, which are the same in several kernels of the system, e.g. to hardlink them.
+/// Modules are compared by their decompressed content (see `KernelInfo::hash_modules`),
+/// so a module recompressed between the kernels is still the same.
+///
+/// Returns a map "hex digest" -> sorted kernel versions (see `cmp_kernel_versions`)
+/// of the modules in at least two kernels. Modules missing on the disk are skipped.
+pub fn shared_modules(rootfs: Option<&str>) -> Result<HashMap<String, Vec<String>>, Error> {
+    let mut shared: HashMap<String, Vec<String>> = HashMap::default();
+    for kinfo in get_kernel_infos(rootfs)? {
+        let modnames: Vec<String> = kinfo
+            .get_disk_module_paths()
+            .into_iter()
+            .filter(|x| kinfo.get_kernel_path().join(x).is_file())
+            .collect();
+        for digest in kinfo.hash_modules(&modnames)?.into_values() {
+            shared
+                .entry(digest)
+                .or_default()
+                .push(kinfo.version.to_owned());
+        }
+    }
+
+    shared.retain(|_, versions| {
+        versions.sort_by(|a, b| cmp_kernel_versions(a, b));
+        versions.dedup();
+        versions.len() > 1
+    });
+
+    Ok(shared)
+}
+
+/// Resolve the modules of a kernel with all their dependencies and pack them
+/// into a CPIO archive, ready for an initramfs: the modules in a load order
+/// under "lib/modules/<version>", modules.dep for just them and their firmware
+/// under "lib/firmware".
+///
+/// Firmware is searched in "<firmware_root>/<version>", then in the firmware root itself,
+/// as the kernel does, and is packed as it is on the disk, also compressed.
+/// Missing modules and firmware do not fail the archive, but are in the summary.
+pub fn build_module_archive<W: Write>(
+    rootfs: Option<&str>,
+    kver: &str,
+    names: &[String],
+    firmware_root: &Path,
+    out: W,
+) -> Result<BuildSummary, Error> {
+    let (rfs_path, _) = get_modules_dir(rootfs);
+    let kinfo = KernelInfo::new(&rfs_path, kver)?;
+    if !kinfo.is_valid() {
+        return Err(Error::KernelNotFound(kver.to_string()));
+    }
+
+    let mut summary = BuildSummary {
+        missing_modules: kinfo
+            .resolve_report(names)
+            .modules
+            .into_iter()
+            .filter(|(_, resolution)| *resolution == ModResolution::NotFound)
+            .map(|(name, _)| name)
+            .collect(),
+        ..Default::default()
+    };
+
+    let mut cpio = CpioWriter::new(out);
+    let archive_path = kinfo.get_archive_path();
+    let mods = kinfo.get_load_order(names);
+    summary.module_count = kinfo
+        .add_cpio_modules(&mut cpio, &mods, &HashSet::default(), |_| {})?
+        .written
+        .len();
+
+    let mut moddep: Vec<u8> = vec![];
+    kinfo.write_modules_dep(&mods, &mut moddep)?;
+    cpio.add_file(&format!("{}/{}", archive_path, MOD_DEP_F), 0o644, &moddep)?;
+
+    let fw_roots = [firmware_root.join(kver), firmware_root.to_path_buf()];
+    for fw in kinfo.locate_firmware(&mods, &fw_roots)? {
+        let fw_path = match fw.path {
+            Some(fw_path) => fw_path,
+            None => {
+                summary.missing_firmware.push(fw.name);
+                continue;
+            }
+        };
+
+        cpio.add_file(
+            &format!(
+                "{}/{}",
+                FIRMWARE_D,
+                fw_path
+                    .strip_prefix(firmware_root)
+                    .unwrap_or(&fw_path)
+                    .to_string_lossy()
+            ),
+            0o644,
+            &read(&fw_path)?,
+        )?;
+        summary.firmware_count += 1;
+    }
+    summary.bytes = cpio.finish()?;
+
+    Ok(summary)
+}
+
+/// Iterate over the kernels in the system, loading each one only when it is reached.
+/// Fails with `KernelNotFound` if there is no kernel modules directory at all.
+///
+/// Unlike `get_kernel_infos`, invalid kernels (see `KernelInfo::is_valid`) are not skipped
+/// and a kernel, that failed to set up, does not stop the iteration. A directory,
+/// whose name is not valid UTF-8, is not a kernel version and comes as an `InvalidData` error.
+pub fn iter_kernels(
+    rootfs: Option<&str>,
+) -> Result<impl Iterator<Item = Result<KernelInfo, Error>>, Error> {
+    let (rfs_path, mod_d) = get_modules_dir(rootfs);
+    Ok(read_modules_dir(&mod_d)?.filter_map(move |fres| {
+        let fd = match fres {
+            Ok(fd) => fd,
+            Err(err) => return Some(Err(err.into())),
+        };
+        if !fd.file_type().is_ok_and(|x| x.is_dir()) {
+            return None;
+        }
+
+        let fname = fd.file_name();
+        Some(match fname.to_str() {
+            Some(kver) => KernelInfo::new(&rfs_path, kver),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Kernel version {:?} is not valid UTF-8", fname),
+            )
+            .into()),
+        })
+    }))
+}
+
+/// List versions of the kernels in the system, sorted (see `cmp_kernel_versions`).
+/// Only the modules directory is read, so this is much cheaper than `get_kernel_infos`.
+///
+/// Kernels are validated as a KernelInfo for the metadata only (see `KernelInfo::metadata_only`):
+/// by the "kernel" directory, modules.dep (also compressed) or modules.builtin. Unlike
+/// `KernelInfo::is_valid` of a loaded one, modules.dep of other than the stock layout
+/// is not parsed, so a kernel with only a stale modules.dep is still listed.
+pub fn list_kernel_versions(rootfs: Option<&str>) -> Result<Vec<String>, Error> {
+    let (rfs_path, mod_d) = get_modules_dir(rootfs);
+    let mut versions: Vec<String> = vec![];
+    for fres in read_modules_dir(&mod_d)? {
+        let fpath = fres?.path();
+        let kver = match fpath.file_name().and_then(|x| x.to_str()) {
+            Some(kver) => kver,
+            None => continue,
+        };
+        if KernelInfo::metadata_only(&rfs_path, kver)?.is_valid() {
+            versions.push(kver.to_string());
+        }
+    }
+    versions.sort_by(|a, b| cmp_kernel_versions(a, b));
+
+    Ok(versions)
+}
+
+/// Compare kernel versions, so that numbers are compared as numbers:
+/// "6.1.9" < "6.1.10" < "6.10.0-rc1" < "6.10.0". Other parts are compared as strings.
+pub fn cmp_kernel_versions(a: &str, b: &str) -> Ordering {
+    let chunks = |v: &str| -> Vec<(bool, String)> {
+        let mut chunks: Vec<(bool, String)> = vec![];
+        for c in v.chars() {
+            match chunks.last_mut() {
+                Some((digits, chunk)) if *digits == c.is_ascii_digit() => chunk.push(c),
+                _ => chunks.push((c.is_ascii_digit(), c.to_string())),
+            }
+        }
+        chunks
+    };
+
+    let (ac, bc) = (chunks(a), chunks(b));
+    for ((ad, a), (bd, b)) in ac.iter().zip(&bc) {
+        let ord = if *ad && *bd {
+            let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+            a.len().cmp(&b.len()).then(a.cmp(b))
+        } else {
+            a.cmp(b)
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    // Release candidates precede the release: "6.1-rc1" < "6.1"
+    let rc = |chunks: &[(bool, String)]| {
+        chunks
+            .get(ac.len().min(bc.len()))
+            .map(|x| x.1.starts_with("-rc"))
+    };
+    match (rc(&ac), rc(&bc)) {
+        (Some(true), None) => Ordering::Less,
+        (None, Some(true)) => Ordering::Greater,
+        _ => ac.len().cmp(&bc.len()),
+    }
+}
+
+/// Get the root filesystem path, as KernelInfo takes it, and the modules directory in it.
+fn get_modules_dir(rootfs: Option<&str>) -> (String, String) {
     let mut rfs_path = "";
     if let Some(mut rootfs) = rootfs {
         rootfs = rootfs.trim().trim_end_matches("/");
@@ -14,19 +296,191 @@ pub fn get_kernel_infos(rootfs: Option<&str>) -> Result<Vec<KernelInfo>, Error>
             rfs_path = rootfs;
         }
     }
+    let mod_d = join_rootfs(rfs_path, MOD_D).to_string_lossy().to_string();
+
+    (rfs_path.to_string(), mod_d)
+}
+
+/// Join a path within the root filesystem to the root: "/mnt/" and "/lib/modules"
+/// are "/mnt/lib/modules". Root "/" is the host filesystem, so the path stays absolute,
+/// and an empty root leaves the path as it is. Surrounding whitespace of the root is ignored.
+fn join_rootfs(root: &str, suffix: &str) -> PathBuf {
+    let root = root.trim();
+    if root.is_empty() {
+        return PathBuf::from(suffix);
+    }
 
-    let mut kernels: Vec<KernelInfo> = vec![];
+    PathBuf::from(format!(
+        "{}/{}",
+        root.trim_end_matches('/'),
+        suffix.trim_start_matches('/')
+    ))
+}
 
-    for fres in read_dir(format!("{}{}", rfs_path.trim_end_matches("/"), MOD_D)).unwrap() {
-        let fd = fres.unwrap();
-        if fd.file_type().unwrap().is_dir() {
-            let kinfo: KernelInfo =
-                KernelInfo::new(rfs_path, fd.path().file_name().unwrap().to_str().unwrap())?;
-            if kinfo.is_valid() {
-                kernels.push(kinfo);
-            }
+/// Read the modules directory. Fails with `KernelNotFound` if there is none.
+fn read_modules_dir(mod_d: &str) -> Result<std::fs::ReadDir, Error> {
+    match read_dir(Path::new(mod_d)) {
+        Ok(mod_dir) => Ok(mod_dir),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Err(Error::KernelNotFound(mod_d.to_string()))
         }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kerman::MOD_BUILTIN_F, testutil::*};
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_kernel_dir() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let fx = Fixture::new();
+        fx.tree("6.1.0", "kernel/a.ko:\n");
+        let bad = OsStr::from_bytes(b"6.1.\xff");
+        let bad_d = fx.path("lib/modules").join(bad);
+        std::fs::create_dir_all(bad_d.join("kernel")).unwrap();
+        std::fs::write(bad_d.join(MOD_DEP_F), "kernel/a.ko:\n").unwrap();
+
+        let scan = scan_kernel_infos(Some(fx.root())).unwrap();
+        assert_eq!(scan.skipped, vec![bad.to_os_string()]);
+        assert_eq!(scan.kernels.len(), 1);
+        assert_eq!(scan.kernels[0].version, "6.1.0");
+
+        let kernels = get_kernel_infos(Some(fx.root())).unwrap();
+        assert_eq!(kernels.len(), 1);
+        assert!(iter_kernels(Some(fx.root()))
+            .unwrap()
+            .any(|x| matches!(x, Err(Error::Io(err)) if err.kind() == ErrorKind::InvalidData)));
+    }
+
+    #[test]
+    fn kernel_versions_by_layout() {
+        let fx = Fixture::new();
+        fx.tree("6.10.0", "kernel/a.ko:\n");
+        fx.mkdir("lib/modules/6.1.0/kernel");
+        fx.tree("6.2.0", "extra/a.ko:\n");
+        fx.kfile("6.3.0", MOD_BUILTIN_F, "kernel/fs/ext4/ext4.ko\n");
+        fx.kfile("6.4.0", "modules.dep.zst", "");
+        fx.mkdir("lib/modules/6.0.0-leftover/build");
+        fx.kfile("6.5.0", "modules.alias", "");
+        fx.write("lib/modules/README", "");
+
+        assert_eq!(
+            list_kernel_versions(Some(fx.root())).unwrap(),
+            names(&["6.1.0", "6.2.0", "6.3.0", "6.4.0", "6.10.0"])
+        );
+        assert!(matches!(
+            list_kernel_versions(Some(&format!("{}/none", fx.root()))),
+            Err(Error::KernelNotFound(_))
+        ));
     }
 
-    Ok(kernels)
+    #[test]
+    fn join_rootfs_cases() {
+        for (root, suffix, path) in [
+            ("", "/lib/modules", "/lib/modules"),
+            ("", "lib/modules", "lib/modules"),
+            ("/", "/lib/modules", "/lib/modules"),
+            ("/", "lib/modules", "/lib/modules"),
+            ("///", "/lib/modules", "/lib/modules"),
+            ("  ", "/lib/modules", "/lib/modules"),
+            ("/mnt", "/lib/modules", "/mnt/lib/modules"),
+            ("/mnt", "lib/modules", "/mnt/lib/modules"),
+            ("/mnt/", "/lib/modules", "/mnt/lib/modules"),
+            ("/mnt//", "//lib/modules", "/mnt/lib/modules"),
+            (" /mnt/ ", "/lib/modules", "/mnt/lib/modules"),
+            ("/mnt/root/lib", "/lib/modules", "/mnt/root/lib/lib/modules"),
+            (
+                "/mnt/sysroot/",
+                "/lib/modules/6.1.0",
+                "/mnt/sysroot/lib/modules/6.1.0",
+            ),
+            ("rel/root", "/lib/modules", "rel/root/lib/modules"),
+        ] {
+            assert_eq!(
+                join_rootfs(root, suffix),
+                PathBuf::from(path),
+                "{:?} + {:?}",
+                root,
+                suffix
+            );
+        }
+    }
+
+    #[test]
+    fn modules_dir_of_rootfs() {
+        for (rootfs, rfs_path, mod_d) in [
+            (None, "", MOD_D),
+            (Some(""), "", MOD_D),
+            (Some("/"), "", MOD_D),
+            (Some("/mnt"), "/mnt", "/mnt/lib/modules"),
+            (Some("/mnt/"), "/mnt", "/mnt/lib/modules"),
+            (Some(" /mnt// "), "/mnt", "/mnt/lib/modules"),
+        ] {
+            assert_eq!(
+                get_modules_dir(rootfs),
+                (rfs_path.to_string(), mod_d.to_string()),
+                "{:?}",
+                rootfs
+            );
+        }
+
+        // KernelInfo and the kernel listing agree on the paths
+        let fx = Fixture::new();
+        let ki = fx.tree("6.1.0", "kernel/a.ko:\n");
+        for root in [
+            fx.root().to_string(),
+            format!("{}/", fx.root()),
+            format!("{}//", fx.root()),
+        ] {
+            let other = KernelInfo::new(&root, "6.1.0").unwrap();
+            assert_eq!(other.get_kernel_path(), ki.get_kernel_path());
+            assert_eq!(other.get_archive_path(), "lib/modules/6.1.0");
+            assert_eq!(
+                list_kernel_versions(Some(&root)).unwrap(),
+                names(&["6.1.0"])
+            );
+        }
+    }
+
+    #[test]
+    fn module_archive_as_write_cpio() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/a.ko: kernel/b.ko\nkernel/b.ko:\nkernel/c.ko:\n",
+        );
+        let mut out: Vec<u8> = vec![];
+        let summary = build_module_archive(
+            Some(fx.root()),
+            "6.1.0",
+            &names(&["a", "nope"]),
+            &fx.path("lib/firmware"),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(summary.module_count, 2);
+        assert_eq!(summary.missing_modules, names(&["nope"]));
+        assert_eq!(summary.bytes, out.len() as u64);
+
+        // Same modules as of write_cpio, in the same order, then modules.dep
+        let mut cpio: Vec<u8> = vec![];
+        ki.write_cpio(&names(&["a"]), &HashSet::default(), &mut cpio)
+            .unwrap();
+        let files = |data: &[u8]| -> Vec<String> {
+            cpio::read_cpio_modules(data)
+                .unwrap()
+                .into_iter()
+                .filter(|x| x.size > 0 || x.is_module)
+                .map(|x| x.path)
+                .collect()
+        };
+        let mut expected = files(&cpio);
+        expected.push(format!("lib/modules/6.1.0/{}", MOD_DEP_F));
+        assert_eq!(files(&out), expected);
+    }
 }
@@ -0,0 +1,102 @@
+/// Parse modules.alias content in a format:
+///     alias <pattern> <module>
+///
+/// Returns a list of (pattern, module) in the order of appearance.
+pub fn parse_aliases(data: &str) -> Vec<(String, String)> {
+    data.lines()
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            match (tokens.next(), tokens.next(), tokens.next()) {
+                (Some("alias"), Some(pattern), Some(module)) => {
+                    Some((pattern.to_string(), module.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Shell-style wildcard matching, as modprobe does for aliases.
+/// Supports `*`, `?` and `[...]` character classes (with ranges and `!`/`^` negation).
+pub fn fnmatch(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() {
+            match p[pi] {
+                b'*' => {
+                    backtrack = Some((pi, ti));
+                    pi += 1;
+                    continue;
+                }
+                b'?' => {
+                    pi += 1;
+                    ti += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, len)) = match_class(&p[pi..], t[ti]) {
+                        if matched {
+                            pi += len;
+                            ti += 1;
+                            continue;
+                        }
+                    } else if t[ti] == b'[' {
+                        // Unterminated class is a literal
+                        pi += 1;
+                        ti += 1;
+                        continue;
+                    }
+                }
+                c if c == t[ti] => {
+                    pi += 1;
+                    ti += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        match backtrack {
+            Some((bpi, bti)) => {
+                pi = bpi + 1;
+                ti = bti + 1;
+                backtrack = Some((bpi, bti + 1));
+            }
+            None => return false,
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+/// Match a character against a `[...]` class at the start of the pattern.
+/// Returns the match result and the length of the class, or `None` if the class is not terminated.
+fn match_class(p: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut idx = 1;
+    let negate = matches!(p.get(idx), Some(b'!') | Some(b'^'));
+    if negate {
+        idx += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while idx < p.len() {
+        if p[idx] == b']' && !first {
+            return Some((matched != negate, idx + 1));
+        }
+
+        if idx + 2 < p.len() && p[idx + 1] == b'-' && p[idx + 2] != b']' {
+            matched |= p[idx] <= c && c <= p[idx + 2];
+            idx += 3;
+        } else {
+            matched |= p[idx] == c;
+            idx += 1;
+        }
+        first = false;
+    }
+
+    None
+}
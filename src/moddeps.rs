@@ -1,52 +1,680 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{read_dir, read_to_string},
+    io::{self, ErrorKind},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::{kerman::KernelInfo, modinfo};
+use crate::{
+    error::Error,
+    kerman::{normalize_module_name, KernelInfo},
+    modinfo::{self, ModInfo},
+};
+
+#[cfg(target_os = "linux")]
+use crate::modload;
+
+/// Release of the running kernel, e.g. "6.1.0-13-amd64"
+pub static PROC_OSRELEASE: &str = "/proc/sys/kernel/osrelease";
 
 pub struct KModuleTree {
     kernel: KernelInfo,
+    snapshot: Option<LiveSnapshot>,
+}
+
+/// Loaded modules of a machine, captured for an offline analysis
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LiveSnapshot {
+    /// Kernel version of the module tree, the snapshot was taken with
+    pub kernel_version: String,
+
+    /// Release of the running kernel, as in /proc/sys/kernel/osrelease
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kernel_release: Option<String>,
+
+    /// Time of the snapshot, seconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// Loaded modules (lsmod)
+    pub modules: Vec<ModInfo>,
+}
+
+/// Memory usage of the currently loaded modules
+#[derive(Debug, Clone, Default)]
+pub struct MemorySummary {
+    /// Total bytes, used by all loaded modules
+    pub total_bytes: u64,
+
+    /// Loaded modules with their sizes, sorted by size descending
+    pub by_module: Vec<(String, u64)>,
+}
+
+impl MemorySummary {
+    /// Get the top N memory consumers
+    pub fn top(&self, n: usize) -> &[(String, u64)] {
+        &self.by_module[..n.min(self.by_module.len())]
+    }
+}
+
+/// Difference between the loaded modules and what their dependencies in modules.dep require
+#[derive(Debug, Clone, Default)]
+pub struct DriftReport {
+    /// Loaded modules, which are not in the module tree, e.g. out-of-tree or manually inserted
+    pub not_in_tree: Vec<String>,
+
+    /// Dependencies of the loaded modules, which are not loaded themselves
+    pub missing_deps: Vec<String>,
+}
+
+impl DriftReport {
+    /// Return true if the loaded modules are consistent with the module tree
+    pub fn is_clean(&self) -> bool {
+        self.not_in_tree.is_empty() && self.missing_deps.is_empty()
+    }
+}
+
+/// Loaded module with its users, as the kernel has it (see `KModuleTree::live_view`)
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LiveModule {
+    /// Normalised module name (see `normalize_module_name`)
+    pub name: String,
+
+    /// Size of the module in memory, bytes
+    pub size: usize,
+
+    /// Number of the references to the module
+    pub refcount: u32,
+
+    /// Loaded modules, which use the module, sorted
+    pub used_by: Vec<String>,
+
+    /// State of the module: "Live", "Loading" or "Unloading"
+    pub state: String,
 }
 
 impl KModuleTree {
+    /// Create a module tree for the kernel
+    pub fn new(kernel: KernelInfo) -> Self {
+        KModuleTree {
+            kernel,
+            snapshot: None,
+        }
+    }
+
+    /// Create a module tree for the kernel, which works with the loaded modules
+    /// of a snapshot instead of the running kernel.
+    pub fn from_snapshot(kernel: KernelInfo, snapshot: LiveSnapshot) -> Self {
+        KModuleTree {
+            kernel,
+            snapshot: Some(snapshot),
+        }
+    }
+
+    /// Capture currently loaded modules for an offline analysis (see `from_snapshot`).
+    /// A tree, created from a snapshot, returns that snapshot.
+    pub fn snapshot(&self) -> Result<LiveSnapshot, Error> {
+        if let Some(snapshot) = &self.snapshot {
+            return Ok(snapshot.clone());
+        }
+
+        Ok(LiveSnapshot {
+            kernel_version: self.kernel.version.to_owned(),
+            kernel_release: self.running_release().ok(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            modules: self.lsmod()?,
+        })
+    }
+
+    /// Get loaded modules from the snapshot, if any, otherwise from the running kernel.
+    /// This is an internal method
+    fn lsmod(&self) -> Result<Vec<ModInfo>, Error> {
+        match &self.snapshot {
+            Some(snapshot) => Ok(snapshot.modules.clone()),
+            None => modinfo::lsmod(),
+        }
+    }
+
+    /// Get release of the running kernel, or of the one the snapshot was taken on.
+    /// This is an internal method
+    fn running_release(&self) -> Result<String, Error> {
+        if let Some(snapshot) = &self.snapshot {
+            return snapshot.kernel_release.to_owned().ok_or_else(|| {
+                io::Error::new(ErrorKind::NotFound, "Snapshot has no kernel release").into()
+            });
+        }
+
+        Ok(read_to_string(PROC_OSRELEASE)?.trim().to_string())
+    }
+
+    /// Check the loaded modules against their files on the disk: the files should be
+    /// built for the running kernel, otherwise the package was updated without a reboot.
+    ///
+    /// Returns (module, vermagic of its file, running kernel release) for each module,
+    /// whose file has vermagic of another kernel release. Loaded modules without files
+    /// or without vermagic are skipped.
+    pub fn check_loaded_vermagic(&self) -> Result<Vec<(String, String, String)>, Error> {
+        let release = self.running_release()?;
+        let mut mismatches: Vec<(String, String, String)> = vec![];
+        for modname in self.get_loaded_modules()? {
+            let vermagic = match self.kernel.get_details(&modname) {
+                Ok(details) => details.get("vermagic").map(|x| x.trim().to_string()),
+                Err(Error::ModuleNotFound(_)) => None,
+                Err(err) => return Err(err),
+            };
+
+            if let Some(vermagic) = vermagic {
+                if vermagic.split_whitespace().next() != Some(release.as_str()) {
+                    mismatches.push((modname, vermagic, release.to_owned()));
+                }
+            }
+        }
+        mismatches.sort();
+
+        Ok(mismatches)
+    }
+
     /// lsmod
-    pub fn get_loaded_modules(&self) -> Vec<String> {
-        modinfo::lsmod()
+    pub fn get_loaded_modules(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .lsmod()?
             .iter()
             .map(|modinfo| modinfo.name.to_owned())
-            .collect()
+            .collect())
     }
 
     /// Snapshot currently active modules (lsmod)
     #[allow(dead_code)]
-    pub fn get_loaded_deps(&self) -> HashMap<String, Vec<String>> {
-        self.get_specified_deps(&self.get_loaded_modules())
+    pub fn get_loaded_deps(&self) -> Result<HashMap<String, Vec<String>>, Error> {
+        self.get_specified_deps(&self.get_loaded_modules()?)
+    }
+
+    /// Get all dependencies for the specified modules.
+    /// Module names (not paths) are normalised, so "i2c-core" and "i2c_core" are the same.
+    pub fn get_specified_deps(
+        &self,
+        modules: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, Error> {
+        if modules.is_empty() {
+            return self.get_specified_deps(&self.get_loaded_modules()?);
+        }
+
+        Ok(self.kernel.get_deps_for(&normalize_names(modules)))
     }
 
-    /// Get all dependencies for the specified modules
-    pub fn get_specified_deps(&self, modules: &[String]) -> HashMap<String, Vec<String>> {
-        match modules.is_empty() {
-            true => return self.kernel.get_deps_for(&self.get_loaded_modules()),
-            false => (),
+    /// Same as `get_specified_deps`, but also returns the names, which could not be
+    /// resolved to a module on the disk (after normalisation), instead of dropping them.
+    /// Unlike `get_specified_deps`, no names means no modules.
+    pub fn get_specified_deps_checked(
+        &self,
+        modules: &[String],
+    ) -> (HashMap<String, Vec<String>>, Vec<String>) {
+        let mut mod_tree: HashMap<String, Vec<String>> = HashMap::default();
+        let mut unresolved: Vec<String> = vec![];
+        for name in normalize_names(modules) {
+            let deps = self.kernel.get_deps_for(std::slice::from_ref(&name));
+            if deps.is_empty() {
+                unresolved.push(name);
+            } else {
+                mod_tree.extend(deps);
+            }
         }
 
-        self.kernel.get_deps_for(modules)
+        (mod_tree, unresolved)
     }
 
     /// Same as a snapshot `get_loaded()` except it is merges
     /// all the dependencies into one list for an actual operations.
     #[allow(dead_code)]
-    pub fn merge_loaded_deps(&self) -> HashSet<String> {
-        self.merge_specified_deps(&self.get_loaded_modules())
+    pub fn merge_loaded_deps(&self) -> Result<HashSet<String>, Error> {
+        self.merge_specified_deps(&self.get_loaded_modules()?)
+    }
+
+    /// Same as `merge_loaded_deps`, but all the loaded modules are resolved at once,
+    /// so their shared dependencies are walked only once (see `KernelInfo::get_deps_closure`).
+    pub fn full_loaded_closure(&self) -> Result<HashSet<String>, Error> {
+        Ok(self
+            .kernel
+            .get_deps_closure(&normalize_names(&self.get_loaded_modules()?)))
     }
 
     /// Same as `get_specified` method, except it merges
     /// all the dependencies into one list for an actual operations.
-    pub fn merge_specified_deps(&self, modules: &[String]) -> HashSet<String> {
+    pub fn merge_specified_deps(&self, modules: &[String]) -> Result<HashSet<String>, Error> {
         let mut deps = HashSet::default();
-        for (module, data) in self.get_specified_deps(modules) {
+        for (module, data) in self.get_specified_deps(modules)? {
             deps.extend(data);
             deps.insert(module);
         }
 
-        deps
+        Ok(deps)
+    }
+
+    /// Summarise memory usage of the currently loaded modules (lsmod).
+    pub fn live_memory_summary(&self) -> Result<MemorySummary, Error> {
+        let mut summary = MemorySummary {
+            by_module: self
+                .lsmod()?
+                .into_iter()
+                .map(|modinfo| (modinfo.name, modinfo.mem_size as u64))
+                .collect(),
+            ..Default::default()
+        };
+        summary.total_bytes = summary.by_module.iter().map(|(_, size)| size).sum();
+        summary
+            .by_module
+            .sort_by(|(an, asz), (bn, bsz)| bsz.cmp(asz).then(an.cmp(bn)));
+
+        Ok(summary)
+    }
+
+    /// Return true if a module is currently loaded (lsmod).
+    /// Names are normalised, so "i2c-core" matches "i2c_core".
+    pub fn is_loaded(&self, name: &str) -> Result<bool, Error> {
+        let name = normalize_module_name(name);
+        Ok(self
+            .get_loaded_modules()?
+            .iter()
+            .any(|x| normalize_module_name(x) == name))
+    }
+
+    /// Get loaded modules, which use the module (lsmod "Used by").
+    /// Names are normalised, so "i2c-core" matches "i2c_core".
+    pub fn get_live_users(&self, name: &str) -> Result<Vec<String>, Error> {
+        let name = normalize_module_name(name);
+        Ok(self
+            .lsmod()?
+            .into_iter()
+            .find(|x| normalize_module_name(&x.name) == name)
+            .map(|x| x.dependencies)
+            .unwrap_or_default())
+    }
+
+    /// Get loaded modules, which use the module, sorted, as sysfs has them
+    /// in "/sys/module/<name>/holders". Unlike `get_live_users`, these are always complete.
+    /// The sysfs root is the kernel one (see `KernelInfoBuilder::sys_root`).
+    ///
+    /// Fails with `ModuleNotFound` if sysfs has no such module.
+    /// A builtin module has no holders.
+    pub fn live_holders(&self, name: &str) -> Result<Vec<String>, Error> {
+        let mod_d = self.sysfs_module_dir(name)?;
+        let mut holders: Vec<String> = vec![];
+        if mod_d.join("holders").is_dir() {
+            for fres in read_dir(mod_d.join("holders"))? {
+                holders.push(fres?.file_name().to_string_lossy().to_string());
+            }
+        }
+        holders.sort();
+
+        Ok(holders)
+    }
+
+    /// Get current values of the module parameters, as sysfs has them
+    /// in "/sys/module/<name>/parameters". Parameters, which cannot be read,
+    /// e.g. write-only ones, are skipped. The sysfs root is as for `live_holders`.
+    ///
+    /// Fails with `ModuleNotFound` if sysfs has no such module.
+    pub fn live_parameters(&self, name: &str) -> Result<HashMap<String, String>, Error> {
+        let param_d = self.sysfs_module_dir(name)?.join("parameters");
+        let mut params: HashMap<String, String> = HashMap::default();
+        if !param_d.is_dir() {
+            return Ok(params);
+        }
+
+        for fres in read_dir(param_d)? {
+            let fd = fres?;
+            match read_to_string(fd.path()) {
+                Ok(value) => {
+                    params.insert(
+                        fd.file_name().to_string_lossy().to_string(),
+                        value.trim_end_matches('\n').to_string(),
+                    );
+                }
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(params)
+    }
+
+    /// Get loaded modules with their sizes, references, users and states, sorted by name.
+    ///
+    /// Users are taken from sysfs (see `live_holders`), as /proc/modules may not have
+    /// all of them. For a snapshot, or if sysfs has no such module, they are from /proc/modules,
+    /// as sysfs is of the running kernel.
+    pub fn live_view(&self) -> Result<Vec<LiveModule>, Error> {
+        let mut mods: Vec<LiveModule> = vec![];
+        for modinfo in self.lsmod()? {
+            let used_by = match self.snapshot {
+                Some(_) => modinfo.dependencies,
+                None => match self.live_holders(&modinfo.name) {
+                    Ok(holders) => holders,
+                    Err(Error::ModuleNotFound(_)) => modinfo.dependencies,
+                    Err(err) => return Err(err),
+                },
+            };
+            let mut used_by: Vec<String> =
+                used_by.iter().map(|x| normalize_module_name(x)).collect();
+            used_by.sort();
+            used_by.dedup();
+
+            mods.push(LiveModule {
+                name: normalize_module_name(&modinfo.name),
+                size: modinfo.mem_size,
+                refcount: modinfo.instances,
+                used_by,
+                state: modinfo.state,
+            });
+        }
+        mods.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(mods)
+    }
+
+    /// Get sysfs directory of a module. Names are normalised, as the kernel names them.
+    /// This is an internal method
+    fn sysfs_module_dir(&self, name: &str) -> Result<PathBuf, Error> {
+        let mod_d = self
+            .kernel
+            .get_sys_root()
+            .join("module")
+            .join(normalize_module_name(name));
+        if !mod_d.is_dir() {
+            return Err(Error::ModuleNotFound(name.to_string()));
+        }
+
+        Ok(mod_d)
+    }
+
+    /// Get loaded modules, which are not from the kernel tree, sorted.
+    ///
+    /// A module is out-of-tree, if the kernel reports it so in its taint flags ("O"),
+    /// or, if there are no taint flags, its module file has no modinfo "intree" field.
+    /// Modules are returned by their paths, relative to the kernel modules directory,
+    /// if their files are found, otherwise by their names.
+    pub fn out_of_tree_loaded(&self) -> Result<Vec<String>, Error> {
+        let mut mods: Vec<String> = vec![];
+        for modinfo in self.lsmod()? {
+            let details = match self.kernel.get_details(&modinfo.name) {
+                Ok(details) => Some(details),
+                Err(Error::ModuleNotFound(_)) => None,
+                Err(err) => return Err(err),
+            };
+
+            let out_of_tree = match (&modinfo.taint, &details) {
+                (Some(taint), _) => taint.contains('O'),
+                (None, Some(details)) => details.get("intree") != Some("Y"),
+                (None, None) => false,
+            };
+            if out_of_tree {
+                mods.push(details.map(|x| x.name).unwrap_or(modinfo.name));
+            }
+        }
+        mods.sort();
+
+        Ok(mods)
+    }
+
+    /// Compare the loaded modules with their dependencies in modules.dep.
+    /// A drift usually means manually inserted modules or tampering.
+    ///
+    /// Loaded modules, which are not in the tree, are reported by their names, sorted.
+    /// Missing dependencies are reported by their paths, in a load order.
+    pub fn drift_report(&self) -> Result<DriftReport, Error> {
+        let loaded = self.get_loaded_modules()?;
+        let mut report = DriftReport {
+            not_in_tree: loaded
+                .iter()
+                .filter(|x| self.kernel.resolve_candidates(x).is_empty())
+                .map(|x| x.to_owned())
+                .collect(),
+            missing_deps: self.not_loaded_deps(&loaded)?,
+        };
+        report.not_in_tree.sort();
+
+        Ok(report)
+    }
+
+    /// Resolve all dependencies for the specified modules and return those,
+    /// which are not currently loaded, in a load order.
+    pub fn not_loaded_deps(&self, names: &[String]) -> Result<Vec<String>, Error> {
+        let loaded: HashSet<String> = self
+            .get_loaded_modules()?
+            .iter()
+            .map(|x| normalize_module_name(x))
+            .collect();
+
+        Ok(self
+            .kernel
+            .get_load_order(names)
+            .into_iter()
+            .filter(|x| !loaded.contains(&normalize_module_name(x)))
+            .collect())
+    }
+
+    /// Load the modules with all their dependencies into the running kernel,
+    /// in a load order. Modules, which are already loaded, are skipped.
+    ///
+    /// Returns modules, which were actually loaded.
+    #[cfg(target_os = "linux")]
+    pub fn load_modules(&self, names: &[String]) -> Result<Vec<String>, Error> {
+        if self.snapshot.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Modules of a snapshot cannot be changed",
+            )
+            .into());
+        }
+
+        let mut loaded: Vec<String> = vec![];
+        for mname in self.not_loaded_deps(names)? {
+            match modload::insert_module(&self.kernel.get_kernel_path().join(&mname), "") {
+                Ok(_) => loaded.push(mname),
+                // Loaded meanwhile by someone else
+                Err(Error::Io(err)) if err.raw_os_error() == Some(libc::EEXIST) => {}
+                Err(Error::Io(err)) => {
+                    return Err(io::Error::new(err.kind(), format!("{}: {}", mname, err)).into())
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    /// Unload the modules from the running kernel, together with their dependencies,
+    /// in an unload order. Modules, which are not loaded, are skipped, as well as
+    /// the dependencies, which are still used by other modules.
+    ///
+    /// Returns modules, which were actually unloaded.
+    #[cfg(target_os = "linux")]
+    pub fn unload_modules(&self, names: &[String]) -> Result<Vec<String>, Error> {
+        if self.snapshot.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Modules of a snapshot cannot be changed",
+            )
+            .into());
+        }
+
+        let requested: HashSet<String> = names.iter().map(|x| normalize_module_name(x)).collect();
+        let mut unloaded: Vec<String> = vec![];
+        for mname in self.kernel.get_unload_order(names) {
+            if !self.is_loaded(&mname)? {
+                continue;
+            }
+
+            match modload::remove_module(&mname) {
+                Ok(_) => unloaded.push(mname),
+                Err(Error::Io(err))
+                    if err.kind() == ErrorKind::WouldBlock
+                        && !requested.contains(&normalize_module_name(&mname)) => {}
+                Err(Error::Io(err)) => {
+                    return Err(io::Error::new(err.kind(), format!("{}: {}", mname, err)).into())
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(unloaded)
+    }
+}
+
+/// Normalise module names (not paths), so "i2c-core" and "i2c_core" are the same.
+/// This is an internal method
+fn normalize_names(modules: &[String]) -> Vec<String> {
+    modules
+        .iter()
+        .map(|x| {
+            if x.contains('/') {
+                x.to_owned()
+            } else {
+                normalize_module_name(x)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kerman::MOD_BUILTIN_F, modinfo::parse_proc_modules, testutil::*};
+
+    /// Module tree with the loaded modules of a snapshot
+    fn snapshot_tree(ki: KernelInfo, proc_modules: &str) -> KModuleTree {
+        KModuleTree::from_snapshot(
+            ki,
+            LiveSnapshot {
+                kernel_version: "6.1.0".to_string(),
+                modules: parse_proc_modules(proc_modules).unwrap(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn loaded_names_normalised() {
+        let fx = Fixture::new();
+        // Dependency, which is compiled in since modules.dep was written
+        fx.kfile("6.1.0", MOD_BUILTIN_F, "kernel/sound/snd-pcm.ko\n");
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/drivers/i2c/i2c-dev.ko: kernel/drivers/i2c/i2c-core.ko\n\
+             kernel/drivers/i2c/i2c-core.ko:\n\
+             kernel/sound/snd-hda_intel.ko: kernel/sound/snd-pcm.ko\n",
+        );
+        let tree = snapshot_tree(
+            ki,
+            "i2c_dev 16384 0 - Live 0x0\n\
+             i2c_core 65536 1 i2c_dev, Live 0x0\n\
+             snd_hda_intel 61440 0 - Live 0x0\n",
+        );
+
+        let deps = tree.get_loaded_deps().unwrap();
+        let mut modnames: Vec<&String> = deps.keys().collect();
+        modnames.sort();
+        assert_eq!(
+            modnames,
+            vec![
+                "kernel/drivers/i2c/i2c-core.ko",
+                "kernel/drivers/i2c/i2c-dev.ko",
+                "kernel/sound/snd-hda_intel.ko"
+            ]
+        );
+        assert_eq!(
+            deps["kernel/drivers/i2c/i2c-dev.ko"],
+            names(&["kernel/drivers/i2c/i2c-core.ko"])
+        );
+        assert!(deps["kernel/sound/snd-hda_intel.ko"].is_empty());
+
+        let (deps, unresolved) =
+            tree.get_specified_deps_checked(&names(&["i2c-core", "snd-hda-intel", "nope_mod"]));
+        assert_eq!(deps.len(), 2);
+        assert_eq!(unresolved, names(&["nope_mod"]));
+        let closure = tree.full_loaded_closure().unwrap();
+        assert_eq!(closure, tree.merge_loaded_deps().unwrap());
+        assert_eq!(closure.len(), 3);
+        assert!(!closure.contains("kernel/sound/snd-pcm.ko"));
+    }
+
+    #[test]
+    fn live_view_of_snapshot() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/drivers/i2c/i2c-dev.ko: kernel/drivers/i2c/i2c-core.ko\n\
+             kernel/drivers/i2c/i2c-core.ko:\n",
+        );
+        // Holders of the running kernel are not of the snapshot
+        fx.mkdir("sys/module/i2c_core/holders/other");
+        let tree = snapshot_tree(
+            ki,
+            "i2c_dev 16384 0 - Live 0x0\n\
+             i2c_core 65536 2 i2c-dev,i2c_smbus, Unloading 0x0\n",
+        );
+
+        let view = tree.live_view().unwrap();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view[0].name, "i2c_core");
+        assert_eq!(view[0].size, 65536);
+        assert_eq!(view[0].refcount, 2);
+        assert_eq!(view[0].used_by, names(&["i2c_dev", "i2c_smbus"]));
+        assert_eq!(view[1].name, "i2c_dev");
+        assert!(view[1].used_by.is_empty());
+        assert_eq!(
+            (view[0].state.as_str(), view[1].state.as_str()),
+            ("Unloading", "Live")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_json_round_trip() {
+        let fx = Fixture::new();
+        let ki = fx.tree(
+            "6.1.0",
+            "kernel/drivers/i2c/i2c-dev.ko: kernel/drivers/i2c/i2c-core.ko\n\
+             kernel/drivers/i2c/i2c-core.ko:\n",
+        );
+        let snapshot = LiveSnapshot {
+            kernel_version: "6.1.0".to_string(),
+            kernel_release: Some("6.1.0-13-amd64".to_string()),
+            timestamp: 1700000000,
+            modules: parse_proc_modules(
+                "i2c_dev 16384 0 - Live 0x0 (OE)\n\
+                 i2c_core 65536 1 i2c_dev, Unloading 0xffffffffc0000000\n",
+            )
+            .unwrap(),
+        };
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored: LiveSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{:?}", restored), format!("{:?}", snapshot));
+        assert_eq!(restored.modules[0].taint.as_deref(), Some("OE"));
+        assert_eq!(restored.modules[1].state, "Unloading");
+
+        let (tree, restored) = (
+            KModuleTree::from_snapshot(ki.clone(), snapshot),
+            KModuleTree::from_snapshot(ki, restored),
+        );
+        assert_eq!(
+            restored.get_live_users("i2c-core").unwrap(),
+            tree.get_live_users("i2c-core").unwrap()
+        );
+        assert_eq!(
+            restored.get_live_users("i2c_core").unwrap(),
+            names(&["i2c_dev"])
+        );
+        assert_eq!(
+            format!("{:?}", restored.live_view().unwrap()),
+            format!("{:?}", tree.live_view().unwrap())
+        );
+        assert_eq!(restored.running_release().unwrap(), "6.1.0-13-amd64");
     }
 }
@@ -0,0 +1,590 @@
+use crate::error::Error;
+use std::{
+    collections::HashMap,
+    fs::read,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+/// Compression of a module file on the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModCompression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl ModCompression {
+    /// Detect module compression by its file name: .ko[.compression]
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("gz") => ModCompression::Gzip,
+            Some("zst") => ModCompression::Zstd,
+            Some("xz") => ModCompression::Xz,
+            _ => ModCompression::None,
+        }
+    }
+
+    /// Detect module compression by the leading magic bytes of its content.
+    /// Returns `None` if the content is neither compressed nor an ELF file.
+    pub fn from_magic(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x7fELF") {
+            Some(ModCompression::None)
+        } else if data.starts_with(b"\x1f\x8b") {
+            Some(ModCompression::Gzip)
+        } else if data.starts_with(b"\x28\xb5\x2f\xfd") {
+            Some(ModCompression::Zstd)
+        } else if data.starts_with(b"\xfd7zXZ") {
+            Some(ModCompression::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Static module information, as found in the `.modinfo` section of a module file.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModDetails {
+    /// Module path, relative to the kernel modules directory
+    pub name: String,
+
+    /// Key/value pairs in the order of appearance. Keys may repeat, e.g. "alias" or "firmware".
+    pub fields: Vec<(String, String)>,
+}
+
+impl ModDetails {
+    /// Get first value of a field
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get all values of a repeated field
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// Get parameters of the module in the order of appearance, from the "parm"
+    /// ("name:description") and "parmtype" ("name:type") fields.
+    pub fn parameters(&self) -> Vec<ModParam> {
+        let mut params: Vec<ModParam> = vec![];
+        for (key, value) in &self.fields {
+            if key != "parm" && key != "parmtype" {
+                continue;
+            }
+
+            let (name, value) = value.split_once(':').unwrap_or((value, ""));
+            let idx = match params.iter().position(|x| x.name == name) {
+                Some(idx) => idx,
+                None => {
+                    params.push(ModParam {
+                        name: name.to_string(),
+                        ..Default::default()
+                    });
+                    params.len() - 1
+                }
+            };
+
+            if key == "parm" {
+                params[idx].description = value.to_string();
+            } else {
+                params[idx].param_type = value.to_string();
+            }
+        }
+
+        params
+    }
+}
+
+/// Module parameter, as the modinfo "parm" and "parmtype" fields describe it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModParam {
+    /// Parameter name, e.g. "debug"
+    pub name: String,
+
+    /// Parameter type, e.g. "int", "charp" or "bool". Empty if the module does not tell.
+    pub param_type: String,
+
+    /// Description of the parameter. Empty if the module does not tell.
+    pub description: String,
+}
+
+/// Module details by full paths of the module files, to look each of them up only once
+/// across the calls, e.g. of `KernelInfo::get_details_cached`. Paths are absolute,
+/// so one cache serves several kernels. A cache, kept between the runs, is not
+/// invalidated when the modules change.
+pub type ModinfoCache = HashMap<String, ModDetails>;
+
+/// Read a module file and decompress it, if needed.
+///
+/// Compression is detected by the content, as a file name may lie, e.g. a compressed ".ko".
+/// The file name is used only if the content is not recognised.
+pub fn read_module(path: &Path) -> Result<Vec<u8>, Error> {
+    let data = read(path)?;
+    let compression =
+        ModCompression::from_magic(&data).unwrap_or_else(|| ModCompression::from_path(path));
+    Ok(decompress(data, compression)?)
+}
+
+#[cfg(feature = "compression")]
+fn decompress(data: Vec<u8>, compression: ModCompression) -> Result<Vec<u8>, io::Error> {
+    use std::io::Read;
+
+    let mut out: Vec<u8> = vec![];
+    match compression {
+        ModCompression::None => return Ok(data),
+        ModCompression::Gzip => {
+            flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut out)?;
+        }
+        ModCompression::Zstd => {
+            ruzstd::decoding::StreamingDecoder::new(data.as_slice())
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?
+                .read_to_end(&mut out)?;
+        }
+        ModCompression::Xz => {
+            lzma_rs::xz_decompress(&mut data.as_slice(), &mut out)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(data: Vec<u8>, compression: ModCompression) -> Result<Vec<u8>, io::Error> {
+    match compression {
+        ModCompression::None => Ok(data),
+        _ => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "{:?} compressed modules require \"compression\" feature",
+                compression
+            ),
+        )),
+    }
+}
+
+/// Minimal ELF reader, just enough to get to the sections of a module.
+/// Both ELF classes (32/64-bit) and data encodings (little/big endian) are supported,
+/// so modules of a foreign architecture can be analysed on any host.
+struct Elf<'a> {
+    data: &'a [u8],
+    class64: bool,
+    be: bool,
+}
+
+/// ELF section header and its content
+struct ElfSection<'a> {
+    name: &'a [u8],
+    data: &'a [u8],
+    kind: u32,
+    flags: u64,
+    size: u64,
+    link: usize,
+}
+
+impl<'a> Elf<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, io::Error> {
+        if data.len() < 0x34 || &data[..4] != b"\x7fELF" {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not an ELF file"));
+        }
+
+        let class64 = match data[4] {
+            1 => false,
+            2 => true,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "Unknown ELF class")),
+        };
+        let be = match data[5] {
+            1 => false,
+            2 => true,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Unknown ELF data encoding",
+                ))
+            }
+        };
+
+        Ok(Elf { data, class64, be })
+    }
+
+    fn bytes(&self, off: usize, len: usize) -> Result<&'a [u8], io::Error> {
+        off.checked_add(len)
+            .and_then(|end| self.data.get(off..end))
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Truncated ELF file"))
+    }
+
+    /// Get an offset of a field, rejecting the ones past the address space,
+    /// as the header values come from the file and cannot be trusted
+    fn offset(base: usize, off: usize) -> Result<usize, io::Error> {
+        base.checked_add(off)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "ELF offset overflow"))
+    }
+
+    /// Read an unsigned integer of 2, 4 or 8 bytes in the file data encoding
+    fn uint(&self, off: usize, len: usize) -> Result<u64, io::Error> {
+        let mut buf = [0u8; 8];
+        let data = self.bytes(off, len)?;
+        if self.be {
+            buf[8 - len..].copy_from_slice(data);
+            Ok(u64::from_be_bytes(buf))
+        } else {
+            buf[..len].copy_from_slice(data);
+            Ok(u64::from_le_bytes(buf))
+        }
+    }
+
+    /// Read an address-sized field: 4 bytes for 32-bit or 8 bytes for 64-bit ELF
+    fn addr(&self, off: usize) -> Result<usize, io::Error> {
+        Ok(self.uint(off, if self.class64 { 8 } else { 4 })? as usize)
+    }
+
+    /// Get e_machine field
+    fn machine(&self) -> Result<u16, io::Error> {
+        Ok(self.uint(0x12, 2)? as u16)
+    }
+
+    /// Get all sections
+    fn sections(&self) -> Result<Vec<ElfSection<'a>>, io::Error> {
+        let (shoff, hdr_off) = if self.class64 {
+            (self.addr(0x28)?, 0x3a)
+        } else {
+            (self.addr(0x20)?, 0x2e)
+        };
+        let shentsize = self.uint(hdr_off, 2)? as usize;
+        let shnum = self.uint(hdr_off + 2, 2)? as usize;
+        let shstrndx = self.uint(hdr_off + 4, 2)? as usize;
+
+        // (sh_name, section without its name)
+        let sh = |idx: usize| -> Result<(usize, ElfSection<'a>), io::Error> {
+            let hdr = Self::offset(
+                shoff,
+                idx.checked_mul(shentsize)
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "ELF offset overflow"))?,
+            )?;
+            let at = |off: usize| Self::offset(hdr, off);
+            let (offset, size, link) = if self.class64 {
+                (self.addr(at(0x18)?)?, self.addr(at(0x20)?)?, at(0x28)?)
+            } else {
+                (self.addr(at(0x10)?)?, self.addr(at(0x14)?)?, at(0x18)?)
+            };
+
+            // SHT_NOBITS (e.g. .bss) occupies no space in the file
+            let kind = self.uint(at(4)?, 4)? as u32;
+            let data = if kind == 8 {
+                &[]
+            } else {
+                self.bytes(offset, size)?
+            };
+
+            Ok((
+                self.uint(hdr, 4)? as usize,
+                ElfSection {
+                    name: &[],
+                    data,
+                    kind,
+                    flags: self.addr(at(8)?)? as u64,
+                    size: size as u64,
+                    link: self.uint(link, 4)? as usize,
+                },
+            ))
+        };
+
+        let (_, strtab) = sh(shstrndx)?;
+        let mut sections: Vec<ElfSection<'a>> = vec![];
+        for idx in 0..shnum {
+            let (sh_name, mut section) = sh(idx)?;
+            section.name = c_str(strtab.data, sh_name);
+            sections.push(section);
+        }
+
+        Ok(sections)
+    }
+
+    /// Get content of a section by its name
+    fn section(&self, name: &str) -> Result<Option<&'a [u8]>, io::Error> {
+        Ok(self
+            .sections()?
+            .into_iter()
+            .find(|x| x.name == name.as_bytes())
+            .map(|x| x.data))
+    }
+
+    /// Get entries of the symbol table (.symtab) as (name, section index).
+    /// No symbol table means no entries.
+    fn symbols(&self, sections: &[ElfSection<'a>]) -> Result<Vec<(&'a [u8], usize)>, io::Error> {
+        // SHT_SYMTAB with its string table
+        let symtab = match sections.iter().find(|x| x.kind == 2) {
+            Some(symtab) => symtab,
+            None => return Ok(vec![]),
+        };
+        let strtab = sections
+            .get(symtab.link)
+            .map(|x| x.data)
+            .unwrap_or_default();
+
+        // Symbol entries are read in the file data encoding: st_name first, then st_shndx
+        let (symsz, shndx_off) = if self.class64 { (24, 6) } else { (16, 14) };
+        let syms = Elf {
+            data: symtab.data,
+            ..*self
+        };
+        let mut symbols: Vec<(&'a [u8], usize)> = vec![];
+        for off in (0..syms.data.len() / symsz).map(|x| x * symsz) {
+            symbols.push((
+                c_str(strtab, syms.uint(off, 4)? as usize),
+                syms.uint(off + shndx_off, 2)? as usize,
+            ));
+        }
+
+        Ok(symbols)
+    }
+}
+
+/// Get a NUL terminated string at an offset of a string table.
+/// An offset out of the table is an empty string.
+fn c_str(strtab: &[u8], off: usize) -> &[u8] {
+    strtab
+        .get(off..)
+        .and_then(|x| x.split(|b| *b == 0).next())
+        .unwrap_or_default()
+}
+
+/// Get human-readable architecture name of a module image, e.g. "x86_64" or "aarch64",
+/// as the kernel (`uname -m`) names it.
+pub fn read_arch(data: &[u8]) -> Result<String, Error> {
+    let elf = Elf::new(data)?;
+    let machine = elf.machine()?;
+    Ok(match (machine, elf.class64) {
+        (2, false) => "sparc",
+        (2 | 43, true) => "sparc64",
+        (3, _) => "i386",
+        (4, _) => "m68k",
+        (8, false) => "mips",
+        (8, true) => "mips64",
+        (15, _) => "parisc",
+        (20, _) => "ppc",
+        (21, _) if !elf.be => "ppc64le",
+        (21, _) => "ppc64",
+        (22, false) => "s390",
+        (22, true) => "s390x",
+        (40, _) => "arm",
+        (42, _) => "sh",
+        (50, _) => "ia64",
+        (62, _) => "x86_64",
+        (92, _) => "openrisc",
+        (94, _) => "xtensa",
+        (183, _) => "aarch64",
+        (189, _) => "microblaze",
+        (243, false) => "riscv32",
+        (243, true) => "riscv64",
+        (252, _) => "csky",
+        (258, _) => "loongarch64",
+        (0x9026, _) => "alpha",
+        _ => return Ok(format!("unknown({})", machine)),
+    }
+    .to_string())
+}
+
+/// Get sizes of the sections of a decompressed module image, which are loaded into the memory
+/// (SHF_ALLOC), in the order of the section headers. Sections with no size are omitted.
+pub fn read_alloc_sections(data: &[u8]) -> Result<Vec<(String, u64)>, Error> {
+    Ok(Elf::new(data)?
+        .sections()?
+        .into_iter()
+        .filter(|x| x.flags & 0x2 != 0 && x.size > 0)
+        .map(|x| (String::from_utf8_lossy(x.name).to_string(), x.size))
+        .collect())
+}
+
+/// Get symbols, exported by a decompressed module image (EXPORT_SYMBOL and EXPORT_SYMBOL_GPL),
+/// as (symbol, GPL only), sorted by the symbol. These are the "__ksymtab_<symbol>" entries
+/// of the symbol table (.symtab), so a module with no symbol table has none.
+pub fn read_exported_symbols(data: &[u8]) -> Result<Vec<(String, bool)>, Error> {
+    let elf = Elf::new(data)?;
+    let sections = elf.sections()?;
+
+    let mut symbols: Vec<(String, bool)> = vec![];
+    for (name, shndx) in elf.symbols(&sections)? {
+        let symbol = match name.strip_prefix(b"__ksymtab_") {
+            Some(symbol) => symbol,
+            None => continue,
+        };
+
+        match sections.get(shndx).map(|x| x.name) {
+            Some(b"__ksymtab") => {
+                symbols.push((String::from_utf8_lossy(symbol).to_string(), false))
+            }
+            Some(b"__ksymtab_gpl") => {
+                symbols.push((String::from_utf8_lossy(symbol).to_string(), true))
+            }
+            _ => {}
+        }
+    }
+    symbols.sort();
+    symbols.dedup();
+
+    Ok(symbols)
+}
+
+/// Get symbols, which a decompressed module image uses, but does not define (SHN_UNDEF),
+/// sorted. These are resolved against the exports of the kernel and the other modules
+/// on loading. A module with no symbol table has none.
+pub fn read_undefined_symbols(data: &[u8]) -> Result<Vec<String>, Error> {
+    let elf = Elf::new(data)?;
+    let mut symbols: Vec<String> = elf
+        .symbols(&elf.sections()?)?
+        .into_iter()
+        .filter(|(name, shndx)| *shndx == 0 && !name.is_empty())
+        .map(|(name, _)| String::from_utf8_lossy(name).to_string())
+        .collect();
+    symbols.sort();
+    symbols.dedup();
+
+    Ok(symbols)
+}
+
+/// Parse NUL-separated `key=value` entries of a `.modinfo` section.
+pub fn parse_modinfo(data: &[u8]) -> Vec<(String, String)> {
+    data.split(|b| *b == 0)
+        .filter_map(|entry| {
+            String::from_utf8_lossy(entry)
+                .split_once('=')
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+        })
+        .collect()
+}
+
+/// Get `.modinfo` key/value pairs from a decompressed module image.
+pub fn read_modinfo(data: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    Ok(parse_modinfo(
+        Elf::new(data)?.section(".modinfo")?.unwrap_or_default(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{compress, ElfModule, Fixture};
+
+    #[test]
+    fn arch_by_class_and_encoding() {
+        for (class64, be, machine, arch) in [
+            (true, false, 62, "x86_64"),
+            (true, false, 183, "aarch64"),
+            (false, false, 3, "i386"),
+            (false, false, 40, "arm"),
+            (true, true, 21, "ppc64"),
+            (true, false, 21, "ppc64le"),
+            (true, true, 22, "s390x"),
+            (false, true, 8, "mips"),
+            (true, false, 243, "riscv64"),
+            (true, false, 0x1234, "unknown(4660)"),
+        ] {
+            let data = ElfModule::new()
+                .class64(class64)
+                .be(be)
+                .machine(machine)
+                .build();
+            assert_eq!(read_arch(&data).unwrap(), arch);
+        }
+    }
+
+    #[test]
+    fn modinfo_of_foreign_module() {
+        for (class64, be) in [(true, false), (true, true), (false, false), (false, true)] {
+            let data = ElfModule::new()
+                .class64(class64)
+                .be(be)
+                .modinfo(&["license=GPL", "alias=pci:v*d*", "alias=usb:v*p*"])
+                .build();
+            assert_eq!(
+                read_modinfo(&data).unwrap(),
+                vec![
+                    ("license".to_string(), "GPL".to_string()),
+                    ("alias".to_string(), "pci:v*d*".to_string()),
+                    ("alias".to_string(), "usb:v*p*".to_string()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_headers() {
+        assert!(read_modinfo(b"not an ELF file at all, but long enough for the header").is_err());
+
+        let data = ElfModule::new().modinfo(&["license=GPL"]).build();
+        for len in [0x10, 0x40, data.len() - 0x30] {
+            assert!(read_modinfo(&data[..len]).is_err());
+        }
+
+        // Section headers at the end of the address space
+        for shoff in [u64::MAX, u64::MAX - 0x10] {
+            let mut data = data.clone();
+            data[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+            assert_eq!(
+                Elf::new(&data).unwrap().sections().err().unwrap().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn compression_by_magic() {
+        let elf = ElfModule::new().modinfo(&["license=GPL"]).build();
+        assert_eq!(ModCompression::from_magic(&elf), Some(ModCompression::None));
+        assert_eq!(ModCompression::from_magic(b"\0\0\0\0\0"), None);
+        assert_eq!(ModCompression::from_magic(b""), None);
+
+        let fx = Fixture::new();
+        let mut formats = vec![ModCompression::None];
+        if cfg!(feature = "compression") {
+            formats.extend([
+                ModCompression::Gzip,
+                ModCompression::Xz,
+                ModCompression::Zstd,
+            ]);
+        }
+        for compression in formats {
+            let data = compress(&elf, compression);
+            assert_eq!(ModCompression::from_magic(&data), Some(compression));
+
+            // Compressed content under a plain name, and the other way around
+            for fname in ["foo.ko", "foo.ko.zst", "foo.ko.gz"] {
+                let path = fx.write(&format!("{:?}/{}", compression, fname), &data);
+                let module = read_module(&path).unwrap();
+                assert_eq!(module, elf, "{:?} {}", compression, fname);
+                assert_eq!(read_modinfo(&module).unwrap()[0].1, "GPL");
+            }
+        }
+    }
+
+    #[test]
+    fn compression_by_extension_fallback() {
+        let fx = Fixture::new();
+        for (fname, compression) in [
+            ("foo.ko", ModCompression::None),
+            ("foo.ko.gz", ModCompression::Gzip),
+            ("foo.ko.xz", ModCompression::Xz),
+            ("foo.ko.zst", ModCompression::Zstd),
+        ] {
+            let path = fx.write(fname, b"garbage");
+            assert_eq!(ModCompression::from_path(&path), compression);
+
+            // Unknown content is read as the name tells, so it fails to decompress
+            match compression {
+                ModCompression::None => assert_eq!(read_module(&path).unwrap(), b"garbage"),
+                _ => assert!(read_module(&path).is_err()),
+            }
+        }
+    }
+}
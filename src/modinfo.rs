@@ -1,54 +1,209 @@
+use crate::error::Error;
+use std::fs::{read_to_string, File};
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::{fs::File, process};
 
 /// ModInfo contains current live module information
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ModInfo {
     pub name: String,
     pub mem_size: usize,
     pub mem_offset: usize, // Available for root only
-    pub instances: u8,
+    pub instances: u32,
     pub dependencies: Vec<String>,
+
+    /// State of the module: "Live", "Loading" or "Unloading"
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub state: String,
+
+    /// Taint flags of the module, e.g. "OE" for an unsigned out-of-tree module.
+    /// `None` if the module does not taint the kernel.
+    pub taint: Option<String>,
 }
 
+/// Loaded modules file of the running kernel
+pub static PROC_MODULES: &str = "/proc/modules";
+
 /// lsmod is just parse /proc/modules
-pub fn lsmod() -> Vec<ModInfo> {
+///
+/// Parsing is strict: a malformed line fails the whole list with `Parse`.
+/// Modules can be loaded or unloaded while the file is read, so a monitoring tool
+/// may better use `lsmod_lenient`.
+pub fn lsmod() -> Result<Vec<ModInfo>, Error> {
+    parse_proc_modules(&read_to_string(PROC_MODULES)?)
+}
+
+/// Parse content of /proc/modules, e.g. captured from another machine.
+/// Parsing is strict, as for `lsmod`: the error tells the line and the malformed field.
+pub fn parse_proc_modules(data: &str) -> Result<Vec<ModInfo>, Error> {
     let mut curr_mods: Vec<ModInfo> = vec![];
-    let rfe = File::open("/proc/modules");
-    if rfe.is_err() {
-        process::exit(1);
+    for (idx, line) in data.lines().enumerate() {
+        curr_mods.push(parse_line(line).map_err(|reason| Error::Parse {
+            line: idx + 1,
+            reason,
+        })?);
     }
 
-    for rfe in BufReader::new(rfe.unwrap()).lines() {
-        if rfe.is_err() {
-            process::exit(1);
-        }
+    Ok(curr_mods)
+}
 
-        let mod_data: Vec<String> = rfe.unwrap().split(' ').map(str::to_string).collect();
+/// Same as `lsmod`, but malformed lines are skipped instead of failing the whole list.
+/// Returns the parsed modules and the warnings about the skipped lines.
+/// If /proc/modules cannot be read at all, there are no modules and only a warning.
+pub fn lsmod_lenient() -> (Vec<ModInfo>, Vec<String>) {
+    let mut curr_mods: Vec<ModInfo> = vec![];
+    let mut warnings: Vec<String> = vec![];
+    let rfe = match File::open(PROC_MODULES) {
+        Ok(rfe) => rfe,
+        Err(err) => return (curr_mods, vec![format!("{}: {}", PROC_MODULES, err)]),
+    };
 
-        if mod_data.len() != 6 {
-            process::exit(1);
+    for (idx, rfe) in BufReader::new(rfe).lines().enumerate() {
+        match rfe
+            .map_err(|err| err.to_string())
+            .and_then(|x| parse_line(&x))
+        {
+            Ok(modinfo) => curr_mods.push(modinfo),
+            Err(reason) => warnings.push(format!("line {}: {}", idx + 1, reason)),
         }
+    }
+
+    (curr_mods, warnings)
+}
+
+/// Parse a line of /proc/modules. Returns the reason, if it is malformed.
+/// Fields are separated by any whitespace, as the padding may differ.
+fn parse_line(line: &str) -> Result<ModInfo, String> {
+    let mod_data: Vec<&str> = line.split_whitespace().collect();
+
+    // Taint flags are an optional last field, e.g. "(OE)"
+    if !(6..=7).contains(&mod_data.len()) {
+        return Err(format!("expected 6 or 7 fields, got {}", mod_data.len()));
+    }
+
+    Ok(ModInfo {
+        name: mod_data[0].to_owned(),
+        mem_size: mod_data[1]
+            .parse::<usize>()
+            .map_err(|err| format!("malformed size \"{}\": {}", mod_data[1], err))?,
+        instances: mod_data[2]
+            .parse::<u32>()
+            .map_err(|err| format!("malformed instances \"{}\": {}", mod_data[2], err))?,
+        dependencies: if mod_data[3] == "-" {
+            vec![]
+        } else {
+            mod_data[3]
+                .strip_suffix(',')
+                .ok_or_else(|| format!("malformed dependencies \"{}\"", mod_data[3]))?
+                .split(',')
+                .map(str::to_string)
+                .collect()
+        },
+        state: mod_data[4].to_owned(),
+        mem_offset: mod_data[5]
+            .strip_prefix("0x")
+            .and_then(|x| usize::from_str_radix(x, 0x10).ok())
+            .ok_or_else(|| format!("malformed offset \"{}\"", mod_data[5]))?,
+        taint: mod_data
+            .get(6)
+            .map(|x| x.trim_matches(|c| c == '(' || c == ')').to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        curr_mods.push(ModInfo {
-            name: mod_data[0].to_owned(),
-            mem_size: mod_data[1].parse::<usize>().unwrap(),
-            instances: mod_data[2].parse::<u8>().unwrap(),
-            dependencies: if mod_data[3] == "-" {
-                vec![]
-            } else {
-                mod_data[3]
-                    .strip_suffix(',')
-                    .unwrap()
-                    .split(',')
-                    .map(str::to_string)
-                    .collect()
-            },
-            mem_offset: usize::from_str_radix(mod_data[5].strip_prefix("0x").unwrap(), 0x10)
-                .unwrap(),
-        });
+    #[test]
+    fn taint_column() {
+        let mods = parse_proc_modules(
+            "nvidia 56426496 92 nvidia_uvm,nvidia_modeset, Live 0xffffffffc1a00000 (POE)\n\
+             vboxdrv 696320 2 vboxnetadp,vboxnetflt, Live 0x0000000000000000 (OE)\n\
+             snd_hda_intel 61440 3 - Live 0xffffffffc0f3c000\n",
+        )
+        .unwrap();
+
+        assert_eq!(mods.len(), 3);
+        assert_eq!(mods[0].name, "nvidia");
+        assert_eq!(mods[0].mem_size, 56426496);
+        assert_eq!(mods[0].instances, 92);
+        assert_eq!(mods[0].dependencies, vec!["nvidia_uvm", "nvidia_modeset"]);
+        assert_eq!(mods[0].state, "Live");
+        assert_eq!(mods[0].mem_offset, 0xffffffffc1a00000);
+        assert_eq!(mods[0].taint.as_deref(), Some("POE"));
+        assert_eq!(mods[1].taint.as_deref(), Some("OE"));
+        assert_eq!(mods[1].mem_offset, 0);
+        assert_eq!(mods[2].taint, None);
+        assert!(mods[2].dependencies.is_empty());
     }
 
-    curr_mods
+    #[test]
+    fn malformed_lines() {
+        for (line, reason) in [
+            ("foo 16384 0 - Live", "expected 6 or 7 fields, got 5"),
+            (
+                "foo 16384 0 - Live 0x0 (O) extra",
+                "expected 6 or 7 fields, got 8",
+            ),
+            ("foo 16k 0 - Live 0x0", "malformed size"),
+            ("foo 16384 x - Live 0x0", "malformed instances"),
+            ("foo 16384 0 bar Live 0x0", "malformed dependencies"),
+            ("foo 16384 0 - Live 12345", "malformed offset"),
+        ] {
+            match parse_proc_modules(&format!("bar 16384 0 - Live 0x0\n{}\n", line)) {
+                Err(Error::Parse { line, reason: err }) => {
+                    assert_eq!(line, 2);
+                    assert!(err.starts_with(reason), "{}", err);
+                }
+                res => panic!("{:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn boundary_values() {
+        let line = |size: &str, instances: &str, offset: &str| {
+            parse_proc_modules(&format!("foo {} {} - Live {}\n", size, instances, offset))
+        };
+
+        let mods = line(
+            &usize::MAX.to_string(),
+            &u32::MAX.to_string(),
+            &format!("{:#x}", usize::MAX),
+        )
+        .unwrap();
+        assert_eq!(mods[0].mem_size, usize::MAX);
+        assert_eq!(mods[0].instances, u32::MAX);
+        assert_eq!(mods[0].mem_offset, usize::MAX);
+
+        let mods = line("0", "256", "0x0").unwrap();
+        assert_eq!(
+            (mods[0].mem_size, mods[0].instances, mods[0].mem_offset),
+            (0, 256, 0)
+        );
+
+        for (size, instances, offset, reason) in [
+            ("18446744073709551616", "0", "0x0", "malformed size"),
+            ("-1", "0", "0x0", "malformed size"),
+            ("0", "4294967296", "0x0", "malformed instances"),
+            ("0", "-1", "0x0", "malformed instances"),
+            ("0", "0", "0x10000000000000000", "malformed offset"),
+            ("0", "0", "0x", "malformed offset"),
+        ] {
+            match line(size, instances, offset) {
+                Err(Error::Parse {
+                    line: 1,
+                    reason: err,
+                }) => {
+                    assert!(err.starts_with(reason), "{}", err)
+                }
+                res => panic!("{:?}", res),
+            }
+        }
+        assert!(matches!(
+            parse_proc_modules("foo 1 0 - Live 0x0\n\n"),
+            Err(Error::Parse { line: 2, .. })
+        ));
+    }
 }
@@ -0,0 +1,60 @@
+use crate::{
+    error::Error,
+    kerman::normalize_module_name,
+    modfile::{read_module, ModCompression},
+};
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, ErrorKind, Read},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+/// Insert a module file into the running kernel with the parameters, e.g. "debug=1".
+///
+/// Compressed modules are decompressed here and inserted from the memory,
+/// as not every kernel can decompress modules on its own.
+pub fn insert_module(path: &Path, params: &str) -> Result<(), Error> {
+    let params =
+        CString::new(params).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+    let mut magic = [0u8; 6];
+    let len = File::open(path)?.read(&mut magic)?;
+    let compression = ModCompression::from_magic(&magic[..len])
+        .unwrap_or_else(|| ModCompression::from_path(path));
+
+    let rc = if compression == ModCompression::None {
+        let file = File::open(path)?;
+        unsafe { libc::syscall(libc::SYS_finit_module, file.as_raw_fd(), params.as_ptr(), 0) }
+    } else {
+        let data = read_module(path)?;
+        unsafe {
+            libc::syscall(
+                libc::SYS_init_module,
+                data.as_ptr(),
+                data.len(),
+                params.as_ptr(),
+            )
+        }
+    };
+
+    if rc != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Remove a module from the running kernel.
+/// Fails with `WouldBlock`, if the module is still in use.
+pub fn remove_module(name: &str) -> Result<(), Error> {
+    let name = CString::new(normalize_module_name(name))
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+    if unsafe { libc::syscall(libc::SYS_delete_module, name.as_ptr(), libc::O_NONBLOCK) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
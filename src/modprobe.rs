@@ -0,0 +1,157 @@
+use crate::{error::Error, kerman::normalize_module_name};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{read_dir, read_to_string},
+    path::Path,
+};
+
+/// Directories with modprobe configuration, relative to the configuration root.
+/// Files in the earlier directories override same-named files in the later ones.
+pub static MODPROBE_D: [&str; 5] = [
+    "etc/modprobe.d",
+    "run/modprobe.d",
+    "usr/local/lib/modprobe.d",
+    "lib/modprobe.d",
+    "usr/lib/modprobe.d",
+];
+pub static PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// Soft dependencies of a module: modules, which are loaded before and after it,
+/// as modprobe does, although the module does not depend on them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SoftDep {
+    pub pre: Vec<String>,
+    pub post: Vec<String>,
+}
+
+/// Module configuration, as modprobe sees it from modprobe.d and the kernel command line.
+/// Module names are normalised (see `normalize_module_name`).
+#[derive(Debug, Clone, Default)]
+pub struct ModprobeConfig {
+    /// Modules, which should not be loaded by their aliases
+    pub blacklist: HashSet<String>,
+
+    /// Aliases (pattern, module) in the order of appearance
+    pub aliases: Vec<(String, String)>,
+
+    /// Soft dependencies by module names
+    pub softdeps: HashMap<String, SoftDep>,
+
+    /// Weak dependencies by module names: modules, which are loaded with the module,
+    /// if they are available
+    pub weakdeps: HashMap<String, Vec<String>>,
+
+    /// Commands, which are run instead of inserting the module, by module names
+    pub install: HashMap<String, String>,
+}
+
+impl ModprobeConfig {
+    /// Load configuration from all modprobe.d directories under the configuration root.
+    /// Missing directories are skipped.
+    pub fn load(config_root: &Path) -> Result<Self, Error> {
+        // file name -> path, the first one wins
+        let mut conf_files: HashMap<String, std::path::PathBuf> = HashMap::default();
+        for conf_d in MODPROBE_D {
+            let conf_d = config_root.join(conf_d);
+            if !conf_d.is_dir() {
+                continue;
+            }
+
+            for fres in read_dir(conf_d)? {
+                let fpath = fres?.path();
+                let fname = fpath
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                if fname.ends_with(".conf") && fpath.is_file() {
+                    conf_files.entry(fname).or_insert(fpath);
+                }
+            }
+        }
+
+        // Files are applied in lexical order, regardless of their directory
+        let mut fnames: Vec<&String> = conf_files.keys().collect();
+        fnames.sort();
+
+        let mut config = ModprobeConfig::default();
+        for fname in fnames {
+            config.parse(&read_to_string(&conf_files[fname])?);
+        }
+
+        Ok(config)
+    }
+
+    /// Parse content of a modprobe.d configuration file.
+    /// The same format have modules.softdep and modules.weakdep of a kernel.
+    ///
+    /// For the repeated "softdep", "weakdep" and "install" of a module the first one wins,
+    /// as in modprobe.
+    pub fn parse(&mut self, data: &str) {
+        for line in data.replace("\\\n", " ").lines() {
+            let mut tokens = line.split_whitespace();
+            match (tokens.next(), tokens.next()) {
+                (Some(cmd), _) if cmd.starts_with('#') => {}
+                (Some("blacklist"), Some(module)) => {
+                    self.blacklist.insert(normalize_module_name(module));
+                }
+                (Some("alias"), Some(pattern)) => {
+                    if let Some(module) = tokens.next() {
+                        self.aliases
+                            .push((pattern.to_string(), normalize_module_name(module)));
+                    }
+                }
+                (Some("softdep"), Some(module)) => {
+                    let mut softdep = SoftDep::default();
+                    let mut post = false;
+                    for token in tokens {
+                        match token {
+                            "pre:" => post = false,
+                            "post:" => post = true,
+                            _ if post => softdep.post.push(normalize_module_name(token)),
+                            _ => softdep.pre.push(normalize_module_name(token)),
+                        }
+                    }
+                    self.softdeps
+                        .entry(normalize_module_name(module))
+                        .or_insert(softdep);
+                }
+                (Some("weakdep"), Some(module)) => {
+                    let weakdeps: Vec<String> = tokens.map(normalize_module_name).collect();
+                    if !weakdeps.is_empty() {
+                        self.weakdeps
+                            .entry(normalize_module_name(module))
+                            .or_insert(weakdeps);
+                    }
+                }
+                (Some("install"), Some(module)) => {
+                    let command = tokens.collect::<Vec<&str>>().join(" ");
+                    if !command.is_empty() {
+                        self.install
+                            .entry(normalize_module_name(module))
+                            .or_insert(command);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Parse kernel command line for the module blacklists:
+    /// "modprobe.blacklist=a,b" and "module_blacklist=a,b". Keys may repeat.
+    pub fn parse_cmdline(&mut self, data: &str) {
+        for token in data.split_whitespace() {
+            if let Some((key, value)) = token.split_once('=') {
+                if ["modprobe.blacklist", "module_blacklist"].contains(&key) {
+                    self.blacklist.extend(
+                        value
+                            .trim_matches('"')
+                            .split(',')
+                            .filter(|x| !x.is_empty())
+                            .map(normalize_module_name),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Fixtures for the tests: module trees in a temporary directory
+//! and minimal ELF module images, built on the fly.
+// Not every test build uses every helper
+#![allow(dead_code)]
+
+use crate::{
+    kerman::{KernelInfo, KernelInfoBuilder},
+    modfile::ModCompression,
+};
+use std::{
+    fs::{create_dir_all, remove_dir_all, write},
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static FIXTURE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Root filesystem in a temporary directory, removed on drop
+pub struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "kmoddep-test-{}-{}",
+            process::id(),
+            FIXTURE_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+
+        Fixture { root }
+    }
+
+    /// Root of the fixture as a string, as `KernelInfo::new` takes it
+    pub fn root(&self) -> &str {
+        self.root.to_str().unwrap()
+    }
+
+    /// Path within the fixture
+    pub fn path(&self, rel: &str) -> PathBuf {
+        self.root.join(rel.trim_start_matches('/'))
+    }
+
+    /// Write a file with its parent directories
+    pub fn write(&self, rel: &str, data: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path(rel);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, data).unwrap();
+
+        path
+    }
+
+    /// Create a directory with its parents
+    pub fn mkdir(&self, rel: &str) -> PathBuf {
+        let path = self.path(rel);
+        create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    /// Write a file of a kernel modules directory, e.g. "modules.dep"
+    pub fn kfile(&self, kver: &str, rel: &str, data: impl AsRef<[u8]>) -> PathBuf {
+        self.write(&format!("lib/modules/{}/{}", kver, rel), data)
+    }
+
+    /// Write a module with the modinfo fields, compressed by its extension
+    pub fn module(&self, kver: &str, rel: &str, fields: &[&str]) -> PathBuf {
+        let path = self.path(&format!("lib/modules/{}/{}", kver, rel));
+        let data = compress(
+            &ElfModule::new().modinfo(fields).build(),
+            ModCompression::from_path(&path),
+        );
+        self.kfile(kver, rel, data)
+    }
+
+    /// Write modules.dep and empty modules for all its entries, which have no files yet.
+    /// A module, which is a dependency only, gets no file. Empty modules are not compressed
+    /// whatever their extension, as modules are read by their content.
+    pub fn tree(&self, kver: &str, modules_dep: &str) -> KernelInfo {
+        self.kfile(kver, "modules.dep", modules_dep);
+        for line in modules_dep.lines() {
+            if let Some((modpath, _)) = line.split_once(':') {
+                if !self.kpath(kver, modpath).exists() {
+                    self.kfile(kver, modpath, ElfModule::new().build());
+                }
+            }
+        }
+
+        self.kernel(kver)
+    }
+
+    /// Path within a kernel modules directory
+    pub fn kpath(&self, kver: &str, rel: &str) -> PathBuf {
+        self.path(&format!("lib/modules/{}/{}", kver, rel))
+    }
+
+    /// KernelInfo of a kernel of the fixture, which does not see the host
+    /// modprobe configuration and command line
+    pub fn kernel(&self, kver: &str) -> KernelInfo {
+        self.builder().build(kver).unwrap()
+    }
+
+    /// Builder of the KernelInfo, as for `kernel`
+    pub fn builder(&self) -> KernelInfoBuilder {
+        KernelInfoBuilder::default()
+            .rootfs(self.root())
+            .cmdline_path(&self.path("proc/cmdline"))
+            .sys_root(&self.path("sys"))
+            .mounts_path(&self.path("proc/mounts"))
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.root);
+    }
+}
+
+/// Get owned strings, as most of the methods take them
+pub fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|x| x.to_string()).collect()
+}
+
+/// Compress data in a module compression
+pub fn compress(data: &[u8], compression: ModCompression) -> Vec<u8> {
+    match compression {
+        ModCompression::None => data.to_vec(),
+        #[cfg(feature = "compression")]
+        ModCompression::Gzip => {
+            use std::io::Write;
+
+            let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            enc.write_all(data).unwrap();
+            enc.finish().unwrap()
+        }
+        #[cfg(feature = "compression")]
+        ModCompression::Xz => {
+            let mut out: Vec<u8> = vec![];
+            lzma_rs::xz_compress(&mut &data[..], &mut out).unwrap();
+            out
+        }
+        #[cfg(feature = "compression")]
+        ModCompression::Zstd => {
+            ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+        }
+        #[cfg(not(feature = "compression"))]
+        _ => panic!(
+            "{:?} compression requires \"compression\" feature",
+            compression
+        ),
+    }
+}
+
+/// Minimal relocatable ELF image of a module: sections with their content
+/// and optionally a symbol table
+pub struct ElfModule {
+    class64: bool,
+    be: bool,
+    machine: u16,
+
+    // (name, type, flags, data, size)
+    sections: Vec<(String, u32, u64, Vec<u8>, u64)>,
+
+    // (name, section name; empty for undefined)
+    symbols: Vec<(String, String)>,
+}
+
+impl ElfModule {
+    /// 64-bit little endian x86_64 module without sections
+    pub fn new() -> Self {
+        ElfModule {
+            class64: true,
+            be: false,
+            machine: 62,
+            sections: vec![],
+            symbols: vec![],
+        }
+    }
+
+    pub fn class64(mut self, class64: bool) -> Self {
+        self.class64 = class64;
+        self
+    }
+
+    pub fn be(mut self, be: bool) -> Self {
+        self.be = be;
+        self
+    }
+
+    pub fn machine(mut self, machine: u16) -> Self {
+        self.machine = machine;
+        self
+    }
+
+    /// Add a section. SHT_NOBITS (8) has the size, but no content.
+    pub fn section(mut self, name: &str, kind: u32, flags: u64, data: &[u8], size: u64) -> Self {
+        self.sections
+            .push((name.to_string(), kind, flags, data.to_vec(), size));
+        self
+    }
+
+    /// Add .modinfo with "key=value" fields
+    pub fn modinfo(self, fields: &[&str]) -> Self {
+        let data: Vec<u8> = fields.iter().flat_map(|x| x.bytes().chain([0])).collect();
+        let size = data.len() as u64;
+        self.section(".modinfo", 1, 0x2, &data, size)
+    }
+
+    /// Add a symbol, defined in a section, or undefined, if the section is empty
+    pub fn symbol(mut self, name: &str, section: &str) -> Self {
+        self.symbols.push((name.to_string(), section.to_string()));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let (ehsize, shentsize) = if self.class64 { (64, 64) } else { (52, 40) };
+        let mut sections = self.sections.clone();
+
+        // Symbol table goes after the sections with its string table
+        if !self.symbols.is_empty() {
+            let mut strtab: Vec<u8> = vec![0];
+            let mut symtab: Vec<u8> = vec![0; if self.class64 { 24 } else { 16 }];
+            for (name, section) in &self.symbols {
+                let st_name = strtab.len() as u32;
+                strtab.extend(name.bytes().chain([0]));
+                let shndx = match section.as_str() {
+                    "" => 0,
+                    section => sections.iter().position(|x| x.0 == section).unwrap() as u16 + 1,
+                };
+                if self.class64 {
+                    symtab.extend(self.u32(st_name));
+                    symtab.extend([0x10, 0]); // STB_GLOBAL, STT_NOTYPE
+                    symtab.extend(self.u16(shndx));
+                    symtab.extend([0; 16]);
+                } else {
+                    symtab.extend(self.u32(st_name));
+                    symtab.extend([0; 8]);
+                    symtab.extend([0x10, 0]);
+                    symtab.extend(self.u16(shndx));
+                }
+            }
+            let (symsz, strsz) = (symtab.len() as u64, strtab.len() as u64);
+            sections.push((".symtab".to_string(), 2, 0, symtab, symsz));
+            sections.push((".strtab".to_string(), 3, 0, strtab, strsz));
+        }
+
+        let mut shstrtab: Vec<u8> = vec![0];
+        let mut sh_names: Vec<u32> = vec![];
+        for (name, ..) in &sections {
+            sh_names.push(shstrtab.len() as u32);
+            shstrtab.extend(name.bytes().chain([0]));
+        }
+        sh_names.push(shstrtab.len() as u32);
+        shstrtab.extend(b".shstrtab\0");
+        let shstrsz = shstrtab.len() as u64;
+        sections.push((".shstrtab".to_string(), 3, 0, shstrtab, shstrsz));
+
+        let mut body: Vec<u8> = vec![];
+        let mut offsets: Vec<u64> = vec![];
+        for (_, _, _, data, _) in &sections {
+            offsets.push((ehsize + body.len()) as u64);
+            body.extend(data);
+        }
+        let shoff = (ehsize + body.len()) as u64;
+        let symtab_idx = sections.iter().position(|x| x.1 == 2);
+
+        // Null section first, then the sections
+        let mut sh: Vec<u8> = vec![0; shentsize];
+        for (idx, (_, kind, flags, _, size)) in sections.iter().enumerate() {
+            let link = match symtab_idx {
+                Some(symtab_idx) if symtab_idx == idx => idx as u32 + 2,
+                _ => 0,
+            };
+            sh.extend(self.u32(sh_names[idx]));
+            sh.extend(self.u32(*kind));
+            sh.extend(self.word(*flags));
+            sh.extend(self.word(0)); // addr
+            sh.extend(self.word(offsets[idx]));
+            sh.extend(self.word(*size));
+            sh.extend(self.u32(link));
+            sh.extend(self.u32(0)); // info
+            sh.extend(self.word(1)); // addralign
+            sh.extend(self.word(if *kind == 2 {
+                if self.class64 {
+                    24
+                } else {
+                    16
+                }
+            } else {
+                0
+            }));
+        }
+
+        let mut elf: Vec<u8> = b"\x7fELF".to_vec();
+        elf.extend([
+            if self.class64 { 2 } else { 1 },
+            if self.be { 2 } else { 1 },
+            1,
+            0,
+        ]);
+        elf.extend([0; 8]);
+        elf.extend(self.u16(1)); // ET_REL
+        elf.extend(self.u16(self.machine));
+        elf.extend(self.u32(1));
+        elf.extend(self.word(0)); // entry
+        elf.extend(self.word(0)); // phoff
+        elf.extend(self.word(shoff));
+        elf.extend(self.u32(0)); // flags
+        elf.extend(self.u16(ehsize as u16));
+        elf.extend(self.u16(0)); // phentsize
+        elf.extend(self.u16(0)); // phnum
+        elf.extend(self.u16(shentsize as u16));
+        elf.extend(self.u16(sections.len() as u16 + 1));
+        elf.extend(self.u16(sections.len() as u16));
+        elf.extend(body);
+        elf.extend(sh);
+
+        elf
+    }
+
+    fn u16(&self, x: u16) -> Vec<u8> {
+        if self.be {
+            x.to_be_bytes().to_vec()
+        } else {
+            x.to_le_bytes().to_vec()
+        }
+    }
+
+    fn u32(&self, x: u32) -> Vec<u8> {
+        if self.be {
+            x.to_be_bytes().to_vec()
+        } else {
+            x.to_le_bytes().to_vec()
+        }
+    }
+
+    /// Address-sized field
+    fn word(&self, x: u64) -> Vec<u8> {
+        match (self.class64, self.be) {
+            (true, true) => x.to_be_bytes().to_vec(),
+            (true, false) => x.to_le_bytes().to_vec(),
+            (false, true) => (x as u32).to_be_bytes().to_vec(),
+            (false, false) => (x as u32).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+/// Build a kmod binary index (see `kmodindex::parse_kmod_index`) of the keys
+/// with their values, as depmod writes it: children before their parent,
+/// shared key prefixes of the nodes as their prefix strings
+#[cfg(feature = "kmod-index")]
+pub fn kmod_index(entries: &[(&str, &[&str])]) -> Vec<u8> {
+    fn node(out: &mut Vec<u8>, entries: &[(&[u8], &[&str])]) -> u32 {
+        let prefix_len = entries
+            .iter()
+            .map(|(key, _)| {
+                key.iter()
+                    .zip(entries[0].0)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+            .min()
+            .unwrap_or(0);
+        let prefix = &entries[0].0[..prefix_len];
+        let values: Vec<&str> = entries
+            .iter()
+            .filter(|(key, _)| key.len() == prefix_len)
+            .flat_map(|(_, values)| values.iter().copied())
+            .collect();
+
+        // Children first, by their first character after the prefix
+        let mut children: Vec<(u8, u32)> = vec![];
+        let rest: Vec<(&[u8], &[&str])> = entries
+            .iter()
+            .filter(|(key, _)| key.len() > prefix_len)
+            .map(|(key, values)| (&key[prefix_len..], *values))
+            .collect();
+        let mut idx = 0;
+        while idx < rest.len() {
+            let ch = rest[idx].0[0];
+            let group: Vec<(&[u8], &[&str])> = rest[idx..]
+                .iter()
+                .take_while(|(key, _)| key[0] == ch)
+                .map(|(key, values)| (&key[1..], *values))
+                .collect();
+            idx += group.len();
+            children.push((ch, node(out, &group)));
+        }
+
+        let mut flags: u32 = 0;
+        let offset = out.len() as u32;
+        if !prefix.is_empty() {
+            flags |= 0x80000000;
+            out.extend(prefix);
+            out.push(0);
+        }
+        if let (Some(first), Some(last)) = (children.first(), children.last()) {
+            flags |= 0x20000000;
+            out.extend([first.0, last.0]);
+            for ch in first.0..=last.0 {
+                let child = children.iter().find(|x| x.0 == ch).map_or(0, |x| x.1);
+                out.extend(child.to_be_bytes());
+            }
+        }
+        if !values.is_empty() {
+            flags |= 0x40000000;
+            out.extend((values.len() as u32).to_be_bytes());
+            for (priority, value) in values.iter().enumerate() {
+                out.extend((priority as u32).to_be_bytes());
+                out.extend(value.bytes().chain([0]));
+            }
+        }
+
+        offset | flags
+    }
+
+    let mut entries: Vec<(&[u8], &[&str])> = entries
+        .iter()
+        .map(|(key, values)| (key.as_bytes(), *values))
+        .collect();
+    entries.sort();
+
+    let mut out: Vec<u8> = vec![];
+    out.extend(0xB007F457u32.to_be_bytes());
+    out.extend(0x00020001u32.to_be_bytes());
+    out.extend([0; 4]);
+    let root = node(&mut out, &entries);
+    out[8..12].copy_from_slice(&root.to_be_bytes());
+
+    out
+}